@@ -0,0 +1,105 @@
+// Handlers of tonic services fail with Status
+#![allow(clippy::result_large_err)]
+
+use std::{path::PathBuf, sync::Arc};
+
+use log::{error, info};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    auth::{Caller, Roles},
+    config::{self, Config},
+    controller,
+    runner::{admin_server, ReloadReply, ReloadRequest},
+    service::{Reloaded, Service},
+    tls::{self, Acceptor},
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] config::Error),
+    #[error(transparent)]
+    Tls(#[from] tls::Error),
+    #[error(transparent)]
+    Controller(#[from] controller::Error),
+}
+
+/// Reloads config of running server, on SIGHUP or through `Admin` service.
+/// Clones share config
+#[derive(Clone)]
+pub struct Admin {
+    path: PathBuf,
+    config: Arc<Mutex<Config>>,
+    acceptor: Acceptor,
+    service: Service,
+    roles: Roles,
+}
+
+impl Admin {
+    pub fn new(
+        path: PathBuf,
+        config: Config,
+        acceptor: Acceptor,
+        service: Service,
+        roles: Roles,
+    ) -> Self {
+        Self {
+            path,
+            config: Arc::new(Mutex::new(config)),
+            acceptor,
+            service,
+            roles,
+        }
+    }
+
+    /// Re-reads config file and TLS material, provisions new clients and applies
+    /// settings of known ones and roles. Config which can't be applied as a whole is rejected,
+    /// leaving current one in place
+    pub async fn reload(&self) -> Result<Reloaded, Error> {
+        // Reloads don't interleave
+        let mut current = self.config.lock().await;
+
+        let config = Config::load(&self.path)?.reloaded(&current)?;
+        // TLS material is validated before clients are touched, but swapped after
+        // them so that new clients are known once their certificates are accepted
+        let tls = tls::load(&config.tls).await?;
+        let reloaded = self.service.reload(&config.clients).await?;
+        self.acceptor.replace(tls);
+        self.roles.replace(config.roles.clone());
+        *current = config;
+
+        Ok(reloaded)
+    }
+}
+
+#[tonic::async_trait]
+impl admin_server::Admin for Admin {
+    async fn reload(
+        &self,
+        request: Request<ReloadRequest>,
+    ) -> Result<Response<ReloadReply>, Status> {
+        // Only admins get past `auth::Authorized`
+        let organization = request
+            .extensions()
+            .get::<Caller>()
+            .map(|caller| caller.organization.clone())
+            .ok_or_else(|| Status::unauthenticated("Caller is not authorized"))?;
+
+        match Admin::reload(self).await {
+            Ok(Reloaded { added, removed }) => {
+                info!(
+                    "Config reloaded by {}, added {:?}, removed {:?}",
+                    organization, added, removed
+                );
+                Ok(Response::new(ReloadReply { added, removed }))
+            }
+            Err(err) => {
+                error!("Config reload by {} failed: {}", organization, err);
+                Err(Status::failed_precondition(err.to_string()))
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+// Calls are refused with Status, same as handlers of tonic services do
+#![allow(clippy::result_large_err)]
+
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+};
+
+use serde::Deserialize;
+use tonic::{
+    body::BoxBody,
+    codegen::{http, BoxFuture, Service},
+    transport::{
+        server::{TcpConnectInfo, TlsConnectInfo},
+        NamedService,
+    },
+    Status,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Metadata key admins name client they act for with
+pub const CLIENT_KEY: &str = "pls-client";
+
+/// Capabilities of a caller, read from `O`rganizational `U`nit of its certificate,
+/// either naming the role or mapped to it in config. Admin is granted only by mapping,
+/// as any client may name its units
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Observes jobs of its client
+    Viewer,
+    /// Runs and interacts with jobs of its client
+    Operator,
+    /// Acts as operator for any client and reloads config
+    Admin,
+}
+
+impl Role {
+    /// Whether role may call JobRunner or Admin RPC of given name
+    pub fn permits(self, rpc: &str) -> bool {
+        match rpc {
+            "Status" | "Output" | "Stats" => true,
+            "Start" | "Stop" | "WriteStdin" | "Attach" => self >= Role::Operator,
+            "Reload" => self == Role::Admin,
+            _ => false,
+        }
+    }
+
+    // First role either mapped or, short of admin, named by one of units
+    fn resolve<'u>(
+        units: impl IntoIterator<Item = &'u str>,
+        mapping: &BTreeMap<String, Role>,
+    ) -> Option<Role> {
+        units.into_iter().find_map(|unit| {
+            mapping
+                .get(unit)
+                .copied()
+                .or_else(|| unit.parse().ok().filter(|role| *role != Role::Admin))
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Caller of RPC, attached to request extensions by `Authorized`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caller {
+    pub organization: String,
+    pub role: Role,
+    /// Client whose jobs the call is about, organization of caller unless admin names another
+    pub client: String,
+}
+
+/// Mapping of organizational units to roles, which can be replaced while the server runs.
+/// Clones share mapping
+#[derive(Debug, Clone, Default)]
+pub struct Roles {
+    mapping: Arc<RwLock<BTreeMap<String, Role>>>,
+}
+
+impl Roles {
+    pub fn new(mapping: BTreeMap<String, Role>) -> Self {
+        Self {
+            mapping: Arc::new(RwLock::new(mapping)),
+        }
+    }
+
+    pub fn replace(&self, mapping: BTreeMap<String, Role>) {
+        *self.mapping.write().expect("Poisoned roles") = mapping;
+    }
+
+    /// Caller identified by DER encoded certificate, calling RPC of given name
+    fn authorize(&self, der: &[u8], rpc: &str, client: Option<&str>) -> Result<Caller, Status> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|_| Status::unauthenticated("Invalid client certificate"))?;
+        let organization = organization(&cert)
+            .ok_or_else(|| Status::unauthenticated("Client certificate has no organization"))?;
+        let units = cert
+            .subject()
+            .iter_organizational_unit()
+            .filter_map(|unit| unit.as_str().ok());
+        let role = Role::resolve(units, &self.mapping.read().expect("Poisoned roles"))
+            .ok_or_else(|| Status::permission_denied("Client certificate names no role"))?;
+
+        if !role.permits(rpc) {
+            return Err(Status::permission_denied(format!(
+                "{:?} may not call {}",
+                role, rpc
+            )));
+        }
+        let client = match client {
+            Some(client) if client != organization && role != Role::Admin => {
+                return Err(Status::permission_denied(format!(
+                    "{:?} may not act for {}",
+                    role, client
+                )))
+            }
+            Some(client) => client,
+            None => organization,
+        };
+
+        Ok(Caller {
+            organization: organization.to_owned(),
+            role,
+            client: client.to_owned(),
+        })
+    }
+}
+
+/// Organization of the subject of certificate, which is the client it belongs to
+pub(crate) fn organization<'c>(cert: &'c X509Certificate) -> Option<&'c str> {
+    let organization = cert.subject().iter_organization().next()?;
+    organization.as_str().ok()
+}
+
+/// Service refusing calls the role of caller does not permit, in front of every RPC
+/// of inner one. Calls it lets through carry `Caller` in their extensions
+#[derive(Debug, Clone)]
+pub struct Authorized<S> {
+    inner: S,
+    roles: Roles,
+}
+
+impl<S> Authorized<S> {
+    pub fn new(inner: S, roles: Roles) -> Self {
+        Self { inner, roles }
+    }
+
+    fn authorize<B>(&self, request: &http::Request<B>) -> Result<Caller, Status> {
+        let rpc = request.uri().path().rsplit('/').next().unwrap_or_default();
+        let certs = request
+            .extensions()
+            .get::<TlsConnectInfo<TcpConnectInfo>>()
+            .and_then(TlsConnectInfo::peer_certs)
+            .ok_or_else(|| Status::unauthenticated("Client certificate is required"))?;
+        let cert = certs
+            .first()
+            .ok_or_else(|| Status::unauthenticated("Client certificate is required"))?;
+        let client = match request.headers().get(CLIENT_KEY) {
+            Some(client) => Some(
+                client
+                    .to_str()
+                    .map_err(|_| Status::invalid_argument("Invalid client"))?,
+            ),
+            None => None,
+        };
+
+        self.roles.authorize(cert.get_ref(), rpc, client)
+    }
+}
+
+impl<S: NamedService> NamedService for Authorized<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> Service<http::Request<B>> for Authorized<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        match self.authorize(&request) {
+            Ok(caller) => {
+                request.extensions_mut().insert(caller);
+                Box::pin(self.inner.call(request))
+            }
+            Err(status) => Box::pin(async move { Ok(status.to_http()) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_role_then_only_its_rpcs_are_permitted() {
+        for rpc in ["Status", "Output", "Stats"] {
+            assert!(Role::Viewer.permits(rpc));
+            assert!(Role::Operator.permits(rpc));
+        }
+        for rpc in ["Start", "Stop", "WriteStdin", "Attach"] {
+            assert!(!Role::Viewer.permits(rpc));
+            assert!(Role::Operator.permits(rpc));
+            assert!(Role::Admin.permits(rpc));
+        }
+        assert!(!Role::Operator.permits("Reload"));
+        assert!(Role::Admin.permits("Reload"));
+    }
+
+    fn der(name: &str) -> Vec<u8> {
+        let pem = std::fs::read(format!(
+            "{}/tests/fixtures/crl/{}.pem",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap();
+        x509_parser::pem::Pem::iter_from_buffer(&pem)
+            .next()
+            .unwrap()
+            .unwrap()
+            .contents
+    }
+
+    #[test]
+    fn given_certificate_without_admin_role_then_reload_is_refused() {
+        let roles = Roles::default();
+        let der = der("operator");
+
+        assert_eq!(
+            roles.authorize(&der, "Start", None).unwrap().role,
+            Role::Operator
+        );
+        assert_eq!(
+            roles.authorize(&der, "Reload", None).unwrap_err().code(),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            roles
+                .authorize(&der, "Status", Some("other"))
+                .unwrap_err()
+                .code(),
+            tonic::Code::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn given_certificate_without_unit_then_every_call_is_refused() {
+        let roles = Roles::default();
+
+        assert_eq!(
+            roles
+                .authorize(&der("valid"), "Status", None)
+                .unwrap_err()
+                .code(),
+            tonic::Code::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn given_admin_unit_then_admin_is_granted_only_by_mapping() {
+        let der = der("sre");
+        let roles = Roles::default();
+
+        assert_eq!(
+            roles.authorize(&der, "Status", None).unwrap_err().code(),
+            tonic::Code::PermissionDenied
+        );
+
+        roles.replace(BTreeMap::from([("sre".to_owned(), Role::Admin)]));
+        let caller = roles.authorize(&der, "Reload", Some("other")).unwrap();
+
+        assert_eq!(caller.role, Role::Admin);
+        assert_eq!(caller.organization, "acme");
+        assert_eq!(caller.client, "other");
+    }
+
+    #[test]
+    fn given_units_then_mapping_takes_precedence_over_named_role() {
+        let mapping = BTreeMap::from([
+            ("sre".to_owned(), Role::Admin),
+            ("viewer".to_owned(), Role::Operator),
+        ]);
+
+        assert_eq!(
+            Role::resolve(["dev", "viewer"], &BTreeMap::new()),
+            Some(Role::Viewer)
+        );
+        assert_eq!(Role::resolve(["viewer"], &mapping), Some(Role::Operator));
+        assert_eq!(Role::resolve(["dev", "sre"], &mapping), Some(Role::Admin));
+        assert_eq!(Role::resolve(["admin"], &BTreeMap::new()), None);
+        assert_eq!(Role::resolve(["dev"], &mapping), None);
+        assert_eq!(Role::resolve([], &mapping), None);
+    }
+}
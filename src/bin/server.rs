@@ -0,0 +1,102 @@
+use std::{error::Error, path::PathBuf};
+
+use log::{error, info};
+use pls::{
+    admin::Admin,
+    auth::{Authorized, Roles},
+    config::Config,
+    runner::{admin_server::AdminServer, job_runner_server::JobRunnerServer},
+    service::Service,
+    tls::{self, Acceptor},
+};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+use tonic::transport::Server;
+
+const CONFIG_PATH: &str = "/etc/pls/config.toml";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    if let Err(err) = run().await {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let path = std::env::args()
+        .nth(1)
+        .map_or_else(|| PathBuf::from(CONFIG_PATH), PathBuf::from);
+    let mut config = Config::load(&path)?;
+
+    config.settle_cgroup_root().await?;
+
+    let acceptor = Acceptor::new(tls::load(&config.tls).await?);
+    let service = Service::new(&config.clients).await?;
+
+    let pruned = service.clone();
+    let prune_interval = config.prune_interval;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(prune_interval);
+        loop {
+            interval.tick().await;
+            pruned.prune().await;
+        }
+    });
+
+    // Every address is served by the same server
+    let mut listeners = Vec::new();
+    for addr in &config.listen {
+        listeners.push(TcpListener::bind(addr).await?);
+        info!("Listening on {}", addr);
+    }
+    let incoming = acceptor.incoming(listeners);
+
+    let revoking = acceptor.clone();
+    let crl_interval = config.crl_interval;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(crl_interval);
+        // CRL was just read along with the rest of TLS material
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(err) = revoking.reload_crl().await {
+                error!(
+                    "CRL reload failed, revoked certificates stay as they were: {}",
+                    err
+                );
+            }
+        }
+    });
+
+    let roles = Roles::new(config.roles.clone());
+    let admin = Admin::new(path, config, acceptor, service.clone(), roles.clone());
+    let mut hangups = signal(SignalKind::hangup())?;
+    let reloaded = admin.clone();
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match reloaded.reload().await {
+                Ok(reloaded) => info!(
+                    "Config reloaded, added {:?}, removed {:?}",
+                    reloaded.added, reloaded.removed
+                ),
+                Err(err) => error!("Config reload failed: {}", err),
+            }
+        }
+    });
+
+    Server::builder()
+        .add_service(Authorized::new(
+            JobRunnerServer::new(service),
+            roles.clone(),
+        ))
+        .add_service(Authorized::new(AdminServer::new(admin), roles))
+        .serve_with_incoming(incoming)
+        .await?;
+
+    Ok(())
+}
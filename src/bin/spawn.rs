@@ -1,3 +1,6 @@
+use std::path::Path;
+
+use pls::config::Config;
 use pls::controller;
 use pls::runner::{job_request::*, JobRequest, LogMessage};
 
@@ -27,6 +30,7 @@ async fn main() -> Result<(), pls::PlsError> {
                 wbps_max: 1024,
             }),
             args,
+            ..Default::default()
         }
     } else {
         JobRequest {
@@ -35,14 +39,24 @@ async fn main() -> Result<(), pls::PlsError> {
             mem_control: None,
             io_control: None,
             args,
+            ..Default::default()
         }
     };
 
-    let mut ctr = controller::Controller::new(&name).await?;
+    // Client settings come from server config if one is given
+    let settings = match std::env::var_os("PLS_CONFIG") {
+        Some(path) => {
+            let mut config = Config::load(Path::new(&path))?;
+            config.settle_cgroup_root().await?;
+            config.clients.remove(&name).unwrap_or_default()
+        }
+        None => Default::default(),
+    };
+    let mut ctr = controller::Controller::with_settings(&name, settings).await?;
     let first_id = ctr.start(req).await?;
     println!("Status: {:#?}", ctr);
 
-    let mut output = ctr.output(first_id).await?;
+    let mut output = ctr.output(first_id, Default::default()).await?;
 
     let f = tokio::spawn(async move {
         while let Some(Ok(msg)) = output.recv().await {
@@ -1,7 +1,15 @@
 use crate::runner::JobRequest;
 use core::fmt;
-use std::path::{Path, PathBuf};
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use thiserror::Error;
+use tokio::fs::create_dir_all;
 
 pub const PROC_FILE: &str = "cgroup.procs";
 pub const ENABLED_CONTROLLERS: &str = "cgroup.controllers";
@@ -10,6 +18,15 @@ pub const CPU_WEIGHT: &str = "cpu.weight";
 pub const MEM_HIGH: &str = "memory.high";
 pub const MEM_MAX: &str = "memory.max";
 pub const IO_MAX: &str = "io.max";
+pub const KILL_FILE: &str = "cgroup.kill";
+// Leaf server moves itself into within delegated cgroup
+pub const SERVER_CGROUP: &str = "pls-server";
+
+// Attempts at emptying cgroup, processes are given a few milliseconds to exit between them
+const KILL_ATTEMPTS: usize = 100;
+// Values limits which are not set are reset to, same as kernel defaults
+const DEFAULT_CPU_WEIGHT: u32 = 100;
+const NO_LIMIT: &str = "max";
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -24,6 +41,12 @@ pub enum Error {
 
     #[error("{0} is invalid, valid range is [1, 10000]")]
     InvalidCpuWeight(u32),
+
+    #[error("Processes left in {0} could not be killed")]
+    NotEmpty(PathBuf),
+
+    #[error("Server is not in cgroup v2 hierarchy")]
+    NoHierarchy,
 }
 
 #[derive(Debug, PartialEq)]
@@ -71,7 +94,7 @@ impl fmt::Display for Controller {
 
 impl PartialEq<str> for Controller {
     fn eq(&self, other: &str) -> bool {
-        self == other
+        <&str>::from(self) == other
     }
 }
 
@@ -118,6 +141,116 @@ pub async fn set_io_control(cgroup_dir: &Path, job_req: &JobRequest) -> Result<(
     Ok(())
 }
 
+/// Limits of client cgroup, shared by all jobs of the client
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub cpu_weight: Option<u32>,
+    pub mem_high: Option<u64>,
+    pub mem_max: Option<u64>,
+}
+
+impl Limits {
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.cpu_weight {
+            Some(weight) if weight == 0 || weight > 10000 => Err(Error::InvalidCpuWeight(weight)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes every limit, resetting ones which are not set, so that limits dropped from
+/// settings don't linger in cgroup outliving the server
+pub async fn set_limits(cgroup_dir: &Path, limits: &Limits) -> Result<(), Error> {
+    limits.validate()?;
+
+    let weight = limits.cpu_weight.unwrap_or(DEFAULT_CPU_WEIGHT);
+    tokio::fs::write(cgroup_dir.join(CPU_WEIGHT), weight.to_string()).await?;
+    for (file, limit) in [(MEM_HIGH, limits.mem_high), (MEM_MAX, limits.mem_max)] {
+        let limit = limit.map_or_else(|| NO_LIMIT.to_string(), |limit| limit.to_string());
+        tokio::fs::write(cgroup_dir.join(file), limit).await?;
+    }
+
+    Ok(())
+}
+
+/// Pids of processes in the cgroup, empty if cgroup is gone
+pub async fn procs(cgroup_dir: &Path) -> Result<Vec<u32>, Error> {
+    let procs = match tokio::fs::read_to_string(cgroup_dir.join(PROC_FILE)).await {
+        Ok(procs) => procs,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    Ok(procs
+        .lines()
+        .filter_map(|pid| pid.trim().parse().ok())
+        .collect())
+}
+
+/// Kills every process left in the cgroup, e.g. daemonized by the job,
+/// and waits for them to exit
+pub async fn kill(cgroup_dir: &Path) -> Result<(), Error> {
+    for _ in 0..KILL_ATTEMPTS {
+        let procs = procs(cgroup_dir).await?;
+        if procs.is_empty() {
+            return Ok(());
+        }
+
+        match tokio::fs::write(cgroup_dir.join(KILL_FILE), "1").await {
+            // Kernel older than 5.14, processes are killed one by one instead.
+            // Pids can't be reused while they are listed in cgroup.procs
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                for pid in procs {
+                    let _ = signal::kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+                }
+            }
+            Err(err) => return Err(err.into()),
+            Ok(()) => (),
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    Err(Error::NotEmpty(cgroup_dir.to_owned()))
+}
+
+/// Cgroup delegated to the server, e.g. by systemd `Delegate=yes`, as found through
+/// /proc/self/cgroup. Processes can't reside in cgroup distributing controllers to its
+/// children, so the server moves itself into a leaf of its own first
+pub async fn delegate() -> Result<PathBuf, Error> {
+    let own = own_cgroup_dir().await?;
+    // Server has already moved itself
+    let root = match own.parent() {
+        Some(parent) if own.ends_with(SERVER_CGROUP) => parent.to_owned(),
+        _ => own,
+    };
+
+    let server = root.join(SERVER_CGROUP);
+    create_dir_all(&server).await?;
+    // Moves every thread of the server
+    tokio::fs::write(server.join(PROC_FILE), "0").await?;
+    enable_subtree(&root, Controller::all()).await?;
+
+    Ok(root)
+}
+
+/// Cgroup v2 directory the calling process resides in
+pub async fn own_cgroup_dir() -> Result<PathBuf, Error> {
+    let mountinfo = tokio::fs::read_to_string("/proc/self/mountinfo").await?;
+    let mount = mountinfo
+        .lines()
+        .find(|line| line.contains(" - cgroup2 "))
+        .and_then(|line| line.split(' ').nth(4))
+        .ok_or(Error::NoHierarchy)?;
+
+    let cgroups = tokio::fs::read_to_string("/proc/self/cgroup").await?;
+    let path = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or(Error::NoHierarchy)?;
+
+    Ok(Path::new(mount).join(path.trim_start_matches('/')))
+}
+
 pub async fn enable_subtree_unchecked(
     cgroup_dir: &Path,
     controllers: &[Controller],
@@ -166,7 +299,32 @@ fn is_subset(enabled: &str, controllers: &[Controller]) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use super::{is_subset, prepend_with, Controller};
+    use std::process::Command;
+
+    use super::{is_subset, kill, prepend_with, procs, Controller, PROC_FILE};
+    use crate::testing::own_cgroup_dir;
+
+    #[tokio::test]
+    async fn given_processes_left_in_cgroup_then_kill_empties_it() {
+        let cgroup_dir = own_cgroup_dir().await.join("pls-test-kill");
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        std::fs::create_dir(&cgroup_dir).unwrap();
+
+        // Orphaned by the shell, the way daemonized process would be
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "echo $$ > {}; sleep 10 & exit",
+                cgroup_dir.join(PROC_FILE).display()
+            ))
+            .status()
+            .unwrap();
+        assert_eq!(procs(&cgroup_dir).await.unwrap().len(), 1);
+
+        kill(&cgroup_dir).await.unwrap();
+        assert!(procs(&cgroup_dir).await.unwrap().is_empty());
+        std::fs::remove_dir(&cgroup_dir).unwrap();
+    }
 
     #[test]
     fn given_some_controllers_are_disabled_then_cant_enable() {
@@ -0,0 +1,105 @@
+use std::{
+    fs::File,
+    os::unix::prelude::{AsRawFd, FromRawFd},
+};
+
+use nix::libc::{self, c_int, SYS_clone3, CLONE_PIDFD, E2BIG, EINVAL, ENOSYS, SIGCHLD};
+
+// libc declares it as c_int, which it does not fit into
+const CLONE_INTO_CGROUP: u64 = 0x2_0000_0000;
+
+/// Arguments of [clone3(2)](https://man7.org/linux/man-pages/man2/clone3.2.html),
+/// up to `cgroup` which was added in Linux 5.7
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+    cgroup: u64,
+}
+
+/// Side of the clone calling process ended up on
+#[derive(Debug)]
+pub enum Fork {
+    Parent { pid: u32, pidfd: File },
+    Child,
+}
+
+/// Forks calling process, child starts in cgroup `cgroup_dir` refers to rather than in
+/// cgroup of the caller. Parent gets pidfd of the child along with its pid.
+///
+/// # Safety
+/// Same as for [fork(2)](https://man7.org/linux/man-pages/man2/fork.2.html): child of
+/// multithreaded process may only call async-signal-safe functions until `execve`
+pub unsafe fn clone_into_cgroup(cgroup_dir: &File) -> std::io::Result<Fork> {
+    let mut pidfd: c_int = -1;
+    let mut args = CloneArgs {
+        flags: CLONE_INTO_CGROUP | CLONE_PIDFD as u64,
+        pidfd: &mut pidfd as *mut c_int as u64,
+        exit_signal: SIGCHLD as u64,
+        cgroup: cgroup_dir.as_raw_fd() as u64,
+        ..Default::default()
+    };
+
+    let pid = libc::syscall(
+        SYS_clone3,
+        &mut args as *mut CloneArgs,
+        std::mem::size_of::<CloneArgs>(),
+    );
+
+    match pid {
+        0 => Ok(Fork::Child),
+        pid if pid < 0 => Err(std::io::Error::last_os_error()),
+        pid => Ok(Fork::Parent {
+            pid: pid as u32,
+            pidfd: File::from_raw_fd(pidfd),
+        }),
+    }
+}
+
+/// Kernel lacks either clone3 (before 5.3) or its `CLONE_INTO_CGROUP` flag (before 5.7)
+pub fn is_unsupported(err: &std::io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(ENOSYS | E2BIG | EINVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::{
+        sys::{signal, wait::waitpid},
+        unistd::Pid,
+    };
+
+    use super::*;
+    use crate::testing::own_cgroup_dir;
+
+    #[tokio::test]
+    async fn given_cgroup_dir_then_child_starts_in_it() {
+        let cgroup_dir = File::open(own_cgroup_dir().await).unwrap();
+
+        // Safety: child only calls async-signal-safe functions
+        let pid = match unsafe { clone_into_cgroup(&cgroup_dir) } {
+            Err(err) if is_unsupported(&err) => return,
+            Ok(Fork::Child) => unsafe {
+                libc::pause();
+                libc::_exit(0);
+            },
+            Ok(Fork::Parent { pid, .. }) => Pid::from_raw(pid as i32),
+            Err(err) => panic!("{}", err),
+        };
+
+        let own = std::fs::read_to_string("/proc/self/cgroup").unwrap();
+        let child = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).unwrap();
+        signal::kill(pid, signal::Signal::SIGKILL).unwrap();
+        waitpid(pid, None).unwrap();
+
+        assert_eq!(own, child);
+    }
+}
@@ -0,0 +1,654 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    auth::Role,
+    cgroup,
+    controller::Settings,
+    logfile::{self, Limit},
+    rlimit::{self, Resource},
+    seccomp::{self, Policy},
+    uid::UidPool,
+    users::Users,
+    BASE_CG_PATH, BASE_PATH, CHANNEL_CAPACITY, READ_SIZE, USERS_DB, USER_RANGE,
+};
+
+// Section client settings are inherited from
+const DEFAULTS: &str = "defaults";
+// Completed jobs are looked for this often, unless configured otherwise
+const PRUNE_INTERVAL: u64 = 600;
+const CRL_INTERVAL: u64 = 300;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Failed to read config {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Malformed config: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("{0} must be an absolute path, got {1}")]
+    RelativePath(&'static str, PathBuf),
+    #[error("{0} must be greater than zero")]
+    Zero(&'static str),
+    #[error("Range of {0} is empty")]
+    EmptyRange(&'static str),
+    #[error("Ranges of provisioned users and of job uids overlap")]
+    Overlap,
+    #[error("No listen address is configured")]
+    NoListen,
+    #[error("No client is configured")]
+    NoClients,
+    #[error("Log limit of {0}: {1}")]
+    LogLimit(String, logfile::Error),
+    #[error("Seccomp policy of {0}: {1}")]
+    Seccomp(String, seccomp::Error),
+    #[error("Rlimits of {0}: {1}")]
+    Rlimit(String, rlimit::Error),
+    #[error("Cgroup limits of {0}: {1}")]
+    Cgroup(String, cgroup::Error),
+    #[error("{0} can't change without restart")]
+    RequiresRestart(&'static str),
+}
+
+/// Server configuration, validated as a whole when loaded
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub tls: Tls,
+    /// Roles of organizational units of client certificates, see `auth::Role`
+    pub roles: BTreeMap<String, Role>,
+    pub storage_root: PathBuf,
+    /// None in rootless mode unless configured, see `Config::settle_cgroup_root`
+    pub cgroup_root: Option<PathBuf>,
+    pub rootless: bool,
+    /// Pool settings of every client share
+    pub uids: Option<UidPool>,
+    /// How often completed jobs past their retention are removed
+    pub prune_interval: Duration,
+    /// How often CRL is re-read
+    pub crl_interval: Duration,
+    /// Settings of every client allowed to call the server, keyed by organization
+    pub clients: BTreeMap<String, Settings>,
+}
+
+/// Material of mutual TLS, in PEM format
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA client certificates are verified against
+    pub ca: PathBuf,
+    /// CRLs issued by CA, in PEM or DER format. Revoked client certificates are refused
+    pub crl: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    storage_root: Option<PathBuf>,
+    cgroup_root: Option<PathBuf>,
+    #[serde(default)]
+    rootless: bool,
+    // Jobs of every client run as uids leased from this range, if configured
+    uids: Option<Range<u32>>,
+    server: Server,
+    #[serde(default)]
+    roles: BTreeMap<String, Role>,
+    #[serde(default)]
+    buffers: Buffers,
+    #[serde(default)]
+    retention: Retention,
+    #[serde(default)]
+    users: UsersFile,
+    #[serde(default)]
+    defaults: Client,
+    #[serde(default)]
+    clients: BTreeMap<String, Client>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Server {
+    listen: Vec<SocketAddr>,
+    tls: Tls,
+    #[serde(default = "crl_interval")]
+    crl_interval_secs: u64,
+}
+
+fn crl_interval() -> u64 {
+    CRL_INTERVAL
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Buffers {
+    read_size: usize,
+    channel_capacity: usize,
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self {
+            read_size: READ_SIZE,
+            channel_capacity: CHANNEL_CAPACITY,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Retention {
+    // Completed jobs are kept forever if unset
+    max_age_secs: Option<u64>,
+    interval_secs: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_age_secs: None,
+            interval_secs: PRUNE_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UsersFile {
+    range: Range<u32>,
+    db: PathBuf,
+    useradd: bool,
+}
+
+impl Default for UsersFile {
+    fn default() -> Self {
+        Self {
+            range: USER_RANGE,
+            db: PathBuf::from(USERS_DB),
+            useradd: false,
+        }
+    }
+}
+
+// Defaults and limits of a client, unset ones are inherited from defaults section
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Client {
+    log_limit: Option<LogLimit>,
+    seccomp: Option<Seccomp>,
+    #[serde(default)]
+    rlimits: BTreeMap<String, u64>,
+    // Limits of client cgroup
+    cpu_weight: Option<u32>,
+    mem_high: Option<u64>,
+    mem_max: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogLimit {
+    max_bytes: u64,
+    #[serde(default)]
+    keep_segments: u32,
+    #[serde(default)]
+    policy: LogPolicy,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogPolicy {
+    #[default]
+    Rotate,
+    Truncate,
+    Kill,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Seccomp {
+    profile: String,
+    #[serde(default)]
+    action: SeccompAction,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SeccompAction {
+    #[default]
+    Errno,
+    Kill,
+}
+
+impl LogLimit {
+    fn limit(&self, client: &str) -> Result<Limit, Error> {
+        let limit = Limit {
+            max_bytes: self.max_bytes,
+            keep_segments: self.keep_segments,
+            policy: match self.policy {
+                LogPolicy::Rotate => logfile::Policy::Rotate,
+                LogPolicy::Truncate => logfile::Policy::Truncate,
+                LogPolicy::Kill => logfile::Policy::Kill,
+            },
+        };
+        limit
+            .validate()
+            .map_err(|err| Error::LogLimit(client.to_owned(), err))?;
+
+        Ok(limit)
+    }
+}
+
+impl Seccomp {
+    fn policy(&self, client: &str) -> Result<Policy, Error> {
+        Ok(Policy {
+            profile: self
+                .profile
+                .as_str()
+                .try_into()
+                .map_err(|err| Error::Seccomp(client.to_owned(), err))?,
+            action: match self.action {
+                SeccompAction::Errno => seccomp::Action::Errno,
+                SeccompAction::Kill => seccomp::Action::Kill,
+            },
+        })
+    }
+}
+
+impl Client {
+    // Settings of the client are overridden field by field, rlimits resource by resource
+    fn inherit(&self, defaults: &Client) -> Client {
+        let mut rlimits = defaults.rlimits.clone();
+        rlimits.extend(self.rlimits.clone());
+
+        Client {
+            log_limit: self.log_limit.or(defaults.log_limit),
+            seccomp: self.seccomp.clone().or_else(|| defaults.seccomp.clone()),
+            rlimits,
+            cpu_weight: self.cpu_weight.or(defaults.cpu_weight),
+            mem_high: self.mem_high.or(defaults.mem_high),
+            mem_max: self.mem_max.or(defaults.mem_max),
+        }
+    }
+
+    fn limits(&self, client: &str) -> Result<cgroup::Limits, Error> {
+        let limits = cgroup::Limits {
+            cpu_weight: self.cpu_weight,
+            mem_high: self.mem_high,
+            mem_max: self.mem_max,
+        };
+        limits
+            .validate()
+            .map_err(|err| Error::Cgroup(client.to_owned(), err))?;
+
+        Ok(limits)
+    }
+
+    fn rlimits(&self, client: &str) -> Result<BTreeMap<Resource, u64>, Error> {
+        self.rlimits
+            .iter()
+            .map(|(resource, max)| {
+                let resource = Resource::try_from(resource.as_str())
+                    .map_err(|err| Error::Rlimit(client.to_owned(), err))?;
+                Ok((resource, *max))
+            })
+            .collect()
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        std::fs::read_to_string(path)
+            .map_err(|err| Error::Read(path.to_owned(), err))?
+            .parse()
+    }
+
+    /// Settles cgroup root of every client, once on start as reloads carry it over. Unless
+    /// configured, rootless server uses cgroup delegated to it, moving itself into a leaf
+    /// of its own, see `cgroup::delegate`
+    pub async fn settle_cgroup_root(&mut self) -> Result<(), cgroup::Error> {
+        let cgroup_root = match &self.cgroup_root {
+            Some(cgroup_root) => cgroup_root.clone(),
+            None => cgroup::delegate().await?,
+        };
+        for settings in self.clients.values_mut() {
+            settings.cgroup_root = cgroup_root.clone();
+        }
+
+        Ok(())
+    }
+
+    /// Checks that config re-read while the server runs changes only what can change
+    /// without restart, and makes it share uid pool which tracks uids of running jobs
+    /// along with settled cgroup root
+    pub fn reloaded(mut self, current: &Config) -> Result<Self, Error> {
+        let range = |config: &Config| config.uids.as_ref().map(|uids| uids.range().clone());
+        for (name, changed) in [
+            ("server.listen", self.listen != current.listen),
+            ("storage_root", self.storage_root != current.storage_root),
+            ("cgroup_root", self.cgroup_root != current.cgroup_root),
+            ("rootless", self.rootless != current.rootless),
+            ("uids", range(&self) != range(current)),
+            (
+                "retention.interval_secs",
+                self.prune_interval != current.prune_interval,
+            ),
+            (
+                "server.crl_interval_secs",
+                self.crl_interval != current.crl_interval,
+            ),
+        ] {
+            if changed {
+                return Err(Error::RequiresRestart(name));
+            }
+        }
+
+        // Every client shares cgroup root, and there is always one
+        let cgroup_root = current
+            .clients
+            .values()
+            .next()
+            .map(|settings| settings.cgroup_root.clone());
+        self.uids = current.uids.clone();
+        for settings in self.clients.values_mut() {
+            settings.uids = current.uids.clone();
+            if let Some(cgroup_root) = &cgroup_root {
+                settings.cgroup_root = cgroup_root.clone();
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        let file: File = toml::from_str(contents)?;
+
+        let storage_root = file
+            .storage_root
+            .unwrap_or_else(|| PathBuf::from(BASE_PATH));
+        let cgroup_root = match file.cgroup_root {
+            Some(root) => Some(root),
+            None if file.rootless => None,
+            None => Some(PathBuf::from(BASE_CG_PATH)),
+        };
+        for (name, path) in [
+            ("storage_root", Some(&storage_root)),
+            ("cgroup_root", cgroup_root.as_ref()),
+            ("users.db", Some(&file.users.db)),
+            ("server.tls.cert", Some(&file.server.tls.cert)),
+            ("server.tls.key", Some(&file.server.tls.key)),
+            ("server.tls.ca", Some(&file.server.tls.ca)),
+            ("server.tls.crl", file.server.tls.crl.as_ref()),
+        ] {
+            match path {
+                Some(path) if path.is_relative() => {
+                    return Err(Error::RelativePath(name, path.to_owned()))
+                }
+                _ => (),
+            }
+        }
+
+        if file.buffers.read_size == 0 {
+            return Err(Error::Zero("buffers.read_size"));
+        }
+        if file.buffers.channel_capacity == 0 {
+            return Err(Error::Zero("buffers.channel_capacity"));
+        }
+        if file.retention.interval_secs == 0 {
+            return Err(Error::Zero("retention.interval_secs"));
+        }
+        if file.server.crl_interval_secs == 0 {
+            return Err(Error::Zero("server.crl_interval_secs"));
+        }
+        if file.users.range.is_empty() {
+            return Err(Error::EmptyRange("users.range"));
+        }
+        if let Some(uids) = &file.uids {
+            if uids.is_empty() {
+                return Err(Error::EmptyRange("uids"));
+            }
+            // Uid of a job would otherwise belong to provisioned user as well
+            if uids.start < file.users.range.end && file.users.range.start < uids.end {
+                return Err(Error::Overlap);
+            }
+        }
+        if file.server.listen.is_empty() {
+            return Err(Error::NoListen);
+        }
+        if file.clients.is_empty() {
+            return Err(Error::NoClients);
+        }
+
+        // Single pool and database are shared by all clients
+        let uids = file.uids.map(UidPool::new);
+        let users = Users::new(file.users.range, file.users.db).set_useradd(file.users.useradd);
+        // Delegated cgroup root is settled only once server runs
+        let defaults = Settings {
+            cgroup_root: cgroup_root
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(BASE_CG_PATH)),
+            rootless: file.rootless,
+            storage_root: storage_root.clone(),
+            read_size: file.buffers.read_size,
+            channel_capacity: file.buffers.channel_capacity,
+            retention: file.retention.max_age_secs.map(Duration::from_secs),
+            uids: uids.clone(),
+            users,
+            ..Default::default()
+        };
+
+        // Defaults are validated even if every client overrides them
+        let default_log_limit = file.defaults.log_limit.as_ref();
+        default_log_limit
+            .map(|log_limit| log_limit.limit(DEFAULTS))
+            .transpose()?;
+        let default_seccomp = file.defaults.seccomp.as_ref();
+        default_seccomp
+            .map(|seccomp| seccomp.policy(DEFAULTS))
+            .transpose()?;
+        file.defaults.rlimits(DEFAULTS)?;
+        file.defaults.limits(DEFAULTS)?;
+
+        let clients = file
+            .clients
+            .iter()
+            .map(|(name, client)| {
+                let client = client.inherit(&file.defaults);
+                let settings = Settings {
+                    log_limit: client
+                        .log_limit
+                        .map(|log_limit| log_limit.limit(name))
+                        .transpose()?,
+                    seccomp: client
+                        .seccomp
+                        .as_ref()
+                        .map(|seccomp| seccomp.policy(name))
+                        .transpose()?,
+                    rlimits: client.rlimits(name)?,
+                    limits: client.limits(name)?,
+                    ..defaults.clone()
+                };
+                Ok((name.clone(), settings))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            listen: file.server.listen,
+            tls: file.server.tls,
+            roles: file.roles,
+            storage_root,
+            cgroup_root,
+            rootless: file.rootless,
+            uids,
+            prune_interval: Duration::from_secs(file.retention.interval_secs),
+            crl_interval: Duration::from_secs(file.server.crl_interval_secs),
+            clients,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        storage_root = "/var/lib/pls"
+        uids = { start = 3000000, end = 3000100 }
+
+        [roles]
+        sre = "admin"
+
+        [server]
+        listen = ["[::1]:50051"]
+        tls = { cert = "/etc/pls/cert", key = "/etc/pls/key", ca = "/etc/pls/ca" }
+
+        [retention]
+        max_age_secs = 3600
+
+        [defaults]
+        seccomp = { profile = "default" }
+        rlimits = { nofile = 1024, core = 0 }
+
+        [clients.acme]
+        seccomp = { profile = "strict", action = "kill" }
+        rlimits = { nofile = 4096 }
+        log_limit = { max_bytes = 1048576, policy = "truncate" }
+
+        [clients.initech]
+    "#;
+
+    #[test]
+    fn given_client_section_then_it_overrides_defaults() {
+        let config: Config = CONFIG.parse().unwrap();
+        assert_eq!(config.cgroup_root, Some(PathBuf::from("/sys/fs/cgroup")));
+        assert_eq!(config.prune_interval, Duration::from_secs(PRUNE_INTERVAL));
+        assert_eq!(
+            config.roles,
+            BTreeMap::from([("sre".to_owned(), Role::Admin)])
+        );
+
+        let acme = &config.clients["acme"];
+        assert_eq!(acme.storage_root, PathBuf::from("/var/lib/pls"));
+        assert_eq!(acme.retention, Some(Duration::from_secs(3600)));
+        assert_eq!(
+            acme.seccomp,
+            Some(Policy {
+                profile: seccomp::Profile::Strict,
+                action: seccomp::Action::Kill,
+            })
+        );
+        assert_eq!(
+            acme.rlimits,
+            BTreeMap::from([(Resource::Nofile, 4096), (Resource::Core, 0)])
+        );
+        assert_eq!(
+            acme.log_limit.map(|limit| limit.policy),
+            Some(logfile::Policy::Truncate)
+        );
+
+        let initech = &config.clients["initech"];
+        assert_eq!(
+            initech.seccomp.map(|policy| policy.profile),
+            Some(seccomp::Profile::Default)
+        );
+        assert_eq!(initech.log_limit, None);
+        assert_eq!(initech.read_size, READ_SIZE);
+    }
+
+    #[test]
+    fn given_invalid_config_then_error_names_offending_setting() {
+        let config = |from: &str, to: &str| CONFIG.replace(from, to).parse::<Config>();
+
+        assert!(matches!(
+            config("profile = \"strict\"", "profile = \"lax\""),
+            Err(Error::Seccomp(client, _)) if client == "acme"
+        ));
+        assert!(matches!(
+            config("core = 0", "cores = 0"),
+            Err(Error::Rlimit(client, _)) if client == DEFAULTS
+        ));
+        assert!(matches!(
+            config("\"/var/lib/pls\"", "\"pls\""),
+            Err(Error::RelativePath("storage_root", _))
+        ));
+        assert!(matches!(
+            config("start = 3000000", "start = 2000000"),
+            Err(Error::Overlap)
+        ));
+        assert!(matches!(
+            config("[\"[::1]:50051\"]", "[]"),
+            Err(Error::NoListen)
+        ));
+        assert!(matches!(
+            config("max_age_secs", "max_age"),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            config("\"admin\"", "\"root\""),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            config("[clients.initech]", "[clients.initech]\ncpu_weight = 0"),
+            Err(Error::Cgroup(client, _)) if client == "initech"
+        ));
+        assert!(matches!(
+            config("max_bytes = 1048576", "max_bytes = 0"),
+            Err(Error::LogLimit(client, _)) if client == "acme"
+        ));
+        assert!(matches!(
+            config(
+                "[clients.initech]",
+                "[clients.initech]\nlog_limit = { max_bytes = 1024, policy = \"rotate\" }"
+            ),
+            Err(Error::LogLimit(client, logfile::Error::NoSegments)) if client == "initech"
+        ));
+    }
+
+    #[test]
+    fn given_reloaded_config_then_only_client_settings_change() {
+        let mut current: Config = CONFIG.parse().unwrap();
+        for settings in current.clients.values_mut() {
+            settings.cgroup_root = PathBuf::from("/sys/fs/cgroup/delegated/pls");
+        }
+        let lease = current.uids.as_ref().unwrap().lease().unwrap();
+        let reload = |from: &str, to: &str| {
+            CONFIG
+                .replace(from, to)
+                .parse::<Config>()
+                .unwrap()
+                .reloaded(&current)
+        };
+
+        let reloaded = reload("[clients.initech]", "[clients.initech]\nmem_max = 1048576").unwrap();
+        assert_eq!(reloaded.clients["initech"].limits.mem_max, Some(1048576));
+        // Uid leased before reload is not leased again
+        let uids = reloaded.clients["initech"].uids.as_ref().unwrap();
+        assert_ne!(uids.lease().unwrap().uid(), lease.uid());
+        // Cgroup root settled on start is not settled again
+        assert!(reloaded
+            .clients
+            .values()
+            .all(|settings| settings.cgroup_root == Path::new("/sys/fs/cgroup/delegated/pls")));
+
+        assert!(matches!(
+            reload("[::1]:50051", "[::1]:50052"),
+            Err(Error::RequiresRestart("server.listen"))
+        ));
+        assert!(matches!(
+            reload("end = 3000100", "end = 3000200"),
+            Err(Error::RequiresRestart("uids"))
+        ));
+    }
+}
@@ -1,47 +1,130 @@
-use crate::job::{Job, Started};
-use crate::runner::{self, JobRequest, LogMessage};
-use log::error;
+use crate::job::{Input, Job, Started};
+use crate::pidfd::PidFd;
+use crate::runner::{
+    self, job_metadata::Compression, job_status::Outcome, JobMetadata, JobRequest, LogMessage,
+};
+use inotify::{Inotify, WatchMask};
+use log::{error, info};
+use regex::bytes::Regex;
 use thiserror::Error;
-use tokio::{
-    io::AsyncReadExt,
-    sync::{
-        mpsc::{self, Receiver},
-        watch,
-    },
+use tokio::sync::{
+    mpsc::{self, Receiver},
+    watch,
 };
 
-use nix::libc::getpwnam;
-// Synchronously adds user during setup phase to avoid
-// dealing with boxing of recursive future
-fn useradd<'c>(client: &'c str) -> Result<(), Error> {
-    let args = vec!["-s", "/sbin/nologin", "-U", client];
-    std::process::Command::new("useradd").args(&args).status()?;
-
-    Ok(())
-}
+use nix::{
+    libc::{c_int, ESRCH, O_DIRECTORY},
+    unistd::{chown, getgrouplist, Gid, Uid},
+};
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     ffi::{CString, NulError},
-    path::Path,
+    fs::File,
+    io::ErrorKind,
+    os::unix::io::{AsRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use tokio::fs::{create_dir_all, File};
+use tokio::fs::create_dir_all;
+use tokio_stream::StreamExt;
 
 use uuid::Uuid;
 
-use crate::{cgroup, job, BASE_CG_PATH, BASE_PATH};
+use crate::{
+    cgroup, job,
+    logfile::{self, Decoder, Fd, Filter, Format, Limit, Reader, Selection, Start},
+    metadata,
+    namespace::{Namespaces, UserNamespace},
+    rlimit::{self, Resource, Rlimits},
+    rootfs::{self, Rootfs, ROOTFS_DIR},
+    runner::job_request::rootfs::Source,
+    seccomp::{self, Policy},
+    uid::{self, Lease, UidPool},
+    users::{self, Users},
+    Empty, BASE_CG_PATH, BASE_PATH, CHANNEL_CAPACITY, READ_SIZE,
+};
 
-pub enum Fd {
-    Out,
-    Err,
+#[derive(Debug, Default, Clone)]
+pub struct OutputOptions {
+    /// Start of combined log for jobs with combined log format
+    pub out: Start,
+    pub err: Start,
+    pub fds: Selection,
+    /// Matched against each line without its trailing newline
+    pub pattern: Option<Regex>,
 }
 
-impl From<Fd> for i32 {
-    fn from(fd: Fd) -> Self {
-        match fd {
-            Fd::Out => 0,
-            Fd::Err => 1,
+impl TryFrom<&runner::OutputRequest> for OutputOptions {
+    type Error = Error;
+
+    fn try_from(value: &runner::OutputRequest) -> Result<Self, Self::Error> {
+        let (out, err) = match value.tail {
+            Some(lines) => (Start::Tail(lines), Start::Tail(lines)),
+            None => (
+                Start::Offset(value.out_offset),
+                Start::Offset(value.err_offset),
+            ),
+        };
+        let pattern = value.pattern.as_deref().map(Regex::new).transpose()?;
+
+        Ok(Self {
+            out,
+            err,
+            fds: value.fds().into(),
+            pattern,
+        })
+    }
+}
+
+/// Per client settings applied to all of its jobs
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Ceiling for job log limits, as well as the limit of jobs which don't request one
+    pub log_limit: Option<Limit>,
+    /// Seccomp policy jobs can only tighten, as well as the policy of jobs which don't request one
+    pub seccomp: Option<Policy>,
+    /// Maximum hard rlimits of jobs, applied to jobs which don't request them as well
+    pub rlimits: BTreeMap<Resource, u64>,
+    /// Jobs run as uids of their own leased from the pool, with gid of the client
+    /// kept for shared access, rather than as the client user
+    pub uids: Option<UidPool>,
+    /// Provisions client user on first use
+    pub users: Users,
+    /// Cgroup under which client and job cgroups are created, see `cgroup::delegate`
+    pub cgroup_root: PathBuf,
+    /// Jobs enter user namespace mapping their uid and gid onto those of the server,
+    /// rather than switching to them, so that the server doesn't need to run as root
+    pub rootless: bool,
+    /// Directory under which client and job directories are created
+    pub storage_root: PathBuf,
+    /// Size of chunks logs are read in, and so the most output a single message carries
+    pub read_size: usize,
+    /// Messages buffered for output and attach streams before the reader waits for the caller
+    pub channel_capacity: usize,
+    /// Completed jobs are kept this long before `Controller::prune` removes them, forever if unset
+    pub retention: Option<Duration>,
+    /// Limits of client cgroup, which all jobs of the client share
+    pub limits: cgroup::Limits,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            log_limit: None,
+            seccomp: None,
+            rlimits: BTreeMap::new(),
+            uids: None,
+            users: Users::default(),
+            cgroup_root: PathBuf::from(BASE_CG_PATH),
+            rootless: false,
+            storage_root: PathBuf::from(BASE_PATH),
+            read_size: READ_SIZE,
+            channel_capacity: CHANNEL_CAPACITY,
+            retention: None,
+            limits: Default::default(),
         }
     }
 }
@@ -51,7 +134,11 @@ pub struct Controller<'c, J> {
     client: &'c str,
     client_uid: u32,
     client_gid: u32,
+    // Supplementary groups of the client, job is a member of them as well
+    client_groups: Vec<u32>,
+    settings: Settings,
     jobs: HashMap<Uuid, J>,
+    disk_stats: Arc<Mutex<runner::DiskStats>>,
 }
 
 #[derive(Debug, Error)]
@@ -68,24 +155,94 @@ pub enum Error {
     JobNotFound(Uuid),
     #[error(transparent)]
     Cgroup(#[from] cgroup::Error),
+    #[error("Stdin of job({0}) is closed")]
+    StdinClosed(Uuid),
+    #[error("Job({0}) is not attached to a tty")]
+    NotATty(Uuid),
+    #[error("Output of job({0}) is lost, its terminal is gone along with previous server")]
+    OutputLost(Uuid),
+    #[error("Working directory {0} is outside of client directory")]
+    InvalidWorkingDir(PathBuf),
+    #[error("Rootfs path {0} is outside of client directory")]
+    InvalidRootfs(PathBuf),
+    #[error("Failed to unpack rootfs tarball {0}")]
+    Unpack(PathBuf),
+    #[error(transparent)]
+    Rootfs(#[from] rootfs::Error),
+    #[error(transparent)]
+    LogLimit(#[from] logfile::Error),
+    #[error(transparent)]
+    Seccomp(#[from] seccomp::Error),
+    #[error(transparent)]
+    Rlimit(#[from] rlimit::Error),
+    #[error(transparent)]
+    Uid(#[from] uid::Error),
+    #[error(transparent)]
+    Users(#[from] users::Error),
+    #[error("Invalid output pattern")]
+    InvalidPattern(#[from] regex::Error),
+    #[error(transparent)]
+    Metadata(#[from] metadata::Error),
+    #[error("{0}")]
+    InvalidOffset(String),
 }
 
 impl<'c> Controller<'c, Job<Started>> {
     pub async fn new(client: &'c str) -> Result<Controller<'c, Job<Started>>, Error> {
-        let (client_uid, client_gid) = Self::ensure_uid_gid(client)?;
-        let cgroup_dir = Path::new(BASE_CG_PATH).join(client);
+        Self::with_settings(client, Settings::default()).await
+    }
+
+    pub async fn with_settings(
+        client: &'c str,
+        settings: Settings,
+    ) -> Result<Controller<'c, Job<Started>>, Error> {
+        let (client_uid, client_gid) = settings.users.ensure(client)?;
+        let client_groups = Self::groups(client, client_gid)?;
+        let cgroup_dir = settings.cgroup_root.join(client);
         create_dir_all(&cgroup_dir).await?;
         cgroup::enable_subtree(&cgroup_dir, cgroup::Controller::all()).await?;
+        cgroup::set_limits(&cgroup_dir, &settings.limits).await?;
 
-        Ok(Self {
+        let mut controller = Self {
             client,
             client_uid,
             client_gid,
+            client_groups,
+            settings,
             jobs: HashMap::new(),
-        })
+            disk_stats: Default::default(),
+        };
+        let client_dir = controller.settings.storage_root.join(client);
+        controller.restore_jobs(&client_dir, &cgroup_dir).await?;
+
+        Ok(controller)
     }
 
+    /// Controller running jobs as root, with neither client user nor client cgroup provisioned
+    #[cfg(test)]
+    pub(crate) fn unprovisioned(client: &'c str, settings: Settings) -> Self {
+        Self {
+            client,
+            client_uid: 0,
+            client_gid: 0,
+            client_groups: vec![0],
+            settings,
+            jobs: Default::default(),
+            disk_stats: Default::default(),
+        }
+    }
+
+    /// Starts job and registers it. Callers sharing the controller should rather `launch`
+    /// the job, start it without holding the controller and only then `register` it
     pub async fn start(&mut self, job_request: JobRequest) -> Result<Uuid, Error> {
+        let launched = self.launch(job_request)?.start().await?;
+        Ok(self.register(launched))
+    }
+
+    /// Checks job against settings of the client, opens its host paths and leases its uid,
+    /// before anything is created for the job. Leaves the slow part of starting it
+    /// (unpacking rootfs, setting up cgroup, spawning) to `Launch::start`
+    pub fn launch(&self, job_request: JobRequest) -> Result<Launch, Error> {
         let job = Job::default();
         let job_id = job
             .id()
@@ -93,26 +250,310 @@ impl<'c> Controller<'c, Job<Started>> {
             .encode_lower(&mut Uuid::encode_buffer())
             .to_owned();
 
-        let job_dir = Path::new(BASE_PATH).join(self.client).join(&job_id);
-        create_dir_all(&job_dir).await?;
-
-        let cgroup_dir = Path::new(BASE_CG_PATH).join(self.client).join(&job_id);
-        create_dir_all(&cgroup_dir).await?;
-        cgroup::set_cpu_control(&cgroup_dir, &job_request).await?;
-        cgroup::set_mem_control(&cgroup_dir, &job_request).await?;
-        cgroup::set_io_control(&cgroup_dir, &job_request).await?;
+        let lease = self
+            .settings
+            .uids
+            .as_ref()
+            .map(UidPool::lease)
+            .transpose()?;
+        let uid = lease.as_ref().map_or(self.client_uid, Lease::uid);
+        // Groups other than the mapped one don't exist within user namespace
+        let groups = if self.settings.rootless {
+            vec![]
+        } else {
+            self.client_groups.clone()
+        };
+        let log_limit = self.log_limit(&job_request)?;
+        let filter = self.seccomp(&job_request)?.map(seccomp::Filter::from);
+        let rlimits = Rlimits::new(&job_request.rlimits, &self.settings.rlimits)?;
+        let client_dir = self.settings.storage_root.join(self.client);
+        // Working dir of job with rootfs lies within rootfs
+        let (working_dir, rootfs) = match &job_request.rootfs {
+            Some(rootfs) => (
+                None,
+                Some(RootfsSource::open(
+                    &client_dir,
+                    rootfs,
+                    &job_request.working_dir,
+                )?),
+            ),
+            None => {
+                let working_dir = job_request
+                    .working_dir
+                    .as_deref()
+                    .map(|working_dir| open_working_dir(&client_dir, working_dir))
+                    .transpose()?;
+                (working_dir, None)
+            }
+        };
 
-        let job = job
-            .add_command(&job_request)
-            .add_to_cgroup(cgroup_dir)?
-            .set_ownership(self.client_uid, self.client_gid)
-            .set_job_dir(job_dir)
-            .spawn()?;
+        Ok(Launch {
+            job,
+            owner: self.client.to_owned(),
+            job_dir: client_dir.join(&job_id),
+            cgroup_dir: self.settings.cgroup_root.join(self.client).join(&job_id),
+            job_request,
+            working_dir,
+            rootfs,
+            lease,
+            uid,
+            gid: self.client_gid,
+            groups,
+            rootless: self.settings.rootless,
+            log_limit,
+            filter,
+            rlimits,
+        })
+    }
 
+    /// Takes over job started by `Launch::start`, so that it is found by id from then on
+    pub fn register(&mut self, launched: Launched) -> Uuid {
+        let Launched {
+            job,
+            metadata,
+            lease,
+        } = launched;
+        self.on_completion(&job, metadata, lease);
         let job_id: Uuid = job.id().to_owned();
         self.jobs.insert(job_id, job);
 
-        Ok(job_id)
+        job_id
+    }
+
+    // Final status is persisted, logs are compressed, unpacked rootfs is removed
+    // and leased uid is released in the background once job completes
+    fn on_completion(&self, job: &Job<Started>, mut metadata: JobMetadata, lease: Option<Lease>) {
+        let job_id = *job.id();
+        let job_dir = job.job_dir().to_owned();
+        let cgroup_dir = job.cgroup_dir().to_owned();
+        let format = job.log_format();
+        let completion = job.completion();
+        let disk_stats = self.disk_stats.clone();
+
+        tokio::spawn(async move {
+            metadata.status = Some(completion.await);
+            metadata.ended_at = Some(logfile::timestamp());
+            if let Err(err) = metadata::write(&job_dir, &metadata).await {
+                error!("Failed to persist status of job({}): {}", job_id, err);
+            }
+
+            match logfile::compress(&job_dir, format).await {
+                Ok(savings) => {
+                    let compression = Compression {
+                        original_bytes: savings.original,
+                        compressed_bytes: savings.compressed,
+                    };
+                    count_savings(&disk_stats, &compression);
+                    // Lets disk stats be rebuilt after restart
+                    metadata.compression = Some(compression);
+                    if let Err(err) = metadata::write(&job_dir, &metadata).await {
+                        error!("Failed to persist log savings of job({}): {}", job_id, err);
+                    }
+                }
+                Err(err) => error!("Failed to compress logs of job({}): {}", job_id, err),
+            }
+
+            // Unpacked rootfs is of no use past the job
+            match tokio::fs::remove_dir_all(job_dir.join(ROOTFS_DIR)).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    error!("Failed to remove rootfs of job({}): {}", job_id, err)
+                }
+                _ => (),
+            }
+
+            if let Some(lease) = lease {
+                release(job_id, cgroup_dir, lease).await;
+            }
+        });
+    }
+
+    // Uid leased to job adopted after restart, None unless client runs jobs as uids of their own
+    fn reserve(&self, job_id: Uuid, metadata: &JobMetadata) -> Option<Lease> {
+        let (pool, uid) = self.settings.uids.as_ref().zip(metadata.uid)?;
+        let lease = pool.reserve(uid);
+        if lease.is_none() {
+            error!("Uid {} of job({}) is not available for lease", uid, job_id);
+        }
+
+        lease
+    }
+
+    // Job limit is capped by the client one, which also applies to jobs not requesting any
+    fn log_limit(&self, job_request: &JobRequest) -> Result<Option<Limit>, Error> {
+        let limit = job_request
+            .log_limit
+            .as_ref()
+            .map(Limit::try_from)
+            .transpose()?;
+
+        Ok(match (limit, &self.settings.log_limit) {
+            (Some(limit), Some(ceiling)) => Some(limit.within(ceiling)?),
+            (limit, ceiling) => limit.or_else(|| ceiling.to_owned()),
+        })
+    }
+
+    // Job policy can't be weaker than the client one, which also applies to jobs not requesting any
+    fn seccomp(&self, job_request: &JobRequest) -> Result<Option<Policy>, Error> {
+        let policy = job_request
+            .seccomp
+            .as_ref()
+            .map(Policy::try_from)
+            .transpose()?;
+
+        Ok(match (policy, &self.settings.seccomp) {
+            (Some(policy), Some(floor)) => Some(policy.within(floor)),
+            (policy, floor) => policy.or_else(|| floor.to_owned()),
+        })
+    }
+
+    // Rebuilds table of jobs from metadata kept in job dirs. Jobs still running
+    // are adopted, those which exited while server was down are marked lost
+    async fn restore_jobs(&mut self, client_dir: &Path, cgroup_dir: &Path) -> Result<(), Error> {
+        let mut entries = match tokio::fs::read_dir(client_dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let job_id = match file_name.to_str().map(Uuid::parse_str) {
+                Some(Ok(job_id)) => job_id,
+                // Not a job dir
+                _ => continue,
+            };
+
+            let job_dir = entry.path();
+            let mut metadata = match metadata::read(&job_dir).await {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    error!("Failed to read metadata of job({}): {}", job_id, err);
+                    continue;
+                }
+            };
+            let request = match metadata.request.clone() {
+                Some(request) => request,
+                None => {
+                    error!("Metadata of job({}) is missing its request", job_id);
+                    continue;
+                }
+            };
+
+            let cgroup_dir = cgroup_dir.join(&file_name);
+            let job = match metadata.status.clone() {
+                Some(status) => {
+                    self.restore_savings(job_id, &request, &mut metadata, &job_dir)
+                        .await;
+                    Job::restore(job_id, &request, &status, job_dir, cgroup_dir)
+                }
+                None => match self
+                    .adopt(job_id, &request, &metadata, &job_dir, &cgroup_dir)
+                    .await
+                {
+                    Ok(Some(job)) => {
+                        let lease = self.reserve(job_id, &metadata);
+                        self.on_completion(&job, metadata, lease);
+                        job
+                    }
+                    outcome => {
+                        if let Err(err) = outcome {
+                            error!("Failed to adopt job({}): {}", job_id, err);
+                        }
+                        // Processes of the job may still be around, uid is released once
+                        // they are gone
+                        if let Some(lease) = self.reserve(job_id, &metadata) {
+                            tokio::spawn(release(job_id, cgroup_dir.clone(), lease));
+                        }
+                        let status = runner::JobStatus {
+                            outcome: Some(Outcome::Lost(true)),
+                            ..Default::default()
+                        };
+                        let job =
+                            Job::restore(job_id, &request, &status, job_dir.clone(), cgroup_dir);
+                        metadata.status = Some(status);
+                        metadata::write(&job_dir, &metadata).await?;
+                        job
+                    }
+                },
+            };
+            self.jobs.insert(job_id, job);
+        }
+
+        Ok(())
+    }
+
+    // Job process is looked up in job cgroup, pidfd is opened in between two reads
+    // of cgroup.procs, so that reused pid is never mistaken for job process
+    async fn adopt(
+        &self,
+        job_id: Uuid,
+        request: &JobRequest,
+        metadata: &JobMetadata,
+        job_dir: &Path,
+        cgroup_dir: &Path,
+    ) -> Result<Option<Job<Started>>, Error> {
+        let pid = match metadata.pid {
+            Some(pid) if cgroup::procs(cgroup_dir).await?.contains(&pid) => pid,
+            _ => return Ok(None),
+        };
+        let pidfd = match PidFd::open(pid) {
+            Ok(pidfd) => pidfd,
+            Err(err) if err.raw_os_error() == Some(ESRCH) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if !cgroup::procs(cgroup_dir).await?.contains(&pid) {
+            return Ok(None);
+        }
+
+        // Job started by older server may carry limit rejected since, client one applies then
+        let log_limit = self.log_limit(request).unwrap_or(self.settings.log_limit);
+        let job = Job::adopt(
+            job_id,
+            request,
+            pidfd,
+            job_dir.to_owned(),
+            cgroup_dir.to_owned(),
+            log_limit,
+        )
+        .await?;
+
+        Ok(Some(job))
+    }
+
+    // Counts savings of logs of job completed before restart, which are read from the logs
+    // themselves if they were compressed without persisting savings
+    async fn restore_savings(
+        &self,
+        job_id: Uuid,
+        request: &JobRequest,
+        metadata: &mut JobMetadata,
+        job_dir: &Path,
+    ) {
+        if metadata.compression.is_none() {
+            match logfile::savings(job_dir, request.log_format().into()).await {
+                Ok(savings) if savings.compressed > 0 => {
+                    metadata.compression = Some(Compression {
+                        original_bytes: savings.original,
+                        compressed_bytes: savings.compressed,
+                    });
+                    if let Err(err) = metadata::write(job_dir, metadata).await {
+                        error!("Failed to persist log savings of job({}): {}", job_id, err);
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => error!("Failed to read log savings of job({}): {}", job_id, err),
+            }
+        }
+
+        if let Some(compression) = &metadata.compression {
+            count_savings(&self.disk_stats, compression);
+        }
+    }
+
+    /// Disk savings of compressing logs of completed jobs which are kept
+    pub fn stats(&self) -> runner::DiskStats {
+        // Unwrap: lock is never held across panicking code
+        self.disk_stats.lock().unwrap().clone()
     }
 
     pub async fn status(&self, job_id: Uuid) -> Result<runner::JobStatus, Error> {
@@ -120,6 +561,47 @@ impl<'c> Controller<'c, Job<Started>> {
         Ok(job.status())
     }
 
+    pub async fn stdin(&self, job_id: Uuid) -> Result<mpsc::Sender<Input>, Error> {
+        let job = self.jobs.get(&job_id).ok_or(Error::JobNotFound(job_id))?;
+        match job.stdin() {
+            Some(stdin) if !stdin.is_closed() => Ok(stdin),
+            _ => Err(Error::StdinClosed(job_id)),
+        }
+    }
+
+    /// Input to the job terminal along with output following its log
+    pub async fn attach(
+        &self,
+        job_id: Uuid,
+    ) -> Result<(mpsc::Sender<Input>, Receiver<Result<LogMessage, Error>>), Error> {
+        let job = self.jobs.get(&job_id).ok_or(Error::JobNotFound(job_id))?;
+        if !job.is_tty() {
+            return Err(Error::NotATty(job_id));
+        }
+        if job.is_output_lost() {
+            return Err(Error::OutputLost(job_id));
+        }
+
+        let stdin = self.stdin(job_id).await?;
+        let (tx, rx) = mpsc::channel(self.settings.channel_capacity);
+        // pty merges stdout and stderr, err is always empty
+        let reader =
+            Reader::open(job.job_dir(), job.log_format(), Fd::Out, Start::default()).await?;
+        Self::watch_file(
+            Fd::Out,
+            job.log_format(),
+            job.job_dir(),
+            reader,
+            Filter::default(),
+            job.subscribe(),
+            tx,
+            self.settings.read_size,
+        )
+        .await;
+
+        Ok((stdin, rx))
+    }
+
     pub async fn stop(&mut self, job_id: Uuid) -> runner::Ack {
         if let Some(job) = self.jobs.get(&job_id) {
             job.cancel();
@@ -128,67 +610,183 @@ impl<'c> Controller<'c, Job<Started>> {
         runner::Ack {}
     }
 
-    pub async fn output(&self, job_id: Uuid) -> Result<Receiver<Result<LogMessage, Error>>, Error> {
+    /// Applies settings to jobs started from now on, and limits to client cgroup,
+    /// running jobs are left as they are
+    pub async fn update(&mut self, settings: Settings) -> Result<(), Error> {
+        let cgroup_dir = settings.cgroup_root.join(self.client);
+        cgroup::set_limits(&cgroup_dir, &settings.limits).await?;
+        self.settings = settings;
+
+        Ok(())
+    }
+
+    /// Removes jobs which completed longer than `Settings::retention` ago, along with
+    /// their directories and cgroups. Returns number of jobs removed
+    pub async fn prune(&mut self) -> usize {
+        let retention = match self.settings.retention {
+            Some(retention) => retention.as_micros() as u64,
+            None => return 0,
+        };
+        let now = logfile::timestamp();
+
+        let mut expired = vec![];
+        for (job_id, job) in self.jobs.iter().filter(|(_, job)| job.is_complete()) {
+            match metadata::read(job.job_dir()).await {
+                // End time is persisted shortly after completion, job is kept until then
+                Ok(JobMetadata {
+                    ended_at: Some(ended_at),
+                    compression,
+                    ..
+                }) if now.saturating_sub(ended_at) >= retention => {
+                    expired.push((*job_id, compression))
+                }
+                Ok(_) => (),
+                Err(err) => error!("Failed to read metadata of job({}): {}", job_id, err),
+            }
+        }
+
+        for (job_id, compression) in &expired {
+            // Ids were collected from jobs just above
+            let job = self.jobs.remove(job_id).unwrap();
+            if let Some(compression) = compression {
+                discount_savings(&self.disk_stats, compression);
+            }
+            if let Err(err) = tokio::fs::remove_dir_all(job.job_dir()).await {
+                error!("Failed to remove dir of job({}): {}", job_id, err);
+            }
+            match tokio::fs::remove_dir(job.cgroup_dir()).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    error!("Failed to remove cgroup of job({}): {}", job_id, err)
+                }
+                _ => (),
+            }
+        }
+
+        expired.len()
+    }
+
+    pub async fn output(
+        &self,
+        job_id: Uuid,
+        options: OutputOptions,
+    ) -> Result<Receiver<Result<LogMessage, Error>>, Error> {
         let job = self.jobs.get(&job_id).ok_or(Error::JobNotFound(job_id))?;
         let job_dir = job.job_dir();
+        let format = job.log_format();
+        // Single combined log holds output of both fds
+        let fds: &[(Fd, Start)] = match format {
+            Format::Separate => &[(Fd::Out, options.out), (Fd::Err, options.err)],
+            Format::Combined => &[(Fd::Out, options.out)],
+        };
 
-        let (tx, rx) = mpsc::channel(20);
-
-        if job.is_complete() {
-            Self::read_file(Fd::Out, job_dir, tx.clone()).await;
-            Self::read_file(Fd::Err, job_dir, tx).await;
+        let (tx, rx) = mpsc::channel(self.settings.channel_capacity);
+        let read_size = self.settings.read_size;
 
-            Ok(rx)
-        } else {
-            let completion = job.subscribe();
-            Self::watch_file(Fd::Out, job_dir, completion.clone(), tx.clone()).await;
-            Self::watch_file(Fd::Err, job_dir, completion, tx).await;
+        for (fd, start) in fds {
+            if format == Format::Separate && !options.fds.contains(*fd) {
+                continue;
+            }
 
-            Ok(rx)
+            // Opened upfront, so that offset the log can't be read from fails the call
+            let reader = match Reader::open(job_dir, format, *fd, *start).await {
+                Ok(reader) => reader,
+                Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                    return Err(Error::InvalidOffset(err.to_string()))
+                }
+                Err(err) => {
+                    error!("Failed to open log of job({}): {}", job_id, err);
+                    continue;
+                }
+            };
+            let filter = Filter::new(options.fds, options.pattern.clone());
+            if job.is_output_lost() {
+                // What was logged before restart is all there is, pty merges both fds into out
+                let lost = (*fd == Fd::Out).then_some(Error::OutputLost(job_id));
+                Self::read_file(*fd, format, reader, filter, tx.clone(), read_size, lost).await;
+            } else if job.is_complete() {
+                Self::read_file(*fd, format, reader, filter, tx.clone(), read_size, None).await;
+            } else {
+                let completion = job.subscribe();
+                Self::watch_file(
+                    *fd,
+                    format,
+                    job_dir,
+                    reader,
+                    filter,
+                    completion,
+                    tx.clone(),
+                    read_size,
+                )
+                .await;
+            }
         }
+
+        Ok(rx)
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn watch_file(
         fd: Fd,
+        format: Format,
         job_dir: &Path,
+        mut reader: Reader,
+        mut filter: Filter,
         mut completion: watch::Receiver<bool>,
         tx: mpsc::Sender<Result<LogMessage, Error>>,
+        read_size: usize,
     ) {
-        let file_path = match fd {
-            Fd::Out => job_dir.join("out"),
-            Fd::Err => job_dir.join("err"),
-        };
+        let job_dir = job_dir.to_owned();
 
         tokio::spawn(async move {
-            let fd: i32 = fd.into();
-            let mut buf = [0u8; 512];
-            let mut file = File::open(file_path).await?;
-            let mut done = false;
+            let mut buf = vec![0u8; read_size];
+            // Watch is set up before the first read, so no modification is missed,
+            // job dir is watched as log files are replaced on rotation
+            let inotify = Inotify::init()?;
+            inotify.watches().add(
+                &job_dir,
+                WatchMask::MODIFY | WatchMask::CREATE | WatchMask::MOVED_TO,
+            )?;
+            let mut events = inotify.into_event_stream([0u8; 1024])?;
+            let mut decoder = Decoder::new(format, fd);
+            let mut done = *completion.borrow();
 
             loop {
-                tokio::select! {
-                    _ = completion.changed() => {
-                        // exit on next EOF;
-                        done = true;
+                match reader.read(&mut buf).await {
+                    Ok((offset, bytes_read)) if bytes_read > 0 => {
+                        let messages = decoder.decode(offset, &buf[..bytes_read]);
+                        for msg in filter.apply(format, messages) {
+                            if let Err(err) = tx.send(Ok(msg)).await {
+                                error!("Failed to send read logs({})", err);
+                                return Ok(());
+                            }
+                        }
+                    }
+                    // Job is complete and nothing is left to read
+                    Ok(_) if done => {
+                        for msg in filter.finish() {
+                            if let Err(err) = tx.send(Ok(msg)).await {
+                                error!("Failed to send read logs({})", err);
+                            }
+                        }
+                        break;
+                    }
+                    // Sleep at EOF until file is modified or job completes
+                    Ok(_) => tokio::select! {
+                        // Dropped sender means job is complete as well
+                        _ = completion.changed() => done = true,
+                        event = events.next() => match event {
+                            Some(Ok(_)) => (),
+                            Some(Err(err)) => {
+                                error!("Error watching log file: {}", err);
+                                break;
+                            }
+                            None => break,
+                        },
+                    },
+                    Err(err) => {
+                        error!("Error reading from log file: {}", err);
+                        break;
                     }
-                   outcome = file.read(&mut buf) => {
-                       match outcome {
-                           Ok(bytes_read) if bytes_read > 0 => {
-                               let msg = LogMessage { fd, output: buf[..bytes_read].to_vec() };
-                               if let Err(err) = tx.send(Ok(msg)).await {
-                                   error!("Failed to send read logs({})", err);
-                                   break;
-                               }
-                           }
-                           Ok(bytes_read) if bytes_read == 0 && done => break,
-                           Err(err) => {
-                               error!("Error reading from log file: {}", err);
-                               break;
-                           },
-                           _ => (),
-                       }
-                   }
-
                 }
             }
 
@@ -196,44 +794,1070 @@ impl<'c> Controller<'c, Job<Started>> {
         });
     }
 
-    async fn read_file(fd: Fd, job_dir: &Path, tx: mpsc::Sender<Result<LogMessage, Error>>) {
-        let file_path = match fd {
-            Fd::Out => job_dir.join("out"),
-            Fd::Err => job_dir.join("err"),
-        };
-
+    #[allow(clippy::too_many_arguments)]
+    async fn read_file(
+        fd: Fd,
+        format: Format,
+        mut reader: Reader,
+        mut filter: Filter,
+        tx: mpsc::Sender<Result<LogMessage, Error>>,
+        read_size: usize,
+        end: Option<Error>,
+    ) {
         tokio::spawn(async move {
-            let fd: i32 = fd.into();
-            let mut file = File::open(file_path).await?;
-            let mut buffer = [0u8; 512];
-
-            while let Ok(bytes_read) = file.read(&mut buffer).await {
-                let msg = LogMessage {
-                    fd,
-                    output: buffer[..bytes_read].to_vec(),
+            let mut decoder = Decoder::new(format, fd);
+            let mut buffer = vec![0u8; read_size];
+
+            while let Ok((offset, bytes_read)) = reader.read(&mut buffer).await {
+                let is_eof = bytes_read == 0;
+                let messages = if is_eof {
+                    filter.finish()
+                } else {
+                    filter.apply(format, decoder.decode(offset, &buffer[..bytes_read]))
                 };
-                if let Err(err) = tx.send(Ok(msg)).await {
-                    error!("Failed to send output message: {}", err);
+
+                for msg in messages {
+                    if let Err(err) = tx.send(Ok(msg)).await {
+                        error!("Failed to send output message: {}", err);
+                        return Ok(());
+                    }
+                }
+
+                if is_eof {
                     break;
                 }
             }
 
+            // Error ending the stream once log is read
+            if let Some(end) = end {
+                let _ = tx.send(Err(end)).await;
+            }
+
             Ok::<(), std::io::Error>(())
         });
     }
 
-    fn ensure_uid_gid(client: &'c str) -> Result<(u32, u32), Error> {
+    fn groups(client: &str, gid: u32) -> Result<Vec<u32>, Error> {
         let cstr = CString::new(client.as_bytes())?;
+        let groups = getgrouplist(&cstr, Gid::from_raw(gid)).map_err(std::io::Error::from)?;
+
+        Ok(groups.into_iter().map(Gid::as_raw).collect())
+    }
+}
+
+/// Job checked against settings of its client, yet to be started
+#[derive(Debug)]
+pub struct Launch {
+    job: Job<Empty>,
+    job_request: JobRequest,
+    owner: String,
+    job_dir: PathBuf,
+    cgroup_dir: PathBuf,
+    working_dir: Option<OwnedFd>,
+    rootfs: Option<RootfsSource>,
+    lease: Option<Lease>,
+    uid: u32,
+    gid: u32,
+    groups: Vec<u32>,
+    rootless: bool,
+    log_limit: Option<Limit>,
+    filter: Option<seccomp::Filter>,
+    rlimits: Rlimits,
+}
 
-        // Safety: ptr is null checked, and if user does not exist, user is created;
-        let passwd = unsafe { getpwnam(cstr.as_ptr()) };
-        if passwd.is_null() {
-            useradd(client)?;
-            return Self::ensure_uid_gid(client);
+/// Job spawned by `Launch::start`, yet to be registered with controller of its client
+#[derive(Debug)]
+pub struct Launched {
+    job: Job<Started>,
+    metadata: JobMetadata,
+    lease: Option<Lease>,
+}
+
+impl Launch {
+    /// Sets job up and spawns it. Takes as long as unpacking rootfs does, so it doesn't
+    /// need the controller, which would otherwise be held up for other calls of the client.
+    /// Job dir and cgroup are removed if job fails to start, killing it if it got spawned
+    pub async fn start(mut self) -> Result<Launched, Error> {
+        let job_dir = self.job_dir.clone();
+        let cgroup_dir = self.cgroup_dir.clone();
+        let job_id = *self.job.id();
+        // Kept out of spawn, so that uid is not given back while job may still run as it
+        let lease = self.lease.take();
+
+        let err = match self.spawn(lease.as_ref().map(Lease::uid)).await {
+            Ok((job, metadata)) => {
+                return Ok(Launched {
+                    job,
+                    metadata,
+                    lease,
+                })
+            }
+            Err(err) => err,
+        };
+
+        match cgroup::kill(&cgroup_dir).await {
+            Ok(()) => drop(lease),
+            Err(err) => {
+                error!(
+                    "Failed to kill job({}) which failed to start: {}",
+                    job_id, err
+                );
+                std::mem::forget(lease);
+            }
         }
+        match tokio::fs::remove_dir(&cgroup_dir).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                error!("Failed to remove cgroup of job({}): {}", job_id, err)
+            }
+            _ => (),
+        }
+        match tokio::fs::remove_dir_all(&job_dir).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                error!("Failed to remove dir of job({}): {}", job_id, err)
+            }
+            _ => (),
+        }
+
+        Err(err)
+    }
+
+    // Spawns job, `leased` uid is recorded in its metadata
+    async fn spawn(self, leased: Option<u32>) -> Result<(Job<Started>, JobMetadata), Error> {
+        let Self {
+            job,
+            job_request,
+            owner,
+            job_dir,
+            cgroup_dir,
+            working_dir,
+            rootfs,
+            lease: _,
+            uid,
+            gid,
+            groups,
+            rootless,
+            log_limit,
+            filter,
+            rlimits,
+        } = self;
+
+        create_dir_all(&job_dir).await?;
+        let rootfs = match rootfs {
+            Some(rootfs) => {
+                let owner = (!rootless).then_some((uid, gid));
+                Some(rootfs.prepare(&job_dir, owner).await?)
+            }
+            None => None,
+        };
+        let user = rootless.then(|| UserNamespace::new(uid, gid));
+        let namespaces = match (&job_request.isolation, rootfs, user) {
+            (None, None, None) => None,
+            (isolation, rootfs, user) => Some(
+                isolation
+                    .as_ref()
+                    .map(Namespaces::from)
+                    .unwrap_or_default()
+                    .set_rootfs(rootfs)
+                    .set_user(user)
+                    .set_owner(uid, gid, groups.clone()),
+            ),
+        };
+
+        create_dir_all(&cgroup_dir).await?;
+        cgroup::set_cpu_control(&cgroup_dir, &job_request).await?;
+        cgroup::set_mem_control(&cgroup_dir, &job_request).await?;
+        cgroup::set_io_control(&cgroup_dir, &job_request).await?;
+
+        let started_at = logfile::timestamp();
+        let job = job
+            .add_command(&job_request)
+            .add_to_cgroup(cgroup_dir)?
+            .isolate(namespaces)
+            .set_rlimits(rlimits)
+            .set_ownership(uid, gid, groups)
+            .confine(filter)?
+            .set_job_dir(job_dir)
+            .set_working_dir(working_dir)
+            .set_log_limit(log_limit)
+            .spawn()?;
+
+        // Written only once job is spawned, so that job which failed to
+        // spawn doesn't show up as lost after restart
+        let metadata = JobMetadata {
+            // Stdin payload and environment may hold secrets, they are not kept around
+            request: Some(JobRequest {
+                stdin: None,
+                env: Default::default(),
+                ..job_request.clone()
+            }),
+            owner,
+            started_at,
+            pid: job.pid(),
+            uid: leased,
+            ..Default::default()
+        };
+        metadata::write(job.job_dir(), &metadata).await?;
+
+        // Job may exit without reading its stdin, which doesn't make it fail to start
+        if let Some(stdin) = job.stdin() {
+            if let Some(payload) = job_request.stdin {
+                if stdin.send(Input::Data(payload)).await.is_err() {
+                    info!("Job({}) closed stdin before its payload", job.id());
+                }
+            }
+
+            if !job_request.keep_stdin_open {
+                let _ = stdin.send(Input::Eof).await;
+            }
+        }
+
+        Ok((job, metadata))
+    }
+}
+
+// Host paths of rootfs, opened before anything is created for the job
+#[derive(Debug)]
+struct RootfsSource {
+    root: Root,
+    binds: Vec<(OwnedFd, runner::job_request::rootfs::Bind)>,
+    working_dir: Option<String>,
+}
+
+#[derive(Debug)]
+enum Root {
+    Dir(OwnedFd),
+    Tarball(PathBuf, File),
+}
+
+impl RootfsSource {
+    fn open(
+        client_dir: &Path,
+        request: &runner::job_request::Rootfs,
+        working_dir: &Option<String>,
+    ) -> Result<Self, Error> {
+        let root = match &request.source {
+            Some(Source::Dir(dir)) => Root::Dir(open_rootfs_path(client_dir, dir, O_DIRECTORY)?),
+            Some(Source::Tarball(tarball)) => {
+                let fd = open_rootfs_path(client_dir, tarball, 0)?;
+                // O_PATH fd is reopened for reading through /proc
+                let contents = File::open(format!("/proc/self/fd/{}", fd.as_raw_fd()))?;
+                Root::Tarball(PathBuf::from(tarball), contents)
+            }
+            None => return Err(Error::InvalidRootfs(PathBuf::new())),
+        };
+        let binds = request
+            .binds
+            .iter()
+            .map(|bind| Ok((open_rootfs_path(client_dir, &bind.source, 0)?, bind.clone())))
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            root,
+            binds,
+            working_dir: working_dir.clone(),
+        })
+    }
+
+    // Unpacks tarball into job dir, as `owner` if there is one
+    async fn prepare(self, job_dir: &Path, owner: Option<(u32, u32)>) -> Result<Rootfs, Error> {
+        let root = match self.root {
+            Root::Dir(root) => root,
+            Root::Tarball(tarball, contents) => {
+                unpack(&tarball, contents, &job_dir.join(ROOTFS_DIR), owner).await?;
+                rootfs::open_beneath(job_dir, Path::new(ROOTFS_DIR), O_DIRECTORY)?
+            }
+        };
+
+        let mut rootfs = Rootfs::new(root)?;
+        for (source, bind) in self.binds {
+            rootfs = rootfs.add_bind(source, Path::new(&bind.target), bind.read_only)?;
+        }
+        if let Some(working_dir) = self.working_dir {
+            rootfs = rootfs.set_working_dir(Path::new(&working_dir))?;
+        }
+
+        Ok(rootfs)
+    }
+}
+
+// Returns uid of the job to the pool, once no process of the job is left running as it.
+// Uid which can't be freed that way stays leased for good. Files job left owned by the uid
+// outside of its job dir, e.g. in /tmp or /dev/shm, are not looked for, so whichever job
+// leases the uid next can access them
+async fn release(job_id: Uuid, cgroup_dir: PathBuf, lease: Lease) {
+    match cgroup::kill(&cgroup_dir).await {
+        Ok(()) => drop(lease),
+        Err(err) => {
+            error!("Failed to release uid of job({}): {}", job_id, err);
+            std::mem::forget(lease);
+        }
+    }
+}
+
+fn count_savings(disk_stats: &Mutex<runner::DiskStats>, compression: &Compression) {
+    // Unwrap: lock is never held across panicking code
+    let mut disk_stats = disk_stats.lock().unwrap();
+    disk_stats.compressed_jobs += 1;
+    disk_stats.original_bytes += compression.original_bytes;
+    disk_stats.compressed_bytes += compression.compressed_bytes;
+}
+
+// Savings of pruned jobs are no longer there
+fn discount_savings(disk_stats: &Mutex<runner::DiskStats>, compression: &Compression) {
+    // Unwrap: lock is never held across panicking code
+    let mut disk_stats = disk_stats.lock().unwrap();
+    disk_stats.compressed_jobs = disk_stats.compressed_jobs.saturating_sub(1);
+    disk_stats.original_bytes = disk_stats
+        .original_bytes
+        .saturating_sub(compression.original_bytes);
+    disk_stats.compressed_bytes = disk_stats
+        .compressed_bytes
+        .saturating_sub(compression.compressed_bytes);
+}
+
+// Opens requested working dir relative to client directory, rejecting anything (including
+// symlinks) escaping it. Job changes to the fd, so the dir can't be swapped meanwhile
+fn open_working_dir(client_dir: &Path, working_dir: &str) -> Result<OwnedFd, Error> {
+    rootfs::open_beneath(client_dir, Path::new(working_dir), O_DIRECTORY)
+        .map_err(|_| Error::InvalidWorkingDir(PathBuf::from(working_dir)))
+}
+
+// Opens requested path relative to client directory, rejecting anything (including symlinks)
+// escaping it. Unlike resolved path, fd can't be redirected by client before it is used
+fn open_rootfs_path(client_dir: &Path, path: &str, flags: c_int) -> Result<OwnedFd, Error> {
+    rootfs::open_beneath(client_dir, Path::new(path), flags)
+        .map_err(|_| Error::InvalidRootfs(PathBuf::from(path)))
+}
+
+// Unpacks as client, so that tarball can't plant files owned by root.
+// Without owner it is unpacked as the server, which is unprivileged in rootless mode
+async fn unpack(
+    tarball: &Path,
+    contents: File,
+    dir: &Path,
+    owner: Option<(u32, u32)>,
+) -> Result<(), Error> {
+    create_dir_all(dir).await?;
+    let mut command = tokio::process::Command::new("tar");
+    // Unlike "-", stdin opened by name gets its compression detected
+    command
+        .arg("-xf")
+        .arg("/dev/stdin")
+        .arg("-C")
+        .arg(dir)
+        .stdin(contents);
+    if let Some((uid, gid)) = owner {
+        chown(dir, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
+            .map_err(std::io::Error::from)?;
+        command.uid(uid).gid(gid);
+    }
+
+    let status = command.status().await?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Unpack(tarball.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        open_rootfs_path, open_working_dir, unpack, Controller, Error, Fd, Filter, Format, Input,
+        OutputOptions, Reader, Start, ROOTFS_DIR,
+    };
+    use crate::{
+        job::{Job, Started, ERR_FIFO, OUT_FIFO},
+        logfile, metadata,
+        runner::{
+            self, job_metadata::Compression, job_request::LogFormat, job_status::Outcome,
+            JobMetadata, JobRequest, JobStatus,
+        },
+        testing::{own_cgroup_dir, temp_dir},
+        uid::UidPool,
+    };
+    use nix::time::{clock_gettime, ClockId};
+    use std::{
+        io::Write,
+        os::unix::{fs::MetadataExt, io::AsRawFd},
+        path::PathBuf,
+        time::Duration,
+    };
+    use tokio::sync::{mpsc, watch};
+    use uuid::Uuid;
+
+    fn client_dir(name: &str) -> PathBuf {
+        let dir = temp_dir(name);
+        std::fs::create_dir_all(dir.join("job")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn given_relative_working_dir_opens_inside_client_dir() {
+        let dir = client_dir("relative");
+        let opened = open_working_dir(&dir, "job").unwrap();
+        let opened = std::fs::read_link(format!("/proc/self/fd/{}", opened.as_raw_fd())).unwrap();
+        assert_eq!(opened, std::fs::canonicalize(dir.join("job")).unwrap());
+    }
+
+    #[test]
+    fn given_working_dir_escaping_client_dir_fails() {
+        let dir = client_dir("escaping");
+        let outcome = open_working_dir(&dir, "../../");
+        assert!(matches!(outcome, Err(Error::InvalidWorkingDir(_))));
+
+        std::os::unix::fs::symlink("/etc", dir.join("etc")).unwrap();
+        let outcome = open_working_dir(&dir, "etc");
+        assert!(matches!(outcome, Err(Error::InvalidWorkingDir(_))));
+    }
+
+    #[test]
+    fn given_absolute_working_dir_outside_client_dir_fails() {
+        let dir = client_dir("absolute");
+        let outcome = open_working_dir(&dir, "/etc");
+        assert!(matches!(outcome, Err(Error::InvalidWorkingDir(_))));
+    }
+
+    #[tokio::test]
+    async fn given_tarball_then_it_is_unpacked_as_client() {
+        let dir = client_dir("tarball");
+        let root = dir.join("job").join(ROOTFS_DIR);
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::write(dir.join("file"), "data").unwrap();
+        let status = std::process::Command::new("tar")
+            .arg("-czf")
+            .arg(dir.join("rootfs.tar.gz"))
+            .arg("-C")
+            .arg(&dir)
+            .arg("file")
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let tarball = dir.join("rootfs.tar.gz");
+        let contents = std::fs::File::open(&tarball).unwrap();
+        unpack(&tarball, contents, &root, Some((65534, 65534)))
+            .await
+            .unwrap();
+
+        let file = root.join("file");
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "data");
+        assert_eq!(std::fs::metadata(&file).unwrap().uid(), 65534);
+        assert!(matches!(
+            open_rootfs_path(&dir, "../rootfs.tar.gz", 0),
+            Err(Error::InvalidRootfs(_))
+        ));
+    }
+
+    #[test]
+    fn given_missing_working_dir_fails() {
+        let dir = client_dir("missing");
+        let outcome = open_working_dir(&dir, "nope");
+        assert!(matches!(outcome, Err(Error::InvalidWorkingDir(_))));
+    }
+
+    fn thread_cpu_time() -> Duration {
+        clock_gettime(ClockId::CLOCK_THREAD_CPUTIME_ID)
+            .unwrap()
+            .into()
+    }
+
+    // Single threaded runtime polls follower on the test thread,
+    // so thread cpu time accounts for all the work follower does
+    #[tokio::test]
+    async fn given_idle_job_follower_uses_negligible_cpu() {
+        let dir = client_dir("follower");
+        let mut log = std::fs::File::create(dir.join("out")).unwrap();
+        log.write_all(b"first").unwrap();
+
+        let (completion_tx, completion) = watch::channel(false);
+        let (tx, mut rx) = mpsc::channel(20);
+        let reader = Reader::open(&dir, Format::Separate, Fd::Out, Start::default())
+            .await
+            .unwrap();
+        Controller::<Job<Started>>::watch_file(
+            Fd::Out,
+            Format::Separate,
+            &dir,
+            reader,
+            Filter::default(),
+            completion,
+            tx,
+            512,
+        )
+        .await;
+        let msg = rx.recv().await.unwrap().unwrap();
+        assert_eq!(msg.output, b"first");
+
+        let before = thread_cpu_time();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let spent = thread_cpu_time() - before;
+        assert!(
+            spent < Duration::from_millis(50),
+            "follower spent {:?}",
+            spent
+        );
+
+        log.write_all(b"second").unwrap();
+        let msg = rx.recv().await.unwrap().unwrap();
+        assert_eq!(msg.output, b"second");
+        assert_eq!(msg.offset, 5);
+
+        completion_tx.send(true).unwrap();
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn given_metadata_on_disk_then_jobs_are_restored() {
+        let dir = client_dir("restore");
+        let completed = Uuid::new_v4();
+        let gone = Uuid::new_v4();
+        for (job_id, status) in [(completed, Some(Outcome::ExitCode(3))), (gone, None)] {
+            let job_dir = dir.join(job_id.to_simple().to_string());
+            std::fs::create_dir_all(&job_dir).unwrap();
+            let metadata = JobMetadata {
+                request: Some(JobRequest {
+                    executable: "ls".into(),
+                    tty: true,
+                    ..Default::default()
+                }),
+                status: status.map(|outcome| JobStatus {
+                    outcome: Some(outcome),
+                    ..Default::default()
+                }),
+                pid: Some(u32::MAX),
+                ..Default::default()
+            };
+            metadata::write(&job_dir, &metadata).await.unwrap();
+        }
+
+        let mut controller = Controller::unprovisioned("restore", Default::default());
+        controller
+            .restore_jobs(&dir, &dir.join("cgroup"))
+            .await
+            .unwrap();
+        assert_eq!(controller.jobs.len(), 2);
+
+        let job = &controller.jobs[&completed];
+        assert!(job.is_complete());
+        assert!(job.is_tty());
+        assert!(*job.subscribe().borrow());
+        assert_eq!(job.status().outcome, Some(Outcome::ExitCode(3)));
+
+        // Process is not in job cgroup anymore
+        let job = &controller.jobs[&gone];
+        assert_eq!(job.status().outcome, Some(Outcome::Lost(true)));
+        let metadata = metadata::read(job.job_dir()).await.unwrap();
+        assert_eq!(metadata.status, Some(job.status()));
+    }
+
+    #[tokio::test]
+    async fn given_lost_job_with_leased_uid_then_uid_is_released() {
+        let dir = client_dir("lost-uid");
+        let job_dir = dir.join(Uuid::new_v4().to_simple().to_string());
+        std::fs::create_dir_all(&job_dir).unwrap();
+        let metadata = JobMetadata {
+            request: Some(JobRequest {
+                executable: "ls".into(),
+                ..Default::default()
+            }),
+            pid: Some(u32::MAX),
+            uid: Some(1000),
+            ..Default::default()
+        };
+        metadata::write(&job_dir, &metadata).await.unwrap();
+
+        let pool = UidPool::new(1000..1001);
+        let mut controller = Controller::unprovisioned("lost-uid", Default::default());
+        controller.settings.uids = Some(pool.clone());
+        controller
+            .restore_jobs(&dir, &dir.join("cgroup"))
+            .await
+            .unwrap();
+
+        // Released in the background, once job cgroup turns out to be empty
+        let lease = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match pool.lease() {
+                    Ok(lease) => break lease,
+                    Err(_) => tokio::task::yield_now().await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(lease.uid(), 1000);
+    }
+
+    #[tokio::test]
+    async fn given_retention_then_only_jobs_completed_before_it_are_pruned() {
+        let dir = client_dir("prune");
+        let hour = Duration::from_secs(3600);
+        let now = crate::logfile::timestamp();
+        let expired = Uuid::new_v4();
+        let recent = Uuid::new_v4();
+        for (job_id, ended_at) in [(expired, now - 2 * hour.as_micros() as u64), (recent, now)] {
+            let job_dir = dir.join(job_id.to_simple().to_string());
+            std::fs::create_dir_all(&job_dir).unwrap();
+            let metadata = JobMetadata {
+                request: Some(JobRequest {
+                    executable: "ls".into(),
+                    ..Default::default()
+                }),
+                ended_at: Some(ended_at),
+                status: Some(JobStatus {
+                    outcome: Some(Outcome::ExitCode(0)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            metadata::write(&job_dir, &metadata).await.unwrap();
+        }
+
+        let mut controller = Controller::unprovisioned("prune", Default::default());
+        controller
+            .restore_jobs(&dir, &dir.join("cgroup"))
+            .await
+            .unwrap();
+        assert_eq!(controller.prune().await, 0);
+
+        controller.settings.retention = Some(hour);
+        assert_eq!(controller.prune().await, 1);
+        assert!(!controller.jobs.contains_key(&expired));
+        assert!(!dir.join(expired.to_simple().to_string()).exists());
+        assert!(controller.jobs.contains_key(&recent));
+    }
+
+    #[tokio::test]
+    async fn given_compressed_jobs_on_disk_then_disk_stats_are_restored() {
+        let dir = client_dir("savings");
+        let persisted = Uuid::new_v4();
+        let unpersisted = Uuid::new_v4();
+        for (job_id, compression) in [
+            (
+                persisted,
+                Some(Compression {
+                    original_bytes: 100,
+                    compressed_bytes: 10,
+                }),
+            ),
+            (unpersisted, None),
+        ] {
+            let job_dir = dir.join(job_id.to_simple().to_string());
+            std::fs::create_dir_all(&job_dir).unwrap();
+            let metadata = JobMetadata {
+                request: Some(JobRequest {
+                    executable: "ls".into(),
+                    ..Default::default()
+                }),
+                ended_at: Some(0),
+                status: Some(JobStatus {
+                    outcome: Some(Outcome::ExitCode(0)),
+                    ..Default::default()
+                }),
+                compression,
+                ..Default::default()
+            };
+            metadata::write(&job_dir, &metadata).await.unwrap();
+        }
+        // Server went down before savings of the job were persisted
+        let job_dir = dir.join(unpersisted.to_simple().to_string());
+        std::fs::write(job_dir.join("out"), b"output\n").unwrap();
+        let savings = logfile::compress(&job_dir, logfile::Format::Separate)
+            .await
+            .unwrap();
+
+        let mut controller = Controller::unprovisioned("savings", Default::default());
+        controller
+            .restore_jobs(&dir, &dir.join("cgroup"))
+            .await
+            .unwrap();
+        let stats = controller.stats();
+        assert_eq!(stats.compressed_jobs, 2);
+        assert_eq!(stats.original_bytes, 100 + savings.original);
+        assert_eq!(stats.compressed_bytes, 10 + savings.compressed);
+        let metadata = metadata::read(&job_dir).await.unwrap();
+        assert_eq!(
+            metadata.compression.unwrap().original_bytes,
+            savings.original
+        );
+
+        controller.settings.retention = Some(Duration::from_secs(1));
+        assert_eq!(controller.prune().await, 2);
+        assert_eq!(controller.stats(), runner::DiskStats::default());
+    }
+
+    #[tokio::test]
+    async fn given_tty_job_started_with_default_flags_then_it_can_be_attached_again() {
+        let dir = temp_dir("attach");
+        let mut controller = Controller::unprovisioned("attach", Default::default());
+        controller.settings.storage_root = dir;
+        controller.settings.cgroup_root = own_cgroup_dir().await;
+        let job_id = controller
+            .start(JobRequest {
+                executable: "/bin/cat".into(),
+                tty: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        for keys in ["first", "second"] {
+            let (stdin, mut output) = controller.attach(job_id).await.unwrap();
+            stdin
+                .send(Input::Data(format!("{}\n", keys).into_bytes()))
+                .await
+                .unwrap();
+            let mut echoed = Vec::new();
+            while !String::from_utf8_lossy(&echoed).contains(keys) {
+                let message = tokio::time::timeout(Duration::from_secs(5), output.recv())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+                echoed.extend(message.output);
+            }
+        }
+
+        controller.stop(job_id).await;
+        controller.jobs[&job_id].wait().await;
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    // Output of both fds of the job, followed until it completes
+    async fn output_of(controller: &Controller<'_, Job<Started>>, job_id: Uuid) -> Vec<u8> {
+        let mut output = controller
+            .output(job_id, OutputOptions::default())
+            .await
+            .unwrap();
+        let mut bytes = Vec::new();
+        while let Some(message) = tokio::time::timeout(Duration::from_secs(5), output.recv())
+            .await
+            .unwrap()
+        {
+            bytes.extend(message.unwrap().output);
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn given_stdin_payload_then_job_reads_it_until_eof() {
+        let mut controller = Controller::unprovisioned("stdin-payload", Default::default());
+        controller.settings.storage_root = temp_dir("stdin-payload");
+        controller.settings.cgroup_root = own_cgroup_dir().await;
+        let job_id = controller
+            .start(JobRequest {
+                executable: "/bin/cat".into(),
+                stdin: Some(b"payload\n".to_vec()),
+                env: [("SECRET".into(), "value".into())].into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(output_of(&controller, job_id).await, b"payload\n");
+        let request = metadata::read(controller.jobs[&job_id].job_dir())
+            .await
+            .unwrap()
+            .request
+            .unwrap();
+        assert_eq!((request.stdin, request.env.len()), (None, 0));
+        assert_eq!(
+            controller.jobs[&job_id].wait().await.outcome,
+            Some(Outcome::ExitCode(0))
+        );
+        assert!(controller.stdin(job_id).await.is_err());
+
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn given_keep_stdin_open_then_job_reads_further_input_until_eof() {
+        let mut controller = Controller::unprovisioned("stdin-open", Default::default());
+        controller.settings.storage_root = temp_dir("stdin-open");
+        controller.settings.cgroup_root = own_cgroup_dir().await;
+        let job_id = controller
+            .start(JobRequest {
+                executable: "/bin/cat".into(),
+                stdin: Some(b"first\n".to_vec()),
+                keep_stdin_open: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let stdin = controller.stdin(job_id).await.unwrap();
+        stdin.send(Input::Data(b"second\n".to_vec())).await.unwrap();
+        stdin.send(Input::Eof).await.unwrap();
+        assert_eq!(output_of(&controller, job_id).await, b"first\nsecond\n");
+        assert_eq!(
+            controller.jobs[&job_id].wait().await.outcome,
+            Some(Outcome::ExitCode(0))
+        );
+
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn given_job_exiting_without_reading_payload_then_it_still_starts() {
+        let mut controller = Controller::unprovisioned("stdin-unread", Default::default());
+        controller.settings.storage_root = temp_dir("stdin-unread");
+        controller.settings.cgroup_root = own_cgroup_dir().await;
+        let job_id = controller
+            .start(JobRequest {
+                executable: "/bin/true".into(),
+                // Larger than pipe buffer, so it can't be written before job exits
+                stdin: Some(vec![0; 4 << 20]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            controller.jobs[&job_id].wait().await.outcome,
+            Some(Outcome::ExitCode(0))
+        );
+
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn given_offset_within_frame_of_combined_log_then_output_is_refused() {
+        let mut controller = Controller::unprovisioned("frame-offset", Default::default());
+        controller.settings.storage_root = temp_dir("frame-offset");
+        controller.settings.cgroup_root = own_cgroup_dir().await;
+        let job_id = controller
+            .start(JobRequest {
+                executable: "/bin/echo".into(),
+                args: vec!["hello".into()],
+                log_format: LogFormat::Combined as i32,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let output = output_of(&controller, job_id).await;
+        assert_eq!(output, b"hello\n");
+
+        let options = OutputOptions {
+            out: Start::Offset(1),
+            ..Default::default()
+        };
+        let refused = controller.output(job_id, options).await;
+        assert!(matches!(refused, Err(Error::InvalidOffset(_))));
+
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    // Leaves job running as previous server would: in its cgroup, writing to its fifos
+    // unless it is a tty job, with metadata recording its pid. Then adopts it the way server
+    // does on restart. Job prints its first line right away and the second one once `go` exists
+    async fn left_running(
+        client: &'static str,
+        tty: bool,
+    ) -> (
+        Controller<'static, Job<Started>>,
+        Uuid,
+        std::process::Child,
+        PathBuf,
+    ) {
+        let dir = temp_dir(client);
+        let cgroup_root = own_cgroup_dir().await;
+        let go = dir.join("go");
+        let job_id = Uuid::new_v4();
+        let job_dir = dir.join(client).join(job_id.to_simple().to_string());
+        let cgroup_dir = cgroup_root
+            .join(client)
+            .join(job_id.to_simple().to_string());
+        std::fs::create_dir_all(&job_dir).unwrap();
+        std::fs::create_dir_all(&cgroup_dir).unwrap();
+
+        let request = JobRequest {
+            executable: "/bin/sh".into(),
+            args: vec![
+                "-c".into(),
+                format!(
+                    "echo before; while [ ! -e {} ]; do sleep 0.01; done; echo after",
+                    go.display()
+                ),
+            ],
+            tty,
+            ..Default::default()
+        };
+        let mut command = std::process::Command::new(&request.executable);
+        command.args(&request.args);
+        if tty {
+            // pty master would be gone along with previous server
+            command.stdout(std::process::Stdio::null());
+        } else {
+            // Job holds fifos open for reading as well, so it never blocks on opening them
+            for (fd, path) in [(Fd::Out, OUT_FIFO), (Fd::Err, ERR_FIFO)] {
+                let path = job_dir.join(path);
+                nix::unistd::mkfifo(&path, nix::sys::stat::Mode::S_IRWXU).unwrap();
+                let fifo = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)
+                    .unwrap();
+                match fd {
+                    Fd::Out => command.stdout(fifo),
+                    Fd::Err => command.stderr(fifo),
+                };
+            }
+        }
+        let child = command.spawn().unwrap();
+        std::fs::write(cgroup_dir.join("cgroup.procs"), child.id().to_string()).unwrap();
+        let metadata = JobMetadata {
+            request: Some(request),
+            pid: Some(child.id()),
+            ..Default::default()
+        };
+        metadata::write(&job_dir, &metadata).await.unwrap();
+
+        let mut controller = Controller::unprovisioned(client, Default::default());
+        controller.settings.storage_root = dir.clone();
+        controller.settings.cgroup_root = cgroup_root.clone();
+        controller
+            .restore_jobs(&dir.join(client), &cgroup_root.join(client))
+            .await
+            .unwrap();
+        assert!(!controller.jobs[&job_id].is_complete());
+
+        (controller, job_id, child, go)
+    }
+
+    #[tokio::test]
+    async fn given_job_left_running_by_previous_server_then_its_output_is_captured_from_fifos() {
+        let (controller, job_id, mut child, go) = left_running("adopt-fifo", false).await;
+        std::fs::write(&go, "").unwrap();
+
+        let output = output_of(&controller, job_id).await;
+        assert_eq!(output, b"before\nafter\n");
+
+        child.wait().unwrap();
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn given_tty_job_left_running_by_previous_server_then_lost_output_is_reported() {
+        let (controller, job_id, mut child, go) = left_running("adopt-tty", true).await;
+
+        let attached = controller.attach(job_id).await;
+        assert!(matches!(attached, Err(Error::OutputLost(_))));
+
+        // Output logged before restart, none here, is followed by the error
+        let mut output = controller
+            .output(job_id, OutputOptions::default())
+            .await
+            .unwrap();
+        let lost = tokio::time::timeout(Duration::from_secs(5), output.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(lost, Err(Error::OutputLost(_))));
+        assert!(output.recv().await.is_none());
+
+        std::fs::write(&go, "").unwrap();
+        controller.jobs[&job_id].wait().await;
+        child.wait().unwrap();
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn given_working_dir_then_job_runs_in_it() {
+        let dir = temp_dir("working-dir");
+        std::fs::create_dir_all(dir.join("working-dir").join("work")).unwrap();
+        let mut controller = Controller::unprovisioned("working-dir", Default::default());
+        controller.settings.storage_root = dir.clone();
+        controller.settings.cgroup_root = own_cgroup_dir().await;
+        let job_id = controller
+            .start(JobRequest {
+                executable: "/bin/pwd".into(),
+                working_dir: Some("work".into()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let output = output_of(&controller, job_id).await;
+        let work = std::fs::canonicalize(dir.join("working-dir").join("work")).unwrap();
+        assert_eq!(output, format!("{}\n", work.display()).into_bytes());
+
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn given_launched_job_then_it_starts_without_controller_until_registered() {
+        let dir = temp_dir("launch");
+        let mut controller = Controller::unprovisioned("launch", Default::default());
+        controller.settings.storage_root = dir;
+        controller.settings.cgroup_root = own_cgroup_dir().await;
+
+        let launch = controller
+            .launch(JobRequest {
+                executable: "/bin/true".into(),
+                ..Default::default()
+            })
+            .unwrap();
+        let launched = launch.start().await.unwrap();
+        assert!(controller.jobs.is_empty());
+
+        let job_id = controller.register(launched);
+        controller.jobs[&job_id].wait().await;
+        assert_eq!(
+            controller.status(job_id).await.unwrap().outcome,
+            Some(Outcome::ExitCode(0))
+        );
+
+        let cgroup_dir = controller.jobs[&job_id].cgroup_dir().to_owned();
+        let _ = std::fs::remove_dir(&cgroup_dir);
+        let _ = std::fs::remove_dir(cgroup_dir.parent().unwrap());
+    }
+
+    #[tokio::test]
+    async fn given_job_failing_to_start_then_nothing_is_left_behind() {
+        let dir = temp_dir("leftovers");
+        std::fs::create_dir_all(dir.join("leftovers")).unwrap();
+        let mut controller = Controller::unprovisioned("leftovers", Default::default());
+        controller.settings.storage_root = dir.clone();
+        controller.settings.cgroup_root = own_cgroup_dir().await;
+        let cgroup_dir = controller.settings.cgroup_root.join("leftovers");
+
+        let invalid = controller.launch(JobRequest {
+            executable: "/bin/true".into(),
+            working_dir: Some("missing".into()),
+            ..Default::default()
+        });
+        assert!(matches!(invalid, Err(Error::InvalidWorkingDir(_))));
 
-        let (uid, gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+        let unspawnable = controller
+            .start(JobRequest {
+                executable: "/missing".into(),
+                ..Default::default()
+            })
+            .await;
+        assert!(unspawnable.is_err());
 
-        Ok((uid, gid))
+        assert_eq!(std::fs::read_dir(dir.join("leftovers")).unwrap().count(), 0);
+        assert!(std::fs::read_dir(&cgroup_dir)
+            .map(|entries| entries.flatten().all(|entry| entry.path().is_file()))
+            .unwrap_or(true));
+        let _ = std::fs::remove_dir(&cgroup_dir);
     }
 }
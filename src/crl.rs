@@ -0,0 +1,197 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use log::warn;
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use thiserror::Error;
+use x509_parser::{
+    oid_registry::{
+        OID_EC_P256, OID_NIST_EC_P384, OID_PKCS1_SHA256WITHRSA, OID_PKCS1_SHA384WITHRSA,
+        OID_PKCS1_SHA512WITHRSA, OID_SIG_ECDSA_WITH_SHA256, OID_SIG_ECDSA_WITH_SHA384,
+        OID_SIG_ED25519,
+    },
+    pem::Pem,
+    prelude::{CertificateRevocationList, FromDer, X509Certificate},
+    time::ASN1Time,
+    x509::SubjectPublicKeyInfo,
+};
+
+const CERTIFICATE_LABEL: &str = "CERTIFICATE";
+
+// Issuer and serial of every revoked certificate, both DER encoded
+type Revoked = HashSet<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Invalid CRL in {0}")]
+    Invalid(PathBuf),
+    #[error("CRL in {0} is not issued by configured CA")]
+    UnknownIssuer(PathBuf),
+    #[error("CRL in {0} has invalid signature")]
+    Signature(PathBuf),
+}
+
+/// Certificates revoked by CRLs of configured CAs, re-read from file on `Crl::reload`.
+/// Clones share revoked certificates
+#[derive(Clone)]
+pub struct Crl {
+    path: PathBuf,
+    // DER encoded CAs CRLs must be issued by
+    cas: Arc<Vec<Vec<u8>>>,
+    revoked: Arc<RwLock<Revoked>>,
+}
+
+impl Crl {
+    /// Reads CRLs from PEM or DER file, each of them must be signed by one of CAs in PEM
+    pub async fn load(path: &Path, ca: &[u8]) -> Result<Self, Error> {
+        let cas = Pem::iter_from_buffer(ca)
+            .filter_map(Result::ok)
+            .filter(|pem| pem.label == CERTIFICATE_LABEL)
+            .map(|pem| pem.contents)
+            .collect();
+        let crl = Self {
+            path: path.to_owned(),
+            cas: Arc::new(cas),
+            revoked: Default::default(),
+        };
+        crl.reload().await?;
+
+        Ok(crl)
+    }
+
+    /// Re-reads CRL file, revoked certificates stay as they were unless it is valid
+    pub async fn reload(&self) -> Result<(), Error> {
+        let contents = tokio::fs::read(&self.path)
+            .await
+            .map_err(|err| Error::Read(self.path.clone(), err))?;
+        let revoked = revoked(&contents, &self.cas, &self.path)?;
+        *self.revoked.write().expect("Poisoned CRL") = revoked;
+
+        Ok(())
+    }
+
+    /// Whether certificate is revoked by CRL of its issuer
+    pub fn is_revoked(&self, cert: &X509Certificate) -> bool {
+        let key = (cert.issuer().as_raw().to_vec(), cert.raw_serial().to_vec());
+        self.revoked.read().expect("Poisoned CRL").contains(&key)
+    }
+}
+
+// Certificates revoked by every CRL in PEM or DER encoded contents
+fn revoked(contents: &[u8], cas: &[Vec<u8>], path: &Path) -> Result<Revoked, Error> {
+    let ders = if contents.starts_with(b"-----BEGIN") {
+        // Every block has to be a CRL
+        Pem::iter_from_buffer(contents)
+            .map(|pem| pem.map(|pem| pem.contents))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Error::Invalid(path.to_owned()))?
+    } else {
+        vec![contents.to_vec()]
+    };
+    if ders.is_empty() {
+        return Err(Error::Invalid(path.to_owned()));
+    }
+
+    let mut revoked = HashSet::new();
+    for der in &ders {
+        let (_, crl) = CertificateRevocationList::from_der(der)
+            .map_err(|_| Error::Invalid(path.to_owned()))?;
+        let issuer = cas
+            .iter()
+            .filter_map(|ca| X509Certificate::from_der(ca).ok())
+            .find(|(_, ca)| ca.subject().as_raw() == crl.issuer().as_raw())
+            .ok_or_else(|| Error::UnknownIssuer(path.to_owned()))?
+            .1;
+        if !is_signed_by(&crl, issuer.public_key()) {
+            return Err(Error::Signature(path.to_owned()));
+        }
+        if crl.next_update().is_some_and(|next| next < ASN1Time::now()) {
+            warn!("CRL in {} is past its next update", path.display());
+        }
+
+        revoked.extend(
+            crl.iter_revoked_certificates()
+                .map(|cert| (crl.issuer().as_raw().to_vec(), cert.raw_serial().to_vec())),
+        );
+    }
+
+    Ok(revoked)
+}
+
+fn is_signed_by(crl: &CertificateRevocationList, key: &SubjectPublicKeyInfo) -> bool {
+    let algorithm = &crl.signature_algorithm.algorithm;
+    let curve = key
+        .algorithm
+        .parameters
+        .as_ref()
+        .and_then(|parameters| parameters.as_oid().ok());
+    let verification: &dyn VerificationAlgorithm = if *algorithm == OID_SIG_ED25519 {
+        &signature::ED25519
+    } else if *algorithm == OID_SIG_ECDSA_WITH_SHA256 && curve == Some(&OID_EC_P256) {
+        &signature::ECDSA_P256_SHA256_ASN1
+    } else if *algorithm == OID_SIG_ECDSA_WITH_SHA384 && curve == Some(&OID_NIST_EC_P384) {
+        &signature::ECDSA_P384_SHA384_ASN1
+    } else if *algorithm == OID_PKCS1_SHA256WITHRSA {
+        &signature::RSA_PKCS1_2048_8192_SHA256
+    } else if *algorithm == OID_PKCS1_SHA384WITHRSA {
+        &signature::RSA_PKCS1_2048_8192_SHA384
+    } else if *algorithm == OID_PKCS1_SHA512WITHRSA {
+        &signature::RSA_PKCS1_2048_8192_SHA512
+    } else {
+        return false;
+    };
+
+    UnparsedPublicKey::new(verification, key.subject_public_key.data)
+        .verify(crl.tbs_cert_list.as_ref(), crl.signature_value.data)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/crl");
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(FIXTURES).join(name)
+    }
+
+    fn cert(name: &str) -> Vec<u8> {
+        let pem = std::fs::read(fixture(name)).unwrap();
+        Pem::iter_from_buffer(&pem)
+            .next()
+            .unwrap()
+            .unwrap()
+            .contents
+    }
+
+    #[tokio::test]
+    async fn given_crl_then_only_revoked_certificates_are_revoked() {
+        let ca = std::fs::read(fixture("ca.pem")).unwrap();
+        let crl = Crl::load(&fixture("crl.pem"), &ca).await.unwrap();
+
+        let revoked = cert("revoked.pem");
+        let valid = cert("valid.pem");
+        assert!(crl.is_revoked(&X509Certificate::from_der(&revoked).unwrap().1));
+        assert!(!crl.is_revoked(&X509Certificate::from_der(&valid).unwrap().1));
+    }
+
+    #[tokio::test]
+    async fn given_crl_not_issued_by_ca_then_it_is_rejected() {
+        let ca = std::fs::read(fixture("ca.pem")).unwrap();
+        assert!(matches!(
+            Crl::load(&fixture("other-crl.pem"), &ca).await,
+            Err(Error::UnknownIssuer(_))
+        ));
+        // Issued in the name of CA, signed by another key
+        assert!(matches!(
+            Crl::load(&fixture("forged-crl.pem"), &ca).await,
+            Err(Error::Signature(_))
+        ));
+    }
+}
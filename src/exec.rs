@@ -0,0 +1,342 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{CString, OsStr, OsString},
+    mem::zeroed,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::Path,
+    ptr,
+};
+
+use nix::libc::{
+    self, c_char, c_int, EACCES, EINVAL, ENOENT, ENOTDIR, O_RDWR, SIGPIPE, SIG_DFL, SIG_SETMASK,
+};
+
+use crate::runner::JobRequest;
+
+// Search path of execvp when PATH is not set, as reported by confstr(_CS_PATH)
+const DEFAULT_PATH: &[u8] = b"/bin:/usr/bin";
+
+type Hook = Box<dyn FnMut() -> std::io::Result<()> + Send + Sync>;
+
+/// Null-terminated array of C strings, as taken by execve
+struct CStrings {
+    strings: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl CStrings {
+    fn new(strings: Vec<CString>) -> Self {
+        let ptrs = strings
+            .iter()
+            .map(|string| string.as_ptr())
+            .chain(std::iter::once(ptr::null()))
+            .collect();
+
+        Self { strings, ptrs }
+    }
+}
+
+// Working directory, either changed to by path or through directory opened upfront
+enum Cwd {
+    Path(CString),
+    Fd(OwnedFd),
+}
+
+/// Program job process executes, along with what the process is set up with between clone
+/// and execve. Everything is allocated upfront, as child of multithreaded server may only
+/// call async-signal-safe functions, so it is set up the same way whether it is cloned
+/// by clone3 or forked
+pub struct Exec {
+    // Paths program is looked up at, in order, as by execvp
+    candidates: Vec<CString>,
+    argv: CStrings,
+    envp: CStrings,
+    // Program, argument or variable containing nul byte, which fails the spawn
+    has_nul: bool,
+    cwd: Option<Cwd>,
+    // Stdin, stdout and stderr, /dev/null unless set
+    stdio: [Option<OwnedFd>; 3],
+    hooks: Vec<Hook>,
+}
+
+// Safety: pointers refer to strings owned by `CStrings`, which are never modified
+unsafe impl Send for Exec {}
+unsafe impl Sync for Exec {}
+
+impl Exec {
+    /// Executable and arguments of the job, with environment of the server if job
+    /// inherits it, overridden by variables the job sets
+    pub fn new(job_request: &JobRequest) -> Self {
+        let mut env: BTreeMap<OsString, OsString> = if job_request.inherit_env {
+            std::env::vars_os().collect()
+        } else {
+            BTreeMap::new()
+        };
+        env.extend(
+            job_request
+                .env
+                .iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+
+        let program = OsStr::new(&job_request.executable);
+        let candidates = candidates(program, env.get(OsStr::new("PATH")));
+        let argv: Vec<_> = std::iter::once(job_request.executable.as_str())
+            .chain(job_request.args.iter().map(String::as_str))
+            .map(|arg| CString::new(arg).ok())
+            .collect();
+        let envp: Vec<_> = env
+            .into_iter()
+            .map(|(key, value)| {
+                let mut var = key.into_vec();
+                var.push(b'=');
+                var.extend(value.into_vec());
+                CString::new(var).ok()
+            })
+            .collect();
+        let has_nul = argv.iter().chain(envp.iter()).any(Option::is_none);
+
+        Self {
+            candidates,
+            argv: CStrings::new(argv.into_iter().flatten().collect()),
+            envp: CStrings::new(envp.into_iter().flatten().collect()),
+            has_nul,
+            cwd: None,
+            stdio: [None, None, None],
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Directory process changes to before running hooks
+    pub fn current_dir(&mut self, dir: &Path) -> &mut Self {
+        let dir = CString::new(dir.as_os_str().as_bytes()).ok();
+        self.has_nul |= dir.is_none();
+        self.cwd = dir.map(Cwd::Path);
+        self
+    }
+
+    /// Same as `current_dir`, for directory opened beforehand so that it can't be
+    /// replaced in between
+    pub fn current_dir_fd(&mut self, dir: OwnedFd) -> &mut Self {
+        self.cwd = Some(Cwd::Fd(dir));
+        self
+    }
+
+    pub fn stdin(&mut self, fd: impl Into<OwnedFd>) -> &mut Self {
+        self.stdio[0] = Some(fd.into());
+        self
+    }
+
+    pub fn stdout(&mut self, fd: impl Into<OwnedFd>) -> &mut Self {
+        self.stdio[1] = Some(fd.into());
+        self
+    }
+
+    pub fn stderr(&mut self, fd: impl Into<OwnedFd>) -> &mut Self {
+        self.stdio[2] = Some(fd.into());
+        self
+    }
+
+    /// Adds hook run by the child in order of addition, once its stdio and working
+    /// directory are set up. Failing hook fails the spawn
+    ///
+    /// # Safety
+    /// Hook runs in child of multithreaded process, so it may only call
+    /// async-signal-safe functions and must not allocate
+    pub unsafe fn pre_exec<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: FnMut() -> std::io::Result<()> + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Sets up calling process and executes the program, returns only on failure.
+    /// Meant to be called by the child right after clone, everything here is
+    /// async-signal-safe and nothing allocates
+    pub fn exec(&mut self) -> std::io::Error {
+        match self.setup() {
+            Ok(()) => self.execve(),
+            Err(err) => err,
+        }
+    }
+
+    fn setup(&mut self) -> std::io::Result<()> {
+        if self.has_nul {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+
+        for (target, fd) in self.stdio.iter().enumerate() {
+            let target = target as c_int;
+            // Safety: path is nul-terminated, the rest take no pointers
+            unsafe {
+                let fd = match fd {
+                    Some(fd) => fd.as_raw_fd(),
+                    None => check(libc::open(c"/dev/null".as_ptr(), O_RDWR))?,
+                };
+                // dup2 clears close-on-exec of the target, unless it is the same fd
+                if fd == target {
+                    check(libc::fcntl(fd, libc::F_SETFD, 0))?;
+                } else {
+                    check(libc::dup2(fd, target))?;
+                }
+            }
+        }
+
+        // Safety: path is nul-terminated
+        match &self.cwd {
+            Some(Cwd::Path(dir)) => check(unsafe { libc::chdir(dir.as_ptr()) })?,
+            Some(Cwd::Fd(dir)) => check(unsafe { libc::fchdir(dir.as_raw_fd()) })?,
+            None => 0,
+        };
+
+        // Server ignores SIGPIPE and its thread may block signals, neither is left to the job
+        // Safety: set outlives the call and is initialized by sigemptyset
+        unsafe {
+            let mut set: libc::sigset_t = zeroed();
+            libc::sigemptyset(&mut set);
+            match libc::pthread_sigmask(SIG_SETMASK, &set, ptr::null_mut()) {
+                0 => (),
+                errno => return Err(std::io::Error::from_raw_os_error(errno)),
+            }
+            if libc::signal(SIGPIPE, SIG_DFL) == libc::SIG_ERR {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        for hook in self.hooks.iter_mut() {
+            hook()?;
+        }
+
+        Ok(())
+    }
+
+    // Tries candidates in order, as execvp would: permission denied is reported
+    // only if no other candidate is found
+    fn execve(&self) -> std::io::Error {
+        let mut denied = false;
+        let mut last = ENOENT;
+
+        for candidate in &self.candidates {
+            // Safety: arrays are null-terminated, strings they point to outlive the call
+            unsafe {
+                libc::execve(
+                    candidate.as_ptr(),
+                    self.argv.ptrs.as_ptr(),
+                    self.envp.ptrs.as_ptr(),
+                )
+            };
+            match std::io::Error::last_os_error().raw_os_error() {
+                Some(EACCES) => denied = true,
+                Some(errno @ (ENOENT | ENOTDIR)) => last = errno,
+                Some(errno) => return std::io::Error::from_raw_os_error(errno),
+                None => (),
+            }
+        }
+
+        std::io::Error::from_raw_os_error(if denied { EACCES } else { last })
+    }
+}
+
+// Program is looked up in PATH of the job unless it contains a slash. Lookup is left to the
+// child, as it may see another root; empty entry stands for working directory
+fn candidates(program: &OsStr, path: Option<&OsString>) -> Vec<CString> {
+    let program = program.as_bytes();
+    if program.contains(&b'/') {
+        return CString::new(program).into_iter().collect();
+    }
+
+    let path = path.map(|path| path.as_bytes()).unwrap_or(DEFAULT_PATH);
+    path.split(|byte| *byte == b':')
+        .filter_map(|dir| {
+            let mut candidate = dir.to_vec();
+            if !candidate.is_empty() {
+                candidate.push(b'/');
+            }
+            candidate.extend(program);
+            CString::new(candidate).ok()
+        })
+        .collect()
+}
+
+fn check(res: c_int) -> std::io::Result<c_int> {
+    if res < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_program_without_slash_then_it_is_looked_up_in_path() {
+        let path = OsString::from("/usr/local/bin::/bin");
+
+        assert_eq!(
+            candidates(OsStr::new("ls"), Some(&path)),
+            [c"/usr/local/bin/ls", c"ls", c"/bin/ls"]
+        );
+        assert_eq!(
+            candidates(OsStr::new("ls"), None),
+            [c"/bin/ls", c"/usr/bin/ls"]
+        );
+        assert_eq!(candidates(OsStr::new("./ls"), Some(&path)), [c"./ls"]);
+    }
+
+    #[test]
+    fn given_forked_child_then_it_runs_program_set_up_as_requested() {
+        let mut exec = Exec::new(&JobRequest {
+            executable: "sh".into(),
+            args: vec!["-c".into(), r#"echo "$GREETING $(pwd)" >&2"#.into()],
+            env: [("GREETING".into(), "hello".into())].into(),
+            inherit_env: true,
+            ..Default::default()
+        });
+        let (reader, writer) = nix::unistd::pipe().unwrap();
+        // Safety: fds were just allocated by pipe and are owned by nothing else
+        let (reader, writer) = unsafe {
+            use std::os::fd::FromRawFd;
+            (
+                std::fs::File::from_raw_fd(reader),
+                OwnedFd::from_raw_fd(writer),
+            )
+        };
+        exec.current_dir(Path::new("/tmp")).stderr(writer);
+        // Safety: hook takes no locks and allocates nothing
+        unsafe { exec.pre_exec(|| check(libc::setpgid(0, 0)).map(drop)) };
+
+        // Safety: child does nothing but exec
+        let pid = match unsafe { libc::fork() } {
+            0 => {
+                exec.exec();
+                unsafe { libc::_exit(127) }
+            }
+            pid => pid,
+        };
+        drop(exec);
+        let mut output = String::new();
+        std::io::Read::read_to_string(&mut { reader }, &mut output).unwrap();
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+
+        assert_eq!(output, "hello /tmp\n");
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    }
+
+    #[test]
+    fn given_nul_in_argument_then_exec_fails_before_setup() {
+        let mut exec = Exec::new(&JobRequest {
+            executable: "/bin/true".into(),
+            args: vec!["a\0b".into()],
+            ..Default::default()
+        });
+
+        assert_eq!(exec.exec().raw_os_error(), Some(EINVAL));
+    }
+}
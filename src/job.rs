@@ -1,26 +1,65 @@
 use log::error;
-use nix::unistd::getpid;
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    libc::{self, ioctl, setsid, EINVAL, EIO, ESRCH, TIOCSCTTY, TIOCSWINSZ},
+    pty::{openpty, OpenptyResult, Winsize},
+    sys::{
+        signal::Signal,
+        socket::{socketpair, AddressFamily, SockFlag, SockType},
+        stat::Mode,
+        wait::waitpid,
+    },
+    unistd::{getpid, mkfifo, pipe2, Pid},
+};
 use std::{
-    os::unix::prelude::ExitStatusExt,
+    future::Future,
+    io::{ErrorKind, Read},
+    mem::MaybeUninit,
+    os::{
+        fd::OwnedFd,
+        unix::prelude::{AsRawFd, ExitStatusExt, FromRawFd, OpenOptionsExt},
+    },
     path::{Path, PathBuf},
-    process::Stdio,
-    sync::{Arc, RwLock, RwLockReadGuard},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock, RwLockReadGuard,
+    },
+    time::Duration,
 };
 use thiserror::Error;
 use tokio::{
     fs::File,
-    io::{AsyncWriteExt, BufReader},
-    process::Command,
-    sync::{watch, Notify},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch, Notify},
+    time::Instant,
 };
 use uuid::Uuid;
 
 use crate::{
     cgroup::PROC_FILE,
+    clone3::{self, Fork},
+    exec::Exec,
+    logfile::{Fd, Format, Limit, Sink},
+    namespace::Namespaces,
+    pidfd::PidFd,
+    rlimit::Rlimits,
     runner::{self, job_status::Outcome, JobRequest},
+    seccomp::{self, Filter, Violations},
     stack_string, Empty,
 };
-use nix::libc::{setgid, setuid};
+use nix::libc::{
+    c_int, c_uint, umask, SYS_setgid, SYS_setgroups, SYS_setuid, CLOSE_RANGE_CLOEXEC, FD_CLOEXEC,
+    F_SETFD, PR_CAPBSET_DROP, PR_CAP_AMBIENT, PR_CAP_AMBIENT_CLEAR_ALL, PR_SET_NO_NEW_PRIVS,
+    RLIMIT_NOFILE,
+};
+
+pub const OUT_FIFO: &str = "out.fifo";
+pub const ERR_FIFO: &str = "err.fifo";
+// How long output is drained once job process exits
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+// Output job can write to fifo while server is not there to read it, before it blocks.
+// Pipe buffer is 64 KiB by default
+const FIFO_SIZE: c_int = 1 << 20;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -28,32 +67,46 @@ pub enum Error {
     STString(#[from] stack_string::Error),
     #[error(transparent)]
     IOError(#[from] std::io::Error),
+    #[error("Failed to allocate pty: {0}")]
+    Pty(#[from] nix::Error),
+    #[error("Failed to set up output fifo: {0}")]
+    Fifo(nix::Error),
+    #[error("Job({0}) has already exited")]
+    Exited(Uuid),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy)]
 pub enum JobStatus {
+    #[default]
     Running,
     Exit(i32),
     Signal(i32),
+    Lost,
 }
 
-impl<'a> From<RwLockReadGuard<'a, JobStatus>> for runner::JobStatus {
-    fn from(value: RwLockReadGuard<'a, JobStatus>) -> Self {
-        match *value {
-            JobStatus::Running => runner::JobStatus { outcome: None },
-            JobStatus::Exit(code) => runner::JobStatus {
-                outcome: Some(Outcome::ExitCode(code)),
-            },
-            JobStatus::Signal(signal) => runner::JobStatus {
-                outcome: Some(Outcome::Signal(signal)),
-            },
+impl From<&runner::JobStatus> for JobStatus {
+    fn from(value: &runner::JobStatus) -> Self {
+        match value.outcome {
+            None => JobStatus::Running,
+            Some(Outcome::ExitCode(code)) => JobStatus::Exit(code),
+            Some(Outcome::Signal(signal)) => JobStatus::Signal(signal),
+            Some(Outcome::Lost(_)) => JobStatus::Lost,
         }
     }
 }
 
-impl Default for JobStatus {
-    fn default() -> Self {
-        JobStatus::Running
+impl<'a> From<RwLockReadGuard<'a, JobStatus>> for runner::JobStatus {
+    fn from(value: RwLockReadGuard<'a, JobStatus>) -> Self {
+        let outcome = match *value {
+            JobStatus::Running => None,
+            JobStatus::Exit(code) => Some(Outcome::ExitCode(code)),
+            JobStatus::Signal(signal) => Some(Outcome::Signal(signal)),
+            JobStatus::Lost => Some(Outcome::Lost(true)),
+        };
+        runner::JobStatus {
+            outcome,
+            ..Default::default()
+        }
     }
 }
 
@@ -62,18 +115,114 @@ pub struct Job<S> {
     id: Uuid,
     cancel: Arc<Notify>,
     status: Arc<RwLock<JobStatus>>,
+    tty: Option<Tty>,
+    log_format: Format,
+    log_limit: Option<Limit>,
+    // Stdin pipe is created on spawn, along with fifos for output
+    pipe_stdin: bool,
+    // Socket seccomp filter listener is passed over on spawn
+    seccomp: Option<Seccomp>,
     state: S,
 }
 
+/// Window size of pseudo-terminal allocated for the job
+#[derive(Debug, Clone, Copy)]
+pub struct Tty {
+    pub rows: u16,
+    pub cols: u16,
+}
+
+impl Default for Tty {
+    fn default() -> Self {
+        Self { rows: 24, cols: 80 }
+    }
+}
+
+impl Tty {
+    fn requested(job_request: &JobRequest) -> Option<Tty> {
+        job_request.tty.then(|| {
+            job_request
+                .window_size
+                .as_ref()
+                .map(Tty::from)
+                .unwrap_or_default()
+        })
+    }
+}
+
+impl From<&runner::WindowSize> for Tty {
+    fn from(value: &runner::WindowSize) -> Self {
+        Self {
+            rows: value.rows.try_into().unwrap_or(u16::MAX),
+            cols: value.cols.try_into().unwrap_or(u16::MAX),
+        }
+    }
+}
+
+impl From<Tty> for Winsize {
+    fn from(value: Tty) -> Self {
+        Winsize {
+            ws_row: value.rows,
+            ws_col: value.cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Started {
     cgroup_dir: PathBuf,
     job_dir: PathBuf,
+    pid: Option<u32>,
+    // Absent for jobs completed before restart
+    pidfd: Option<Arc<PidFd>>,
     completion: watch::Receiver<bool>,
+    stdin: Option<mpsc::Sender<Input>>,
+    violations: Violations,
+    // Set for tty jobs adopted after restart, whose output is no longer captured
+    output_lost: bool,
 }
 
+/// Input forwarded to the job stdin by writer task
+#[derive(Debug)]
+pub enum Input {
+    Data(Vec<u8>),
+    /// Ignored unless job is attached to pseudo-terminal
+    Resize(Tty),
+    /// Ignored if job is attached to pseudo-terminal, which stays open for later attaches
+    Eof,
+}
+
+type LogReader = Box<dyn AsyncRead + Unpin + Send>;
+
 pub struct Initialized;
 
+// Cleared once clone3 turns out to be unsupported, so that later jobs go straight to fork
+static CLONE_INTO_CGROUP: AtomicBool = AtomicBool::new(true);
+
+/// Cgroup job process is placed into on spawn
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    // Written by child which is forked rather than cloned straight into cgroup
+    procs: stack_string::String<256>,
+}
+
+impl Cgroup {
+    // Moves calling process into cgroup, called by the child so nothing allocates
+    fn enter(&self) -> std::io::Result<()> {
+        let pid: stack_string::String<11> = getpid().as_raw().into();
+        std::fs::write(self.procs, pid)
+    }
+}
+
+// Parent end of socket pair, child sends listener of its seccomp filter over the other one
+#[derive(Debug)]
+struct Seccomp {
+    sock: std::fs::File,
+}
+
 impl Default for Job<Empty> {
     fn default() -> Self {
         let id = Uuid::new_v4();
@@ -83,11 +232,115 @@ impl Default for Job<Empty> {
             id,
             cancel,
             status,
+            tty: None,
+            log_format: Format::default(),
+            log_limit: None,
+            pipe_stdin: false,
+            seccomp: None,
             state: Empty,
         }
     }
 }
 impl Job<Started> {
+    /// Job completed before server restart, only its status and logs are left
+    pub fn restore(
+        id: Uuid,
+        job_request: &JobRequest,
+        status: &runner::JobStatus,
+        job_dir: PathBuf,
+        cgroup_dir: PathBuf,
+    ) -> Self {
+        // Dropped sender marks job complete for subscribers
+        let (_, completion) = watch::channel(true);
+        Job {
+            id,
+            cancel: Arc::new(Notify::new()),
+            status: Arc::new(RwLock::new(status.into())),
+            tty: Tty::requested(job_request),
+            log_format: job_request.log_format().into(),
+            log_limit: None,
+            pipe_stdin: false,
+            seccomp: None,
+            state: Started {
+                cgroup_dir,
+                job_dir,
+                pid: None,
+                pidfd: None,
+                completion,
+                stdin: None,
+                violations: status.seccomp_violations.as_slice().into(),
+                output_lost: false,
+            },
+        }
+    }
+
+    /// Takes over job left running by previous server process. Output capture resumes
+    /// from its fifos, but exit status is lost as the job is not a child of this process
+    pub async fn adopt(
+        id: Uuid,
+        job_request: &JobRequest,
+        pidfd: PidFd,
+        job_dir: PathBuf,
+        cgroup_dir: PathBuf,
+        log_limit: Option<Limit>,
+    ) -> Result<Self, Error> {
+        let tty = Tty::requested(job_request);
+        let log_format = job_request.log_format().into();
+        let (out, err): (LogReader, LogReader) = match tty {
+            // pty master is gone along with previous server process
+            Some(_) => {
+                error!("Output of job({}) is lost along with its terminal", id);
+                (Box::new(tokio::io::empty()), Box::new(tokio::io::empty()))
+            }
+            None => (
+                Box::new(open_fifo(&job_dir, Fd::Out)?),
+                Box::new(open_fifo(&job_dir, Fd::Err)?),
+            ),
+        };
+        let sink = Sink::resume(&job_dir, log_format, log_limit).await?;
+
+        let cancel = Arc::new(Notify::new());
+        let status = Arc::new(RwLock::new(JobStatus::default()));
+        let (tx, rx) = watch::channel(false);
+        let capture = Capture {
+            out,
+            err,
+            sink,
+            is_tty: tty.is_some(),
+            job_dir: job_dir.clone(),
+        };
+        let pidfd = Arc::new(pidfd);
+        let process = Process {
+            pidfd: pidfd.clone(),
+            is_child: false,
+        };
+        supervise(id, capture, process, cancel.clone(), status.clone(), tx);
+        // Filter listener is gone along with previous server process,
+        // so further violations fail with ENOSYS and go unreported
+        let violations = Violations::load(&job_dir).await;
+
+        Ok(Job {
+            id,
+            cancel,
+            status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin: false,
+            seccomp: None,
+            state: Started {
+                cgroup_dir,
+                job_dir,
+                pid: None,
+                pidfd: Some(pidfd),
+                completion: rx,
+                stdin: None,
+                violations,
+                output_lost: tty.is_some(),
+            },
+        })
+    }
+
     pub fn job_dir(&self) -> &Path {
         &self.state.job_dir
     }
@@ -96,26 +349,87 @@ impl Job<Started> {
         &self.state.cgroup_dir
     }
 
+    /// Pid of the job process, known only for jobs spawned by this server process
+    pub fn pid(&self) -> Option<u32> {
+        self.state.pid
+    }
+
+    /// Signals job process through its pidfd, so signal never reaches process reusing its pid
+    pub fn signal(&self, signal: Signal) -> Result<(), Error> {
+        match &self.state.pidfd {
+            Some(pidfd) if !self.is_complete() => Ok(pidfd.signal(signal)?),
+            _ => Err(Error::Exited(self.id)),
+        }
+    }
+
+    /// Waits for the job process to exit, while status is collected by job task
+    pub async fn wait(&self) -> runner::JobStatus {
+        self.completion().await
+    }
+
     pub fn subscribe(&self) -> watch::Receiver<bool> {
         self.state.completion.clone()
     }
 
+    /// Resolves with final status once job completes
+    pub fn completion(&self) -> impl Future<Output = runner::JobStatus> + Send + 'static {
+        let mut completion = self.subscribe();
+        let status = self.status.clone();
+        let violations = self.state.violations.clone();
+
+        async move {
+            // Dropped sender means job is complete as well
+            while !*completion.borrow() && completion.changed().await.is_ok() {}
+
+            let mut status: runner::JobStatus = match status.read() {
+                Ok(status) => status.into(),
+                Err(err) => {
+                    error!("Failed to read job status: {}", err);
+                    runner::JobStatus::default()
+                }
+            };
+            status.seccomp_violations = violations.to_vec();
+            status
+        }
+    }
+
+    /// Handle to job stdin, present only if job was started with stdin
+    pub fn stdin(&self) -> Option<mpsc::Sender<Input>> {
+        self.state.stdin.clone()
+    }
+
+    pub fn is_tty(&self) -> bool {
+        self.tty.is_some()
+    }
+
+    /// Whether output of the job is lost rather than logged, as pty master of tty job
+    /// adopted after restart is gone along with previous server process
+    pub fn is_output_lost(&self) -> bool {
+        self.state.output_lost && !self.is_complete()
+    }
+
+    pub fn log_format(&self) -> Format {
+        self.log_format
+    }
+
     pub fn cancel(&self) {
         self.cancel.notify_one()
     }
 
     pub fn status(&self) -> runner::JobStatus {
-        if !self.is_complete() {
-            runner::JobStatus { outcome: None }
+        let mut status = if !self.is_complete() {
+            runner::JobStatus::default()
         } else {
             match self.status.read() {
                 Ok(status) => status.into(),
                 Err(err) => {
                     error!("Failed to read job status: {}", err);
-                    runner::JobStatus { outcome: None }
+                    runner::JobStatus::default()
                 }
             }
-        }
+        };
+        status.seccomp_violations = self.state.violations.to_vec();
+        status
     }
 
     pub fn is_complete(&self) -> bool {
@@ -135,59 +449,123 @@ impl<T> Job<T> {
 }
 
 impl Job<Empty> {
-    pub fn add_command(self, job_request: &JobRequest) -> Job<(Command, Empty, Empty, Empty)> {
+    pub fn add_command(self, job_request: &JobRequest) -> Job<(Exec, Empty, Empty, Empty)> {
         let Self {
-            id, cancel, status, ..
+            id,
+            cancel,
+            status,
+            log_limit,
+            ..
         } = self;
 
-        let mut handle = Command::new(&job_request.executable);
-        handle
-            .args(&job_request.args)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+        // Stdio of tty job is attached to pty allocated on spawn
+        let tty = Tty::requested(job_request);
+
+        let log_format = job_request.log_format().into();
+
+        let pipe_stdin = job_request.stdin.is_some() || job_request.keep_stdin_open;
+
+        let exec = Exec::new(job_request);
 
         Job {
             id,
             cancel,
             status,
-            state: (handle, Empty, Empty, Empty),
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp: None,
+            state: (exec, Empty, Empty, Empty),
         }
     }
 }
 
-impl<P, O, C> Job<(Command, P, O, C)> {
-    pub fn set_job_dir(self, job_dir: PathBuf) -> Job<(Command, PathBuf, O, C)> {
+impl<P, O, C> Job<(Exec, P, O, C)> {
+    pub fn set_job_dir(self, job_dir: PathBuf) -> Job<(Exec, PathBuf, O, C)> {
         let Self {
             id,
             cancel,
             status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
             state,
-            ..
-        }: Job<(Command, P, O, C)> = self;
-        let (mut cmd, _, ownership, cgroup) = state;
-        cmd.current_dir(&job_dir);
+        }: Job<(Exec, P, O, C)> = self;
+        let (mut exec, _, ownership, cgroup) = state;
+        exec.current_dir(&job_dir);
+        Job {
+            id,
+            cancel,
+            status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
+            state: (exec, job_dir, ownership, cgroup),
+        }
+    }
+
+    /// Caps size of job logs, unlimited by default
+    pub fn set_log_limit(mut self, log_limit: Option<Limit>) -> Self {
+        self.log_limit = log_limit;
+        self
+    }
+}
+
+impl<O, C> Job<(Exec, PathBuf, O, C)> {
+    /// Overrides working directory of the job, which defaults to job directory
+    pub fn set_working_dir(self, working_dir: Option<OwnedFd>) -> Self {
+        let Self {
+            id,
+            cancel,
+            status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
+            state,
+        } = self;
+        let (mut exec, job_dir, ownership, cgroup) = state;
+        if let Some(working_dir) = working_dir {
+            exec.current_dir_fd(working_dir);
+        }
+
         Job {
             id,
             cancel,
             status,
-            state: (cmd, job_dir, ownership, cgroup),
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
+            state: (exec, job_dir, ownership, cgroup),
         }
     }
 }
 
-impl<P> Job<(Command, P, Empty, Empty)> {
+impl<P> Job<(Exec, P, Empty, Empty)> {
     pub fn add_to_cgroup(
         self,
         cgroup_path: PathBuf,
-    ) -> Result<Job<(Command, P, Empty, PathBuf)>, Error> {
+    ) -> Result<Job<(Exec, P, Empty, Cgroup)>, Error> {
         let Self {
             id,
             cancel,
             status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
             state,
-            ..
         } = self;
-        let (mut cmd, job_dir, _, _) = state;
+        let (exec, job_dir, _, _) = state;
         let cgroup_procs: stack_string::String<256> = cgroup_path
             .join(PROC_FILE)
             // BASE_PATH is &str,
@@ -198,167 +576,731 @@ impl<P> Job<(Command, P, Empty, Empty)> {
             .expect("Panic on valid utf8")
             .try_into()?;
 
-        // Safety: all calls inside closure are async-signal-safe
-        unsafe {
-            cmd.pre_exec(move || {
-                let pid: stack_string::String<11> = getpid().as_raw().into();
-                std::fs::write(cgroup_procs, pid)?;
-                Ok(())
-            });
-        }
+        let cgroup = Cgroup {
+            path: cgroup_path,
+            procs: cgroup_procs,
+        };
 
         Ok(Job {
             id,
             cancel,
             status,
-            state: (cmd, job_dir, Empty, cgroup_path),
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
+            state: (exec, job_dir, Empty, cgroup),
         })
     }
 }
 
-impl Job<(Command, PathBuf, Initialized, PathBuf)> {
+impl Job<(Exec, PathBuf, Initialized, Cgroup)> {
     pub fn spawn(self) -> Result<Job<Started>, Error> {
         let Self {
             id,
             cancel,
             status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
             state,
-            ..
         } = self;
-        let (mut cmd, job_dir, _, cgroup_dir, ..) = state;
-        let mut child = cmd.spawn()?;
+        let (mut exec, job_dir, _, cgroup, ..) = state;
+        let (tx, rx) = watch::channel(false);
 
-        // Unwrap: Command is instantiated by Job and is a private field
-        // therefore out/error must be present
-        let out = child.stdout.take().expect("Child stdout is missing");
-        let err = child.stderr.take().expect("Child stderr is missing");
+        let master = tty
+            .map(|tty| Self::attach_tty(&mut exec, tty))
+            .transpose()?;
+        let (out, err): (LogReader, LogReader) = match &master {
+            Some(master) => (
+                Box::new(File::from_std(master.try_clone()?)),
+                Box::new(tokio::io::empty()),
+            ),
+            None => (
+                Box::new(Self::attach_fifo(&mut exec, &job_dir, Fd::Out)?),
+                Box::new(Self::attach_fifo(&mut exec, &job_dir, Fd::Err)?),
+            ),
+        };
+        let stdin = match (&master, pipe_stdin) {
+            (None, true) => Some(Self::attach_stdin(&mut exec)?),
+            _ => None,
+        };
 
-        let mut out = BufReader::new(out);
-        let mut err = BufReader::new(err);
+        // Everything fallible is done before spawn where possible, so that
+        // failure doesn't leave the job running unsupervised
+        let sink = Sink::create(&job_dir, log_format, log_limit)?;
 
-        let cancel_clone = cancel.clone();
-        let status_clone = status.clone();
+        let (pid, pidfd) = Self::spawn_process(&mut exec, &cgroup)?;
+        let pidfd = Arc::new(pidfd);
+        // Closes parent copies of pty slave, stdin reader and fifo writers,
+        // so that reads see the end of output once job exits
+        drop(exec);
 
-        let outfile = job_dir.join("out");
-        let errfile = job_dir.join("err");
+        let violations = Violations::default();
+        if let Some(Seccomp { sock }) = seccomp {
+            // Sent by the child before exec, so it is there once spawn succeeds
+            let watched = seccomp::recv_listener(sock.as_raw_fd())
+                .and_then(|listener| seccomp::watch(listener, violations.clone(), job_dir.clone()));
+            if let Err(err) = watched {
+                abandon(pidfd);
+                return Err(err.into());
+            }
+        }
 
-        let (tx, rx) = watch::channel(false);
+        let stdin = match master {
+            Some(master) => Some(Self::spawn_stdin_writer(
+                id,
+                File::from_std(master),
+                true,
+                rx.clone(),
+            )),
+            None => stdin.map(|stdin| Self::spawn_stdin_writer(id, stdin, false, rx.clone())),
+        };
 
-        tokio::spawn(async move {
-            let mut wout = File::create(outfile).await?;
-            let mut werr = File::create(errfile).await?;
+        let capture = Capture {
+            out,
+            err,
+            sink,
+            is_tty: tty.is_some(),
+            job_dir: job_dir.clone(),
+        };
+        let process = Process {
+            pidfd: pidfd.clone(),
+            is_child: true,
+        };
+        supervise(id, capture, process, cancel.clone(), status.clone(), tx);
 
+        Ok(Job {
+            id,
+            cancel,
+            status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp: None,
+            state: Started {
+                cgroup_dir: cgroup.path,
+                job_dir,
+                pid: Some(pid),
+                pidfd: Some(pidfd),
+                completion: rx,
+                stdin,
+                violations,
+                output_lost: false,
+            },
+        })
+    }
+
+    // Prefers clone3, which starts child in its cgroup, so that it never runs in cgroup of
+    // the server. Falls back to fork, after which child moves itself into cgroup
+    fn spawn_process(exec: &mut Exec, cgroup: &Cgroup) -> Result<(u32, PidFd), Error> {
+        let cgroup_dir = std::fs::File::open(&cgroup.path)?;
+        // Child reports failure through the pipe, successful exec closes it
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC).map_err(std::io::Error::from)?;
+        // Safety: fds were just allocated by pipe2 and are owned by nothing else
+        let (mut reader, writer) = unsafe {
+            (
+                std::fs::File::from_raw_fd(reader),
+                std::fs::File::from_raw_fd(writer),
+            )
+        };
+
+        let cloned = match CLONE_INTO_CGROUP.load(Ordering::Relaxed) {
+            // Safety: child does nothing but exec, see `exec_child`
+            true => match unsafe { clone3::clone_into_cgroup(&cgroup_dir) } {
+                Err(err) if clone3::is_unsupported(&err) => {
+                    CLONE_INTO_CGROUP.store(false, Ordering::Relaxed);
+                    None
+                }
+                cloned => Some(cloned?),
+            },
+            false => None,
+        };
+        let (pid, pidfd) = match cloned {
+            Some(Fork::Parent { pid, pidfd }) => (pid, PidFd::try_from(pidfd)?),
+            Some(Fork::Child) => Self::exec_child(exec, None, &writer),
+            // Safety: as above
+            None => match unsafe { libc::fork() } {
+                0 => Self::exec_child(exec, Some(cgroup), &writer),
+                pid if pid < 0 => return Err(std::io::Error::last_os_error().into()),
+                // Child is not reaped until waited on through pidfd, so pid can't be reused
+                pid => (pid as u32, PidFd::open(pid as u32)?),
+            },
+        };
+        drop(writer);
+
+        let mut errno = [0u8; 4];
+        match reader.read_exact(&mut errno) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok((pid, pidfd)),
+            Err(err) => Err(err.into()),
+            Ok(()) => {
+                // Reaps child which failed to exec
+                let _ = waitpid(Pid::from_raw(pid as i32), None);
+                Err(std::io::Error::from_raw_os_error(i32::from_ne_bytes(errno)).into())
+            }
+        }
+    }
+
+    // Runs in the child, which moves itself into `cgroup` unless it was cloned into it.
+    // Exec returns only on failure, which is reported through `writer`
+    fn exec_child(exec: &mut Exec, cgroup: Option<&Cgroup>, writer: &std::fs::File) -> ! {
+        let err = match cgroup.map(Cgroup::enter).transpose() {
+            Ok(_) => exec.exec(),
+            Err(err) => err,
+        };
+        let errno = err.raw_os_error().unwrap_or(EINVAL).to_ne_bytes();
+        // Safety: write and _exit are async-signal-safe, errno outlives the call
+        unsafe {
+            libc::write(writer.as_raw_fd(), errno.as_ptr().cast(), errno.len());
+            libc::_exit(127);
+        }
+    }
+
+    // Pipe is created upfront, as the child must not allocate
+    fn attach_stdin(exec: &mut Exec) -> Result<File, Error> {
+        let (reader, writer) = pipe2(OFlag::O_CLOEXEC).map_err(std::io::Error::from)?;
+        // Safety: fds were just allocated by pipe2 and are owned by nothing else
+        let (reader, writer) = unsafe {
+            (
+                std::fs::File::from_raw_fd(reader),
+                std::fs::File::from_raw_fd(writer),
+            )
+        };
+        exec.stdin(reader);
+
+        Ok(File::from_std(writer))
+    }
+
+    // Output goes through fifo rather than pipe, so that job keeps its stdout and stderr
+    // across server restarts, see `Job::adopt`. Child holds fifo open for both reading
+    // and writing, so it doesn't get SIGPIPE while server is not there to read. It still
+    // blocks once fifo fills up until server is back, so fifo is enlarged to `FIFO_SIZE`
+    fn attach_fifo(exec: &mut Exec, job_dir: &Path, fd: Fd) -> Result<File, Error> {
+        let path = fifo_path(job_dir, fd);
+        mkfifo(&path, Mode::S_IRUSR | Mode::S_IWUSR).map_err(Error::Fifo)?;
+        let writer = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        // Beyond fs.pipe-max-size it takes CAP_SYS_RESOURCE, default size does then
+        if let Err(err) = fcntl(writer.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(FIFO_SIZE)) {
+            error!("Failed to enlarge fifo {}: {}", path.display(), err);
+        }
+        // Does not block, as writer is already there
+        let reader = std::fs::File::open(&path)?;
+
+        match fd {
+            Fd::Out => exec.stdout(writer),
+            Fd::Err => exec.stderr(writer),
+        };
+
+        Ok(File::from_std(reader))
+    }
+
+    // Allocates pty pair, slave becomes stdio and controlling terminal of the job
+    fn attach_tty(exec: &mut Exec, tty: Tty) -> Result<std::fs::File, Error> {
+        let OpenptyResult { master, slave } = openpty(Some(&tty.into()), None)?;
+        // Safety: fds are freshly allocated by openpty and owned by nothing else
+        let (master, slave) = unsafe {
+            (
+                std::fs::File::from_raw_fd(master),
+                std::fs::File::from_raw_fd(slave),
+            )
+        };
+        set_cloexec(&master)?;
+        set_cloexec(&slave)?;
+
+        exec.stdin(slave.try_clone()?)
+            .stdout(slave.try_clone()?)
+            .stderr(slave);
+
+        // Safety: all calls are async-signal-safe;
+        unsafe {
+            exec.pre_exec(|| {
+                if setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                // stdio is already redirected to pty slave at this point
+                if ioctl(0, TIOCSCTTY, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                Ok(())
+            });
+        }
+
+        Ok(master)
+    }
+
+    // Writing happens in separate task, otherwise job blocked on writing
+    // to full stdout pipe would never drain its stdin and vice versa
+    fn spawn_stdin_writer<W>(
+        id: Uuid,
+        mut stdin: W,
+        is_tty: bool,
+        mut completion: watch::Receiver<bool>,
+    ) -> mpsc::Sender<Input>
+    where
+        W: AsyncWrite + AsRawFd + Unpin + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel(20);
+
+        tokio::spawn(async move {
             loop {
                 tokio::select! {
-                    outcome = tokio::io::copy_buf(&mut out, &mut wout) => {
-                        if let Err(err) = outcome {
-                            error!("Error copying from child stdout to file: {}", err);
+                    _ = completion.changed() => break,
+                    input = rx.recv() => match input {
+                        Some(Input::Data(data)) => {
+                            let outcome = match stdin.write_all(&data).await {
+                                Ok(()) => stdin.flush().await,
+                                err => err,
+                            };
+                            if let Err(err) = outcome {
+                                error!("Failed to write to job({}) stdin: {}", id, err);
+                                break;
+                            }
                         }
-                    }
-                    outcome = tokio::io::copy_buf(&mut err, &mut werr) => {
-                        if let Err(err) = outcome {
-                            error!("Error copying from child stderr to file: {}", err);
+                        Some(Input::Resize(tty)) if is_tty => {
+                            let winsize: Winsize = tty.into();
+                            // Safety: fd is owned by writer and winsize outlives the call
+                            if unsafe { ioctl(stdin.as_raw_fd(), TIOCSWINSZ, &winsize) } < 0 {
+                                error!(
+                                    "Failed to resize job({}) tty: {}",
+                                    id,
+                                    std::io::Error::last_os_error()
+                                );
+                            }
                         }
-                    }
-                    _ = cancel_clone.notified() => {
-                        wout.flush().await?;
-                        werr.flush().await?;
-                        child.kill().await?;
-                        // Can error only if RwLock is poisoned
-                        // which cannot happen b/c it is the fist and only
-                        // time this lock is used for writing;
-                        let mut status = status_clone.write().unwrap();
-                        *status = JobStatus::Signal(9);
-                        break;
-                    }
-                    outcome = child.wait() => {
-                        wout.flush().await?;
-                        werr.flush().await?;
-                        match outcome {
-                            Ok(outcome) => {
-                                if let Some(code) = outcome.code() {
-                                    let mut status = status_clone.write().unwrap();
-                                    *status = JobStatus::Exit(code);
-                                } else {
-                                    let signal = outcome.signal()
-                                        .expect("Neither exit code nor signal");
-                                    let mut status = status_clone.write().unwrap();
-                                    *status = JobStatus::Signal(signal);
-                                }
-                            },
-
-                            Err(outcome) => {
-                                let exit_code = outcome.raw_os_error().unwrap_or_else(|| {
-                                    error!("No exit code on child process failure: {}", outcome);
-                                    -1
-                                });
-
-                                let mut status = status_clone.write().unwrap();
-                                *status = JobStatus::Exit(exit_code);
-                            },
-                        }
-
-                        break;
+                        Some(Input::Resize(_)) => (),
+                        // Closing pty master would hang the job up
+                        Some(Input::Eof) if is_tty => (),
+                        // Dropping stdin closes the pipe
+                        Some(Input::Eof) | None => break,
                     }
                 }
             }
+        });
 
-            if let Err(err) = tx.send(true) {
-                error!(
-                    "Failed to notify about job completion: {}, for job({})",
-                    err, id
-                );
+        tx
+    }
+}
+
+impl<P> Job<(Exec, P, Empty, Cgroup)> {
+    /// Isolates job in namespaces of its own, before `set_ownership` drops privileges
+    /// needed to create them. Job shares namespaces of the server if `namespaces` is None
+    pub fn isolate(mut self, namespaces: Option<Namespaces>) -> Self {
+        if let Some(namespaces) = namespaces {
+            let (exec, ..) = &mut self.state;
+            // Safety: all calls are async-signal-safe, see `Namespaces::enter`
+            unsafe {
+                exec.pre_exec(move || namespaces.enter());
             }
+        }
 
-            Ok::<(), std::io::Error>(())
-        });
+        self
+    }
 
-        Ok(Job {
+    /// Sets rlimits of the job, before `set_ownership` drops privilege to raise hard limits
+    pub fn set_rlimits(mut self, rlimits: Rlimits) -> Self {
+        if !rlimits.is_empty() {
+            let (exec, ..) = &mut self.state;
+            // Safety: setrlimit is async-signal-safe, see `Rlimits::apply`
+            unsafe {
+                exec.pre_exec(move || rlimits.apply());
+            }
+        }
+
+        self
+    }
+
+    /// Runs job as `uid`, with `gid` and supplementary `groups`, hardened on the way, see `harden`.
+    /// Empty `groups` are left as they are, as in user namespace where setgroups is denied
+    pub fn set_ownership(
+        self,
+        uid: u32,
+        gid: u32,
+        groups: Vec<u32>,
+    ) -> Job<(Exec, P, Initialized, Cgroup)> {
+        let Self {
             id,
             cancel,
             status,
-            state: Started {
-                cgroup_dir,
-                job_dir,
-                completion: rx,
-            },
-        })
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
+            state,
+        } = self;
+        let (mut exec, job_dir, _, cgroup) = state;
+
+        // Safety: all calls are async-signal-safe, see `harden`
+        unsafe {
+            exec.pre_exec(move || harden(uid, gid, &groups));
+        }
+
+        Job {
+            id,
+            cancel,
+            status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp,
+            state: (exec, job_dir, Initialized, cgroup),
+        }
     }
 }
 
-impl<P> Job<(Command, P, Empty, PathBuf)> {
-    pub fn set_ownership(self, uid: u32, gid: u32) -> Job<(Command, P, Initialized, PathBuf)> {
+impl<P> Job<(Exec, P, Initialized, Cgroup)> {
+    /// Confines job to syscalls allowed by seccomp filter. Filter is installed right
+    /// before exec, once privileges are dropped, so that it only restricts the job itself
+    pub fn confine(self, filter: Option<Filter>) -> Result<Self, Error> {
+        let Some(filter) = filter else {
+            return Ok(self);
+        };
         let Self {
             id,
             cancel,
             status,
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
             state,
             ..
         } = self;
-        let (mut cmd, job_dir, _, cgroup) = state;
-
-        // Safety: all calls are async-signal-safe;
-        unsafe {
-            cmd.pre_exec(move || {
-                match setgid(gid) {
-                    0 => (),
-                    x => return Err(std::io::Error::from_raw_os_error(x)),
-                }
+        let (mut exec, job_dir, ownership, cgroup) = state;
 
-                match setuid(uid) {
-                    0 => (),
-                    x => return Err(std::io::Error::from_raw_os_error(x)),
+        // Only filter which reports violations has listener to pass
+        let (sock, child_sock) = match filter.is_reported() {
+            true => {
+                let (sock, child_sock) = socketpair(
+                    AddressFamily::Unix,
+                    SockType::SeqPacket,
+                    None,
+                    SockFlag::SOCK_CLOEXEC,
+                )?;
+                // Safety: fds were just allocated by socketpair and are owned by nothing else
+                unsafe {
+                    (
+                        Some(std::fs::File::from_raw_fd(sock)),
+                        Some(std::fs::File::from_raw_fd(child_sock)),
+                    )
                 }
+            }
+            false => (None, None),
+        };
 
-                Ok(())
-            });
+        // Safety: all calls are async-signal-safe, see `Filter::install`
+        unsafe {
+            exec.pre_exec(move || filter.install(child_sock.as_ref().map(AsRawFd::as_raw_fd)));
         }
 
-        Job {
+        Ok(Job {
             id,
             cancel,
             status,
-            state: (cmd, job_dir, Initialized, cgroup),
+            tty,
+            log_format,
+            log_limit,
+            pipe_stdin,
+            seccomp: sock.map(|sock| Seccomp { sock }),
+            state: (exec, job_dir, ownership, cgroup),
+        })
+    }
+}
+
+// Drops privileges of the child, calls are ordered so that each one still has
+// capabilities it needs. Every call is async-signal-safe and nothing allocates.
+// Credentials are set by raw syscalls: glibc wrappers sync them across threads of the
+// server, which child cloned by clone3 still believes it has, and may wait forever
+// for one which was being created at the time
+pub(crate) fn harden(uid: u32, gid: u32, groups: &[u32]) -> std::io::Result<()> {
+    // Safety: groups outlive the call, the rest take no pointers
+    unsafe {
+        // Otherwise job keeps supplementary groups of the server
+        if !groups.is_empty() {
+            check(libc::syscall(SYS_setgroups, groups.len(), groups.as_ptr()) as c_int)?;
+        }
+
+        // Capabilities can't be regained through setuid binaries or file capabilities
+        // once dropped from bounding set. Capabilities unknown to kernel fail with EINVAL
+        for cap in 0..64 {
+            if libc::prctl(PR_CAPBSET_DROP, cap, 0, 0, 0) < 0 {
+                match std::io::Error::last_os_error() {
+                    err if err.raw_os_error() == Some(EINVAL) => break,
+                    err => return Err(err),
+                }
+            }
+        }
+
+        check(libc::syscall(SYS_setgid, gid) as c_int)?;
+        check(libc::syscall(SYS_setuid, uid) as c_int)?;
+        // Ambient capabilities would survive exec of the job otherwise
+        check(libc::prctl(
+            PR_CAP_AMBIENT,
+            PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        ))?;
+        check(libc::prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+
+        umask(0o077);
+    }
+
+    cloexec_inherited();
+    Ok(())
+}
+
+// Fds leaked by the server without O_CLOEXEC are closed on exec. Fds past stdio are
+// marked rather than closed, as pre_exec closures run later may still use them
+fn cloexec_inherited() {
+    // Safety: only changes flags of fds of this process
+    if unsafe { libc::close_range(3, c_uint::MAX, CLOSE_RANGE_CLOEXEC as c_int) } == 0 {
+        return;
+    }
+
+    // Kernel older than 5.11
+    let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+    // Safety: limit outlives the call and is initialized on success
+    let max = match unsafe { libc::getrlimit(RLIMIT_NOFILE, limit.as_mut_ptr()) } {
+        0 => unsafe { limit.assume_init() }
+            .rlim_cur
+            .min(c_int::MAX as u64) as c_int,
+        _ => 1024,
+    };
+    (3..max).for_each(|fd| {
+        // Safety: see above, fails with EBADF for fds which are not open
+        unsafe { libc::fcntl(fd, F_SETFD, FD_CLOEXEC) };
+    });
+}
+
+fn check(res: c_int) -> std::io::Result<()> {
+    if res < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Fifo job writes its `fd` to
+pub fn fifo_path(job_dir: &Path, fd: Fd) -> PathBuf {
+    match fd {
+        Fd::Out => job_dir.join(OUT_FIFO),
+        Fd::Err => job_dir.join(ERR_FIFO),
+    }
+}
+
+// Opens fifo of running job without blocking in case its writer is already gone
+fn open_fifo(job_dir: &Path, fd: Fd) -> Result<File, Error> {
+    let fifo = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(fifo_path(job_dir, fd))?;
+    // Reads are done on blocking pool, see `tokio::fs::File`
+    fcntl(fifo.as_raw_fd(), FcntlArg::F_SETFL(OFlag::empty())).map_err(Error::Fifo)?;
+
+    Ok(File::from_std(fifo))
+}
+
+// Output of supervised process along with log it is stored to
+struct Capture {
+    out: LogReader,
+    err: LogReader,
+    sink: Sink,
+    is_tty: bool,
+    job_dir: PathBuf,
+}
+
+// Process supervised by job task, either spawned by this server process or adopted
+struct Process {
+    pidfd: Arc<PidFd>,
+    // Exit status is reported only to the parent
+    is_child: bool,
+}
+
+impl Process {
+    async fn kill(&self) -> std::io::Result<()> {
+        match self.pidfd.signal(Signal::SIGKILL) {
+            Err(err) if err.raw_os_error() != Some(ESRCH) => return Err(err),
+            // Already exited, yet to be reaped
+            _ => (),
+        }
+        self.wait().await;
+
+        Ok(())
+    }
+
+    async fn wait(&self) -> JobStatus {
+        if !self.is_child {
+            if let Err(err) = self.pidfd.exited().await {
+                error!("Failed to wait for adopted process: {}", err);
+            }
+            return JobStatus::Lost;
+        }
+
+        match self.pidfd.wait().await {
+            Ok(outcome) => {
+                if let Some(code) = outcome.code() {
+                    JobStatus::Exit(code)
+                } else {
+                    let signal = outcome.signal().expect("Neither exit code nor signal");
+                    JobStatus::Signal(signal)
+                }
+            }
+
+            Err(outcome) => {
+                let exit_code = outcome.raw_os_error().unwrap_or_else(|| {
+                    error!("No exit code on child process failure: {}", outcome);
+                    -1
+                });
+
+                JobStatus::Exit(exit_code)
+            }
         }
     }
 }
+
+// Kills and reaps process of job which failed to start once it was spawned
+fn abandon(pidfd: Arc<PidFd>) {
+    let process = Process {
+        pidfd,
+        is_child: true,
+    };
+    tokio::spawn(async move {
+        if let Err(err) = process.kill().await {
+            error!("Failed to kill job which failed to start: {}", err);
+        }
+    });
+}
+
+// Stores output of the process until it exits, then publishes its exit status
+fn supervise(
+    id: Uuid,
+    capture: Capture,
+    process: Process,
+    cancel: Arc<Notify>,
+    status: Arc<RwLock<JobStatus>>,
+    tx: watch::Sender<bool>,
+) {
+    let Capture {
+        mut out,
+        mut err,
+        mut sink,
+        is_tty,
+        job_dir,
+    } = capture;
+
+    tokio::spawn(async move {
+        let mut out_buf = [0u8; 4096];
+        let mut err_buf = [0u8; 4096];
+        let (mut out_done, mut err_done) = (false, false);
+        let mut exit = None;
+        let mut drain_deadline = None;
+
+        // Pipes are drained after child exits, as output may still be buffered there. Processes
+        // the job left behind may keep them open, so draining gives up after a while
+        while exit.is_none() || !(out_done && err_done) {
+            tokio::select! {
+                outcome = out.read(&mut out_buf), if !out_done => {
+                    out_done = store_output(Fd::Out, outcome, &out_buf, &mut sink, is_tty).await;
+                }
+                outcome = err.read(&mut err_buf), if !err_done => {
+                    err_done = store_output(Fd::Err, outcome, &err_buf, &mut sink, is_tty).await;
+                }
+                _ = cancel.notified() => {
+                    if exit.is_none() {
+                        process.kill().await?;
+                        exit = Some(JobStatus::Signal(9));
+                    }
+                    break;
+                }
+                outcome = process.wait(), if exit.is_none() => {
+                    exit = Some(outcome);
+                }
+                _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(Instant::now)),
+                    if drain_deadline.is_some() => {
+                    error!("Job({}) left processes holding its output open", id);
+                    break;
+                }
+            }
+
+            // Kill policy of log limit is enforced once cap is hit
+            if sink.is_exceeded() && exit.is_none() {
+                process.kill().await?;
+                exit = Some(JobStatus::Signal(9));
+                break;
+            }
+
+            // Status is published as soon as it is known, rather than once output is drained
+            if let (Some(exit), None) = (&exit, drain_deadline) {
+                publish(&status, *exit);
+                drain_deadline = Some(Instant::now() + DRAIN_TIMEOUT);
+            }
+        }
+
+        sink.flush().await?;
+        for fd in [Fd::Out, Fd::Err] {
+            if let Err(err) = std::fs::remove_file(fifo_path(&job_dir, fd)) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    error!("Failed to remove fifo of job({}): {}", id, err);
+                }
+            }
+        }
+        // Unwrap: loop exits only once exit status is known
+        publish(&status, exit.unwrap());
+
+        if let Err(err) = tx.send(true) {
+            error!(
+                "Failed to notify about job completion: {}, for job({})",
+                err, id
+            );
+        }
+
+        Ok::<(), std::io::Error>(())
+    });
+}
+
+fn publish(status: &RwLock<JobStatus>, exit: JobStatus) {
+    // Can error only if RwLock is poisoned, which cannot happen b/c
+    // status is written only by the job task
+    *status.write().unwrap() = exit;
+}
+
+// Returns whether reading from fd is done
+async fn store_output(
+    fd: Fd,
+    outcome: std::io::Result<usize>,
+    buf: &[u8],
+    sink: &mut Sink,
+    is_tty: bool,
+) -> bool {
+    match outcome {
+        Ok(0) => true,
+        Ok(bytes_read) => {
+            if let Err(err) = sink.write(fd, &buf[..bytes_read]).await {
+                error!("Error writing child {:?} to log: {}", fd, err);
+            }
+            false
+        }
+        // pty master reports closed slave with EIO
+        Err(err) if is_tty && err.raw_os_error() == Some(EIO) => true,
+        Err(err) => {
+            error!("Error reading from child {:?}: {}", fd, err);
+            true
+        }
+    }
+}
+
+fn set_cloexec(file: &std::fs::File) -> Result<(), nix::Error> {
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    Ok(())
+}
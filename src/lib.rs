@@ -5,10 +5,29 @@ pub mod runner {
 }
 
 use thiserror::Error;
+pub mod admin;
+pub mod auth;
 pub mod cgroup;
+pub mod clone3;
+pub mod config;
 pub mod controller;
+pub mod crl;
+pub mod exec;
 pub mod job;
+pub mod logfile;
+pub mod metadata;
+pub mod namespace;
+pub mod pidfd;
+pub mod rlimit;
+pub mod rootfs;
+pub mod seccomp;
+pub mod service;
 pub mod stack_string;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+pub mod uid;
+pub mod users;
 
 #[derive(Error, Debug)]
 pub enum PlsError {
@@ -16,6 +35,10 @@ pub enum PlsError {
     IOError(#[from] std::io::Error),
     #[error(transparent)]
     CTError(#[from] controller::Error),
+    #[error(transparent)]
+    ConfigError(#[from] config::Error),
+    #[error(transparent)]
+    CgroupError(#[from] cgroup::Error),
 }
 
 #[derive(Debug)]
@@ -23,13 +46,10 @@ pub struct Empty;
 
 const BASE_PATH: &str = "/tmp/pls/clients";
 const BASE_CG_PATH: &str = "/sys/fs/cgroup";
-
-mod service {
-    use std::collections::HashMap;
-
-    use crate::controller::Controller;
-
-    pub struct Service<'c, J> {
-        controllers: HashMap<&'c str, &'c Controller<'c, J>>,
-    }
-}
+// Root owned, ids recorded there decide whom jobs run as
+const USERS_DB: &str = "/var/lib/pls/users";
+// Ids of users provisioned for clients, unless configured otherwise
+const USER_RANGE: std::ops::Range<u32> = 2_000_000..2_065_536;
+// Defaults of buffer sizes, unless configured otherwise
+const READ_SIZE: usize = 512;
+const CHANNEL_CAPACITY: usize = 20;
@@ -0,0 +1,1660 @@
+use crate::runner::{
+    job_request::{log_limit::Policy as LimitPolicy, LogFormat, LogLimit},
+    output_request::Fds,
+    LogMessage,
+};
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+use regex::bytes::Regex;
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+};
+
+pub const OUT_FILE: &str = "out";
+pub const ERR_FILE: &str = "err";
+pub const COMBINED_FILE: &str = "log";
+/// Extension of compressed log segments
+pub const COMPRESSED_EXT: &str = "gz";
+
+/// Combined log frame is prefixed with header:
+/// fd(1) | seq(8) | timestamp(8) | len(4), integers are little endian
+pub const FRAME_HEADER_LEN: usize = 21;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fd {
+    Out,
+    Err,
+}
+
+impl From<Fd> for i32 {
+    fn from(fd: Fd) -> Self {
+        match fd {
+            Fd::Out => 0,
+            Fd::Err => 1,
+        }
+    }
+}
+
+impl From<Fd> for u8 {
+    fn from(fd: Fd) -> Self {
+        match fd {
+            Fd::Out => 0,
+            Fd::Err => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for Fd {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Fd::Out),
+            1 => Ok(Fd::Err),
+            _ => Err(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// Raw stdout and stderr in `out` and `err` files
+    #[default]
+    Separate,
+    /// Framed chunks of both stdout and stderr in single `log` file
+    Combined,
+}
+
+impl From<LogFormat> for Format {
+    fn from(value: LogFormat) -> Self {
+        match value {
+            LogFormat::Separate => Format::Separate,
+            LogFormat::Combined => Format::Combined,
+        }
+    }
+}
+
+/// Path to the log file holding output of `fd`
+pub fn path(job_dir: &Path, format: Format, fd: Fd) -> PathBuf {
+    match (format, fd) {
+        (Format::Combined, _) => job_dir.join(COMBINED_FILE),
+        (Format::Separate, Fd::Out) => job_dir.join(OUT_FILE),
+        (Format::Separate, Fd::Err) => job_dir.join(ERR_FILE),
+    }
+}
+
+/// Position in log file output streaming starts from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Start {
+    /// Byte offset, as reported by `LogMessage`
+    Offset(u64),
+    /// Beginning of the last N lines, rounded to frame start for combined log
+    Tail(u32),
+}
+
+impl Default for Start {
+    fn default() -> Self {
+        Start::Offset(0)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Log limit max_bytes must be greater than zero")]
+    ZeroMaxBytes,
+    #[error("Rotated log limit must keep at least one segment")]
+    NoSegments,
+}
+
+/// Policy applied once log file reaches `Limit::max_bytes`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Full log is renamed to `<name>.<offset>`, keeping `Limit::keep_segments` of those
+    Rotate,
+    /// Ring buffer keeping between half and full `Limit::max_bytes` of the most recent output
+    Truncate,
+    /// Job is killed once `Limit::max_bytes` is written
+    Kill,
+}
+
+impl From<LimitPolicy> for Policy {
+    fn from(value: LimitPolicy) -> Self {
+        match value {
+            LimitPolicy::Rotate => Policy::Rotate,
+            LimitPolicy::Truncate => Policy::Truncate,
+            LimitPolicy::Kill => Policy::Kill,
+        }
+    }
+}
+
+/// Size limit of every log file of the job
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub max_bytes: u64,
+    pub keep_segments: u32,
+    pub policy: Policy,
+}
+
+impl TryFrom<&LogLimit> for Limit {
+    type Error = Error;
+
+    fn try_from(value: &LogLimit) -> Result<Self, Self::Error> {
+        let limit = Self {
+            max_bytes: value.max_bytes,
+            keep_segments: value.keep_segments,
+            policy: value.policy().into(),
+        };
+        limit.validate()?;
+
+        Ok(limit)
+    }
+}
+
+impl Limit {
+    /// Rejects limits which would discard output as soon as it is written
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.policy {
+            _ if self.max_bytes == 0 => Err(Error::ZeroMaxBytes),
+            Policy::Rotate if self.keep_segments == 0 => Err(Error::NoSegments),
+            _ => Ok(()),
+        }
+    }
+
+    /// Restricts limit requested by a job to the ceiling configured for its client. Job
+    /// can't pick a policy keeping more output than the ceiling one, Kill being the strictest
+    /// and Rotate the loosest, so that the client budget of bytes holds
+    pub fn within(self, ceiling: &Limit) -> Result<Limit, Error> {
+        let policy = match (self.policy, ceiling.policy) {
+            (Policy::Kill, _) | (_, Policy::Kill) => Policy::Kill,
+            (Policy::Truncate, _) | (_, Policy::Truncate) => Policy::Truncate,
+            (Policy::Rotate, Policy::Rotate) => Policy::Rotate,
+        };
+        let limit = Limit {
+            max_bytes: self.max_bytes.min(ceiling.max_bytes),
+            keep_segments: self.keep_segments.min(ceiling.keep_segments),
+            policy,
+        };
+        limit.validate()?;
+
+        Ok(limit)
+    }
+
+    // Size of a single segment along with number of rotated segments to keep
+    fn segments(&self) -> Option<(u64, usize)> {
+        match self.policy {
+            Policy::Rotate => Some((self.max_bytes.max(1), self.keep_segments as usize)),
+            Policy::Truncate => Some(((self.max_bytes / 2).max(1), 1)),
+            Policy::Kill => None,
+        }
+    }
+}
+
+// Path of rotated segment starting at `base` offset of the log
+fn segment_path(path: &Path, base: u64) -> PathBuf {
+    let mut segment = path.as_os_str().to_owned();
+    segment.push(format!(".{}", base));
+    PathBuf::from(segment)
+}
+
+// Path of compressed segment starting at `base` offset of the log
+fn compressed_path(path: &Path, base: u64) -> PathBuf {
+    let mut segment = segment_path(path, base).into_os_string();
+    segment.push(format!(".{}", COMPRESSED_EXT));
+    PathBuf::from(segment)
+}
+
+/// Log file stored on disk
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Stored {
+    base: u64,
+    path: PathBuf,
+    compressed: bool,
+}
+
+// Rotated and compressed segments of the log ordered by their base offset
+async fn segments(path: &Path) -> std::io::Result<Vec<Stored>> {
+    let (dir, name) = match (
+        path.parent(),
+        path.file_name().and_then(|name| name.to_str()),
+    ) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Ok(Vec::new()),
+    };
+    let prefix = format!("{}.", name);
+    let suffix = format!(".{}", COMPRESSED_EXT);
+    let mut segments = Vec::new();
+
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        let base = match file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix(&prefix))
+        {
+            Some(base) => base,
+            None => continue,
+        };
+        let (base, compressed) = match base.strip_suffix(&suffix) {
+            Some(base) => (base, true),
+            None => (base, false),
+        };
+
+        if let Ok(base) = base.parse::<u64>() {
+            segments.push(Stored {
+                base,
+                path: entry.path(),
+                compressed,
+            });
+        }
+    }
+    segments.sort();
+
+    Ok(segments)
+}
+
+// Segments along with active file if it is still there
+async fn stored(path: &Path) -> std::io::Result<Vec<Stored>> {
+    let mut files = segments(path).await?;
+    match tokio::fs::metadata(path).await {
+        Ok(_) => {
+            // Active file is compressed first, so rotated segments are plain as long as it exists
+            let base = match files.last() {
+                Some(last) => last.base + tokio::fs::metadata(&last.path).await?.len(),
+                None => 0,
+            };
+            files.push(Stored {
+                base,
+                path: path.to_owned(),
+                compressed: false,
+            });
+        }
+        // Active file is compressed once job completes, which may have happened after
+        // segments were listed, in which case listing misses it
+        Err(err) if err.kind() == ErrorKind::NotFound => files = segments(path).await?,
+        Err(err) => return Err(err),
+    }
+
+    Ok(files)
+}
+
+type Source = Box<dyn AsyncRead + Unpin + Send + Sync>;
+
+// Opens stored file positioned at `pos` along with its inode
+async fn open_stored(stored: &Stored, pos: u64) -> std::io::Result<(Source, u64)> {
+    let file = match File::open(&stored.path).await {
+        Ok(file) => file,
+        // Segment got compressed after it was listed
+        Err(err) if err.kind() == ErrorKind::NotFound && !stored.compressed => {
+            // Strips base offset of rotated segment, active file has no extension
+            let log = stored.path.with_extension("");
+            let compressed = Stored {
+                path: compressed_path(&log, stored.base),
+                compressed: true,
+                ..stored.to_owned()
+            };
+            return Box::pin(open_stored(&compressed, pos)).await;
+        }
+        Err(err) => return Err(err),
+    };
+    let ino = file.metadata().await?.ino();
+
+    if !stored.compressed {
+        let mut file = file;
+        file.seek(SeekFrom::Start(pos)).await?;
+        return Ok((Box::new(file), ino));
+    }
+
+    // Compressed stream can't seek, so preceding output is skipped instead
+    let mut source = GzipDecoder::new(BufReader::new(file));
+    tokio::io::copy(&mut (&mut source).take(pos), &mut tokio::io::sink()).await?;
+
+    Ok((Box::new(source), ino))
+}
+
+/// Sizes of log files before and after compression
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Savings {
+    pub original: u64,
+    pub compressed: u64,
+}
+
+/// Compresses logs of completed job, every file including the active one is stored as
+/// `<name>.<offset>.gz` segment, readers having them open keep reading the original
+pub async fn compress(job_dir: &Path, format: Format) -> std::io::Result<Savings> {
+    let fds: &[Fd] = match format {
+        Format::Separate => &[Fd::Out, Fd::Err],
+        Format::Combined => &[Fd::Out],
+    };
+    let mut savings = Savings::default();
+
+    for fd in fds {
+        let path = path(job_dir, format, *fd);
+        // Newest first, see `stored`
+        for stored in stored(&path).await?.iter().rev() {
+            if stored.compressed {
+                continue;
+            }
+
+            let target = compressed_path(&path, stored.base);
+            let mut partial = target.clone().into_os_string();
+            partial.push(".partial");
+
+            let mut source = File::open(&stored.path).await?;
+            let mut encoder = GzipEncoder::new(File::create(&partial).await?);
+            savings.original += tokio::io::copy(&mut source, &mut encoder).await?;
+            encoder.shutdown().await?;
+
+            tokio::fs::rename(&partial, &target).await?;
+            tokio::fs::remove_file(&stored.path).await?;
+            savings.compressed += tokio::fs::metadata(&target).await?.len();
+        }
+    }
+
+    Ok(savings)
+}
+
+/// Savings of logs compressed by `compress`, as found on disk. Original size is read from
+/// gzip trailer, which keeps it modulo 4 GiB
+pub async fn savings(job_dir: &Path, format: Format) -> std::io::Result<Savings> {
+    let fds: &[Fd] = match format {
+        Format::Separate => &[Fd::Out, Fd::Err],
+        Format::Combined => &[Fd::Out],
+    };
+    let mut savings = Savings::default();
+
+    for fd in fds {
+        for stored in segments(&path(job_dir, format, *fd)).await? {
+            if !stored.compressed {
+                continue;
+            }
+
+            let mut file = File::open(&stored.path).await?;
+            savings.compressed += file.metadata().await?.len();
+            file.seek(SeekFrom::End(-4)).await?;
+            savings.original += file.read_u32_le().await? as u64;
+        }
+    }
+
+    Ok(savings)
+}
+
+/// Log file currently written to, along with its position in the whole log
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    file: File,
+    base: u64,
+    len: u64,
+    rotated: VecDeque<u64>,
+}
+
+impl Segment {
+    fn create(path: PathBuf) -> std::io::Result<Self> {
+        let file = File::from_std(std::fs::File::create(&path)?);
+        Ok(Self {
+            path,
+            file,
+            base: 0,
+            len: 0,
+            rotated: VecDeque::new(),
+        })
+    }
+
+    // Continues log written before server restart
+    async fn resume(path: PathBuf) -> std::io::Result<Self> {
+        let rotated: VecDeque<_> = segments(&path)
+            .await?
+            .into_iter()
+            .filter(|segment| !segment.compressed)
+            .collect();
+        let base = match rotated.back() {
+            Some(last) => last.base + tokio::fs::metadata(&last.path).await?.len(),
+            None => 0,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let len = file.metadata().await?.len();
+
+        Ok(Self {
+            path,
+            file,
+            base,
+            len,
+            rotated: rotated.iter().map(|segment| segment.base).collect(),
+        })
+    }
+
+    // Returns false once the hard cap is hit and data did not fit.
+    // Data which can't be split is neither cut at the cap nor spread across segments
+    async fn write(
+        &mut self,
+        mut data: &[u8],
+        limit: Option<&Limit>,
+        splittable: bool,
+    ) -> std::io::Result<bool> {
+        let len = data.len() as u64;
+        match limit.map(|limit| (limit, limit.segments())) {
+            Some((limit, None)) if self.len + len > limit.max_bytes => {
+                let room = limit.max_bytes.saturating_sub(self.len) as usize;
+                if splittable {
+                    self.append(&data[..room]).await?;
+                }
+
+                return Ok(false);
+            }
+            Some((_, Some((segment_len, keep)))) => {
+                while self.len + data.len() as u64 > segment_len {
+                    let room = segment_len.saturating_sub(self.len) as usize;
+                    if splittable && room > 0 {
+                        self.append(&data[..room]).await?;
+                        data = &data[room..];
+                    } else if self.len == 0 {
+                        // Frame larger than the whole segment gets one of its own
+                        break;
+                    }
+                    self.rotate(keep).await?;
+                }
+            }
+            _ => (),
+        }
+
+        self.append(data).await?;
+        Ok(true)
+    }
+
+    async fn append(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data).await?;
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    async fn rotate(&mut self, keep: usize) -> std::io::Result<()> {
+        self.file.flush().await?;
+        tokio::fs::rename(&self.path, segment_path(&self.path, self.base)).await?;
+        self.file = File::create(&self.path).await?;
+        self.rotated.push_back(self.base);
+        self.base += self.len;
+        self.len = 0;
+
+        while self.rotated.len() > keep {
+            if let Some(oldest) = self.rotated.pop_front() {
+                tokio::fs::remove_file(segment_path(&self.path, oldest)).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writing end of job output
+#[derive(Debug)]
+pub struct Sink {
+    limit: Option<Limit>,
+    exceeded: bool,
+    seq: u64,
+    out: Segment,
+    // Combined log has single segment holding both fds
+    err: Option<Segment>,
+}
+
+impl Sink {
+    /// Files are created before returning, so output followers always find them
+    pub fn create(job_dir: &Path, format: Format, limit: Option<Limit>) -> std::io::Result<Self> {
+        let out = Segment::create(path(job_dir, format, Fd::Out))?;
+        let err = match format {
+            Format::Separate => Some(Segment::create(path(job_dir, format, Fd::Err))?),
+            Format::Combined => None,
+        };
+
+        Ok(Self {
+            limit,
+            exceeded: false,
+            seq: 0,
+            out,
+            err,
+        })
+    }
+
+    /// Appends to logs of a job adopted after server restart
+    pub async fn resume(
+        job_dir: &Path,
+        format: Format,
+        limit: Option<Limit>,
+    ) -> std::io::Result<Self> {
+        let mut out = Segment::resume(path(job_dir, format, Fd::Out)).await?;
+        let (err, seq) = match format {
+            Format::Separate => {
+                let err = Segment::resume(path(job_dir, format, Fd::Err)).await?;
+                (Some(err), 0)
+            }
+            Format::Combined => (None, Self::resume_frames(&mut out).await?),
+        };
+
+        Ok(Self {
+            limit,
+            exceeded: false,
+            seq,
+            out,
+            err,
+        })
+    }
+
+    // Cuts frame left partially written by previous server process,
+    // returning sequence number following the last complete frame
+    async fn resume_frames(segment: &mut Segment) -> std::io::Result<u64> {
+        let (end, next_seq) = scan_frames(&segment.path).await?;
+        if end < segment.len {
+            segment.file.set_len(end).await?;
+            segment.len = end;
+        }
+
+        match (next_seq, segment.rotated.back()) {
+            (Some(next_seq), _) => Ok(next_seq),
+            // Active segment was just rotated, numbering continues from the previous one
+            (None, Some(base)) => {
+                let (_, next_seq) = scan_frames(&segment_path(&segment.path, *base)).await?;
+                Ok(next_seq.unwrap_or_default())
+            }
+            (None, None) => Ok(0),
+        }
+    }
+
+    /// Whether hard cap on log size was hit
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    pub async fn write(&mut self, fd: Fd, data: &[u8]) -> std::io::Result<()> {
+        let limit = self.limit.as_ref();
+        let written = match (&mut self.err, fd) {
+            (Some(err), Fd::Err) => err.write(data, limit, true).await?,
+            (Some(_), Fd::Out) => self.out.write(data, limit, true).await?,
+            (None, _) => {
+                let frame = encode_frame(fd, self.seq, timestamp(), data);
+                self.seq += 1;
+                // Frames never span segments or get cut
+                self.out.write(&frame, limit, false).await?
+            }
+        };
+        self.exceeded |= !written;
+
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        self.out.file.flush().await?;
+        if let Some(err) = &mut self.err {
+            err.file.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+// End of the last complete frame of combined log along with sequence number following it
+async fn scan_frames(path: &Path) -> std::io::Result<(u64, Option<u64>)> {
+    let mut file = File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    let (mut end, mut next_seq) = (0, None);
+
+    while end + (FRAME_HEADER_LEN as u64) <= len {
+        file.read_exact(&mut header).await?;
+        // Unwrap: buffer is exactly header sized
+        let header = decode_header(&header).unwrap();
+        let frame_end = end + (FRAME_HEADER_LEN + header.len) as u64;
+        if frame_end > len {
+            break;
+        }
+        file.seek(SeekFrom::Start(frame_end)).await?;
+        end = frame_end;
+        next_seq = Some(header.seq + 1);
+    }
+
+    Ok((end, next_seq))
+}
+
+// Microseconds since unix epoch
+pub(crate) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+fn encode_frame(fd: Fd, seq: u64, timestamp: u64, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + data.len());
+    frame.push(fd.into());
+    frame.extend_from_slice(&seq.to_le_bytes());
+    frame.extend_from_slice(&timestamp.to_le_bytes());
+    frame.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+struct Header {
+    fd: Fd,
+    seq: u64,
+    timestamp: u64,
+    len: usize,
+}
+
+fn decode_header(buf: &[u8]) -> Option<Header> {
+    let buf = buf.get(..FRAME_HEADER_LEN)?;
+    // Unwraps: slice lengths are fixed by the header layout
+    let fd = Fd::try_from(buf[0]).unwrap_or(Fd::Out);
+    let seq = u64::from_le_bytes(buf[1..9].try_into().unwrap());
+    let timestamp = u64::from_le_bytes(buf[9..17].try_into().unwrap());
+    let len = u32::from_le_bytes(buf[17..21].try_into().unwrap()) as usize;
+
+    Some(Header {
+        fd,
+        seq,
+        timestamp,
+        len,
+    })
+}
+
+/// Output streams selected for streaming
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Selection {
+    #[default]
+    Both,
+    Out,
+    Err,
+}
+
+impl From<Fds> for Selection {
+    fn from(value: Fds) -> Self {
+        match value {
+            Fds::Both => Selection::Both,
+            Fds::Out => Selection::Out,
+            Fds::Err => Selection::Err,
+        }
+    }
+}
+
+impl Selection {
+    pub fn contains(&self, fd: Fd) -> bool {
+        matches!(
+            (self, fd),
+            (Selection::Both, _) | (Selection::Out, Fd::Out) | (Selection::Err, Fd::Err)
+        )
+    }
+}
+
+/// Lines longer than that are matched in pieces
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Drops messages of unselected fds and, given a pattern, lines not matching it.
+/// Matching lines are sent one per message, lines split across chunks are merged
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    selection: Selection,
+    pattern: Option<Regex>,
+    // Incomplete last line of each fd
+    partial: [Option<LogMessage>; 2],
+}
+
+impl Filter {
+    pub fn new(selection: Selection, pattern: Option<Regex>) -> Self {
+        Self {
+            selection,
+            pattern,
+            partial: Default::default(),
+        }
+    }
+
+    pub fn apply(&mut self, format: Format, messages: Vec<LogMessage>) -> Vec<LogMessage> {
+        let mut passed = Vec::new();
+        for msg in messages {
+            // Unwrap: fd of decoded message is always valid
+            let fd = Fd::try_from(msg.fd as u8).unwrap();
+            if !self.selection.contains(fd) {
+                continue;
+            }
+            let pattern = match &self.pattern {
+                Some(pattern) => pattern,
+                None => {
+                    passed.push(msg);
+                    continue;
+                }
+            };
+
+            let slot = &mut self.partial[msg.fd as usize];
+            let mut start = 0;
+            while start < msg.output.len() {
+                let end = match msg.output[start..].iter().position(|byte| *byte == b'\n') {
+                    Some(ix) => start + ix + 1,
+                    None => msg.output.len(),
+                };
+                let chunk = &msg.output[start..end];
+                let line = match slot.take() {
+                    Some(mut line) => {
+                        line.output.extend_from_slice(chunk);
+                        line
+                    }
+                    None => LogMessage {
+                        output: chunk.to_vec(),
+                        // Combined log can be resumed only from frame start
+                        offset: match format {
+                            Format::Separate => msg.offset + start as u64,
+                            Format::Combined => msg.offset,
+                        },
+                        ..msg.clone()
+                    },
+                };
+                start = end;
+
+                if line.output.ends_with(b"\n") || line.output.len() >= MAX_LINE_LEN {
+                    if is_match(pattern, &line) {
+                        passed.push(line);
+                    }
+                } else {
+                    *slot = Some(line);
+                }
+            }
+        }
+
+        passed
+    }
+
+    /// Matches unterminated last lines once output is complete
+    pub fn finish(&mut self) -> Vec<LogMessage> {
+        let pattern = match &self.pattern {
+            Some(pattern) => pattern,
+            None => return Vec::new(),
+        };
+
+        self.partial
+            .iter_mut()
+            .filter_map(Option::take)
+            .filter(|line| is_match(pattern, line))
+            .collect()
+    }
+}
+
+fn is_match(pattern: &Regex, line: &LogMessage) -> bool {
+    let text = line.output.strip_suffix(b"\n").unwrap_or(&line.output);
+    pattern.is_match(text)
+}
+
+/// Turns chunks read from log file into messages,
+/// buffering incomplete frames of combined log until the rest is read
+#[derive(Debug)]
+pub struct Decoder {
+    format: Format,
+    fd: Fd,
+    // Offset of the first buffered byte
+    offset: u64,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(format: Format, fd: Fd) -> Self {
+        Self {
+            format,
+            fd,
+            offset: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn decode(&mut self, offset: u64, chunk: &[u8]) -> Vec<LogMessage> {
+        match self.format {
+            Format::Separate => {
+                let msg = LogMessage {
+                    fd: self.fd.into(),
+                    output: chunk.to_vec(),
+                    offset,
+                    ..Default::default()
+                };
+                vec![msg]
+            }
+            Format::Combined => {
+                // Reader skipped over dropped segments, which always start with a frame
+                if offset != self.offset + self.buf.len() as u64 {
+                    self.buf.clear();
+                    self.offset = offset;
+                }
+                self.buf.extend_from_slice(chunk);
+                let mut messages = Vec::new();
+                let mut consumed = 0;
+
+                while let Some(header) = decode_header(&self.buf[consumed..]) {
+                    let end = consumed + FRAME_HEADER_LEN + header.len;
+                    if end > self.buf.len() {
+                        break;
+                    }
+
+                    messages.push(LogMessage {
+                        fd: header.fd.into(),
+                        output: self.buf[consumed + FRAME_HEADER_LEN..end].to_vec(),
+                        offset: self.offset,
+                        seq: header.seq,
+                        timestamp: header.timestamp,
+                    });
+                    self.offset += (end - consumed) as u64;
+                    consumed = end;
+                }
+
+                self.buf.drain(..consumed);
+                messages
+            }
+        }
+    }
+}
+
+/// Reads log of a single fd across rotated and compressed segments,
+/// keeping track of offset in the whole log
+pub struct Reader {
+    path: PathBuf,
+    source: Source,
+    ino: u64,
+    base: u64,
+    pos: u64,
+    active: bool,
+}
+
+impl Reader {
+    /// Opens segment holding requested start, output preceding the oldest
+    /// segment is gone, so reading starts from there in such case.
+    /// Offset into combined log which is not at a frame boundary is `InvalidInput`
+    pub async fn open(
+        job_dir: &Path,
+        format: Format,
+        fd: Fd,
+        start: Start,
+    ) -> std::io::Result<Self> {
+        let path = path(job_dir, format, fd);
+        let files = stored(&path).await?;
+        if files.is_empty() {
+            return Err(ErrorKind::NotFound.into());
+        }
+
+        let offset = match start {
+            Start::Offset(offset) => offset,
+            Start::Tail(lines) => tail(&files, format, lines).await?,
+        };
+
+        let file = files
+            .iter()
+            .rev()
+            .find(|file| file.base <= offset)
+            .unwrap_or(&files[0]);
+        let pos = offset.saturating_sub(file.base);
+        if format == Format::Combined
+            && matches!(start, Start::Offset(_))
+            && !is_frame_start(file, pos).await?
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Offset {} is not at a frame boundary", offset),
+            ));
+        }
+        let (source, ino) = open_stored(file, pos).await?;
+
+        Ok(Self {
+            active: file.path == path,
+            path,
+            source,
+            ino,
+            base: file.base,
+            pos,
+        })
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.base + self.pos
+    }
+
+    /// Reads next chunk of output along with its offset,
+    /// zero bytes read means all output written so far is read
+    pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<(u64, usize)> {
+        loop {
+            let bytes_read = self.source.read(buf).await?;
+            if bytes_read > 0 {
+                let offset = self.offset();
+                self.pos += bytes_read as u64;
+                return Ok((offset, bytes_read));
+            }
+
+            if self.active {
+                if !self.is_rotated().await? {
+                    return Ok((self.offset(), 0));
+                }
+                // Renamed file could have been written to after last read, drain it first
+                self.active = false;
+            } else if !self.next_segment().await? {
+                return Ok((self.offset(), 0));
+            }
+        }
+    }
+
+    async fn is_rotated(&self) -> std::io::Result<bool> {
+        match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) => Ok(metadata.ino() != self.ino),
+            // Active file is renamed, but the new one is not created yet,
+            // or it is compressed
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Moves past exhausted segment, returns false if next one is not there yet
+    async fn next_segment(&mut self) -> std::io::Result<bool> {
+        let end = self.offset();
+        let base = self.base;
+        let after = |files: Vec<Stored>| {
+            files
+                .into_iter()
+                .find(|file| file.base >= end && file.base > base)
+        };
+
+        // Active file is compressed into a segment once job completes, so listing is
+        // repeated if it disappears in between
+        let next = match after(segments(&self.path).await?) {
+            Some(next) => next,
+            None => match after(stored(&self.path).await?) {
+                Some(next) => next,
+                None => return Ok(false),
+            },
+        };
+
+        match open_stored(&next, 0).await {
+            Ok((source, ino)) => {
+                self.active = next.path == self.path;
+                self.source = source;
+                self.ino = ino;
+                self.base = next.base;
+                self.pos = 0;
+                Ok(true)
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Whether `pos` of combined log file is where a frame starts, or where the last complete
+// one ends, as decoding from elsewhere would take frame data for a header
+async fn is_frame_start(stored: &Stored, pos: u64) -> std::io::Result<bool> {
+    let (mut source, _) = open_stored(stored, 0).await?;
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    let mut frame = 0;
+
+    while frame < pos {
+        match source.read_exact(&mut header).await {
+            Ok(_) => (),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        // Unwrap: buffer is exactly header sized
+        let len = decode_header(&header).unwrap().len as u64;
+        let skipped = tokio::io::copy(&mut (&mut source).take(len), &mut tokio::io::sink()).await?;
+        if skipped < len {
+            return Ok(false);
+        }
+        frame += FRAME_HEADER_LEN as u64 + len;
+    }
+
+    Ok(frame == pos)
+}
+
+// Offset of the last `lines` lines, walking from the newest file back
+async fn tail(files: &[Stored], format: Format, lines: u32) -> std::io::Result<u64> {
+    let mut remaining = lines;
+    for (ix, stored) in files.iter().rev().enumerate() {
+        let is_last = ix == 0;
+        let (offset, seen) = match (stored.compressed, File::open(&stored.path).await) {
+            (_, Err(err)) if err.kind() == ErrorKind::NotFound => break,
+            (_, Err(err)) => return Err(err),
+            (false, Ok(mut file)) if lines == 0 => (file.seek(SeekFrom::End(0)).await?, 0),
+            (false, Ok(mut file)) => match format {
+                Format::Separate => tail_offset(&mut file, remaining, is_last).await?,
+                Format::Combined => framed_tail_offset(&mut file, remaining, is_last).await?,
+            },
+            (true, Ok(file)) => {
+                let mut source = GzipDecoder::new(BufReader::new(file));
+                forward_tail_offset(&mut source, format, remaining, is_last).await?
+            }
+        };
+        if seen == remaining {
+            return Ok(stored.base + offset);
+        }
+        remaining -= seen;
+    }
+
+    Ok(files.first().map(|file| file.base).unwrap_or(0))
+}
+
+// Scans file backwards until beginning of the last `lines` lines,
+// returns how many were found along with their start
+async fn tail_offset(file: &mut File, lines: u32, is_last: bool) -> std::io::Result<(u64, u32)> {
+    let len = file.seek(SeekFrom::End(0)).await?;
+    let mut pos = len;
+    let mut buf = [0u8; 512];
+    let mut seen = 0;
+
+    while pos > 0 {
+        let chunk = pos.min(buf.len() as u64);
+        pos -= chunk;
+        file.seek(SeekFrom::Start(pos)).await?;
+        file.read_exact(&mut buf[..chunk as usize]).await?;
+
+        for (ix, byte) in buf[..chunk as usize].iter().enumerate().rev() {
+            let next = pos + ix as u64 + 1;
+            // Trailing newline terminates the last line rather than starting a new one
+            if *byte == b'\n' && !(is_last && next == len) {
+                seen += 1;
+                if seen == lines {
+                    return Ok((next, seen));
+                }
+            }
+        }
+    }
+
+    Ok((0, seen))
+}
+
+// Frames are variable length, so offsets are collected walking forward,
+// then frames are scanned backwards for newlines
+async fn framed_tail_offset(
+    file: &mut File,
+    lines: u32,
+    is_last: bool,
+) -> std::io::Result<(u64, u32)> {
+    let len = file.seek(SeekFrom::End(0)).await?;
+    let mut frames = Vec::new();
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    let mut pos = 0;
+
+    file.seek(SeekFrom::Start(0)).await?;
+    while pos + (FRAME_HEADER_LEN as u64) <= len {
+        file.read_exact(&mut header).await?;
+        // Unwrap: buffer is exactly header sized
+        let frame_len = decode_header(&header).unwrap().len as u64;
+        if pos + FRAME_HEADER_LEN as u64 + frame_len > len {
+            // Frame is still being written
+            break;
+        }
+        frames.push((pos, frame_len));
+        pos = file.seek(SeekFrom::Current(frame_len as i64)).await?;
+    }
+
+    let end = pos;
+    let mut seen = 0;
+    let mut next_frame = end;
+    let mut data = Vec::new();
+    for (frame, frame_len) in frames.into_iter().rev() {
+        data.resize(frame_len as usize, 0);
+        file.seek(SeekFrom::Start(frame + FRAME_HEADER_LEN as u64))
+            .await?;
+        file.read_exact(&mut data).await?;
+
+        for (ix, byte) in data.iter().enumerate().rev() {
+            let is_frame_end = ix + 1 == data.len();
+            // Trailing newline terminates the last line rather than starting a new one
+            if *byte == b'\n' && !(is_last && is_frame_end && next_frame == end) {
+                seen += 1;
+                if seen == lines {
+                    return Ok((if is_frame_end { next_frame } else { frame }, seen));
+                }
+            }
+        }
+        next_frame = frame;
+    }
+
+    Ok((0, seen))
+}
+
+// Compressed logs can't be scanned backwards, so line starts are collected walking forward
+async fn forward_tail_offset<R: AsyncRead + Unpin>(
+    source: &mut R,
+    format: Format,
+    lines: u32,
+    is_last: bool,
+) -> std::io::Result<(u64, u32)> {
+    // One extra start is kept in case the last one is dropped as trailing
+    let capacity = lines as usize + 1;
+    let mut starts = VecDeque::with_capacity(capacity);
+    let mut push = |start: u64| {
+        if starts.len() == capacity {
+            starts.pop_front();
+        }
+        starts.push_back(start);
+    };
+    let mut end = 0;
+
+    match format {
+        Format::Separate => {
+            let mut buf = [0u8; 4096];
+            loop {
+                let bytes_read = source.read(&mut buf).await?;
+                if bytes_read == 0 {
+                    break;
+                }
+                for (ix, byte) in buf[..bytes_read].iter().enumerate() {
+                    if *byte == b'\n' {
+                        push(end + ix as u64 + 1);
+                    }
+                }
+                end += bytes_read as u64;
+            }
+        }
+        Format::Combined => {
+            let mut header = [0u8; FRAME_HEADER_LEN];
+            let mut data = Vec::new();
+            loop {
+                match source.read_exact(&mut header).await {
+                    Ok(_) => (),
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                }
+                // Unwrap: buffer is exactly header sized
+                data.resize(decode_header(&header).unwrap().len, 0);
+                match source.read_exact(&mut data).await {
+                    Ok(_) => (),
+                    // Frame was cut short
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                }
+
+                let frame = end;
+                end += (FRAME_HEADER_LEN + data.len()) as u64;
+                for (ix, byte) in data.iter().enumerate() {
+                    if *byte == b'\n' {
+                        push(if ix + 1 == data.len() { end } else { frame });
+                    }
+                }
+            }
+        }
+    }
+
+    if lines == 0 {
+        return Ok((end, 0));
+    }
+    // Trailing newline terminates the last line rather than starting a new one
+    if is_last && starts.back() == Some(&end) {
+        starts.pop_back();
+    }
+    let seen = starts.len().min(lines as usize);
+    let offset = if seen == lines as usize {
+        starts[starts.len() - seen]
+    } else {
+        0
+    };
+
+    Ok((offset, seen as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compress, encode_frame, path, Decoder, Error, Fd, Filter, Format, Limit, LimitPolicy,
+        LogLimit, Policy, Reader, Selection, Sink, Start, FRAME_HEADER_LEN,
+    };
+    use crate::{runner::LogMessage, testing::temp_dir};
+    use regex::bytes::Regex;
+    use std::path::PathBuf;
+
+    async fn offset_of(contents: &[u8], format: Format, start: Start) -> u64 {
+        let dir = temp_dir(&format!("{:?}-{:?}-{}", format, start, contents.len()));
+        std::fs::write(path(&dir, format, Fd::Out), contents).unwrap();
+        Reader::open(&dir, format, Fd::Out, start)
+            .await
+            .unwrap()
+            .offset()
+    }
+
+    async fn read_all(reader: &mut Reader) -> (u64, Vec<u8>) {
+        let mut buf = [0u8; 3];
+        let mut output = Vec::new();
+        let mut first = None;
+        loop {
+            let (offset, n) = reader.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return (first.unwrap_or(offset), output);
+            }
+            first.get_or_insert(offset);
+            output.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    async fn sink_with(
+        name: &str,
+        format: Format,
+        limit: Limit,
+        chunks: &[&[u8]],
+    ) -> (PathBuf, bool) {
+        let dir = temp_dir(name);
+        let mut sink = Sink::create(&dir, format, Some(limit)).unwrap();
+        for chunk in chunks {
+            sink.write(Fd::Out, chunk).await.unwrap();
+        }
+        sink.flush().await.unwrap();
+        (dir, sink.is_exceeded())
+    }
+
+    fn frames(chunks: &[(Fd, &[u8])]) -> Vec<u8> {
+        chunks
+            .iter()
+            .enumerate()
+            .flat_map(|(seq, (fd, data))| encode_frame(*fd, seq as u64, 42, data))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn given_tail_then_starts_at_last_lines() {
+        let contents = b"one\ntwo\nthree\n";
+        assert_eq!(
+            offset_of(contents, Format::Separate, Start::Tail(1)).await,
+            8
+        );
+        assert_eq!(
+            offset_of(contents, Format::Separate, Start::Tail(2)).await,
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn given_tail_longer_than_file_then_starts_at_beginning() {
+        let offset = offset_of(b"one\ntwo", Format::Separate, Start::Tail(5)).await;
+        assert_eq!(offset, 0);
+    }
+
+    #[tokio::test]
+    async fn given_unterminated_last_line_then_it_is_counted() {
+        let offset = offset_of(b"one\ntwo", Format::Separate, Start::Tail(1)).await;
+        assert_eq!(offset, 4);
+    }
+
+    #[tokio::test]
+    async fn given_zero_tail_then_starts_at_end() {
+        let offset = offset_of(b"one\ntwo\n", Format::Separate, Start::Tail(0)).await;
+        assert_eq!(offset, 8);
+    }
+
+    #[tokio::test]
+    async fn given_tail_spanning_multiple_chunks_then_starts_at_last_lines() {
+        let mut contents = vec![b'x'; 2000];
+        contents.extend_from_slice(b"\nlast\n");
+        let offset = offset_of(&contents, Format::Separate, Start::Tail(1)).await;
+        assert_eq!(offset, 2001);
+        let offset = offset_of(&contents, Format::Separate, Start::Tail(2)).await;
+        assert_eq!(offset, 0);
+    }
+
+    #[tokio::test]
+    async fn given_combined_tail_then_starts_at_frame_after_newline() {
+        let contents = frames(&[
+            (Fd::Out, b"one\n"),
+            (Fd::Err, b"two\n"),
+            (Fd::Out, b"three\n"),
+        ]);
+        let second = (FRAME_HEADER_LEN + 4) as u64;
+        let third = second + (FRAME_HEADER_LEN + 4) as u64;
+        let offset = offset_of(&contents, Format::Combined, Start::Tail(1)).await;
+        assert_eq!(offset, third);
+        let offset = offset_of(&contents, Format::Combined, Start::Tail(2)).await;
+        assert_eq!(offset, second);
+        let offset = offset_of(&contents, Format::Combined, Start::Tail(3)).await;
+        assert_eq!(offset, 0);
+    }
+
+    #[tokio::test]
+    async fn given_combined_tail_within_frame_then_starts_at_that_frame() {
+        let contents = frames(&[(Fd::Out, b"one\n"), (Fd::Out, b"two\nthree\n")]);
+        let offset = offset_of(&contents, Format::Combined, Start::Tail(1)).await;
+        assert_eq!(offset, (FRAME_HEADER_LEN + 4) as u64);
+    }
+
+    #[test]
+    fn given_split_frames_decoder_buffers_until_complete() {
+        let contents = frames(&[(Fd::Out, b"one"), (Fd::Err, b"two")]);
+        let mut decoder = Decoder::new(Format::Combined, Fd::Out);
+
+        let (head, tail) = contents.split_at(FRAME_HEADER_LEN + 1);
+        let messages = decoder.decode(0, head);
+        assert!(messages.is_empty());
+
+        let messages = decoder.decode(head.len() as u64, tail);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].output, b"one");
+        assert_eq!(messages[0].fd, 0);
+        assert_eq!(messages[0].seq, 0);
+        assert_eq!(messages[0].timestamp, 42);
+        assert_eq!(messages[1].output, b"two");
+        assert_eq!(messages[1].fd, 1);
+        assert_eq!(messages[1].seq, 1);
+        assert_eq!(messages[1].offset, (FRAME_HEADER_LEN + 3) as u64);
+    }
+
+    #[test]
+    fn given_gap_in_offsets_decoder_drops_partial_frame() {
+        let contents = frames(&[(Fd::Out, b"one"), (Fd::Err, b"two")]);
+        let mut decoder = Decoder::new(Format::Combined, Fd::Out);
+
+        assert!(decoder.decode(0, &contents[..5]).is_empty());
+        let second = &contents[FRAME_HEADER_LEN + 3..];
+        let messages = decoder.decode(100, second);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].output, b"two");
+        assert_eq!(messages[0].offset, 100);
+    }
+
+    #[tokio::test]
+    async fn given_rotate_limit_then_reader_follows_across_segments() {
+        let limit = Limit {
+            max_bytes: 4,
+            keep_segments: 3,
+            policy: Policy::Rotate,
+        };
+        let (dir, exceeded) = sink_with(
+            "rotate",
+            Format::Separate,
+            limit,
+            &[b"one\n", b"two\n", b"three\n"],
+        )
+        .await;
+        assert!(!exceeded);
+        assert!(dir.join("out.0").exists());
+        assert!(dir.join("out.4").exists());
+        // Output is split at segment boundary
+        assert_eq!(std::fs::read(dir.join("out.8")).unwrap(), b"thre");
+
+        let mut reader = Reader::open(&dir, Format::Separate, Fd::Out, Start::Offset(2))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_all(&mut reader).await,
+            (2, b"e\ntwo\nthree\n".to_vec())
+        );
+
+        let mut reader = Reader::open(&dir, Format::Separate, Fd::Out, Start::Tail(2))
+            .await
+            .unwrap();
+        assert_eq!(read_all(&mut reader).await, (4, b"two\nthree\n".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn given_rotate_limit_then_oldest_segments_are_dropped() {
+        let limit = Limit {
+            max_bytes: 4,
+            keep_segments: 1,
+            policy: Policy::Rotate,
+        };
+        let (dir, _) = sink_with(
+            "drop",
+            Format::Separate,
+            limit,
+            &[b"one\n", b"two\n", b"six\n"],
+        )
+        .await;
+        assert!(!dir.join("out.0").exists());
+
+        let mut reader = Reader::open(&dir, Format::Separate, Fd::Out, Start::Offset(0))
+            .await
+            .unwrap();
+        assert_eq!(read_all(&mut reader).await, (4, b"two\nsix\n".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn given_truncate_limit_then_keeps_most_recent_output() {
+        let limit = Limit {
+            max_bytes: 8,
+            keep_segments: 0,
+            policy: Policy::Truncate,
+        };
+        let chunks: [&[u8]; 4] = [b"aaaa", b"bbbb", b"cccc", b"dd"];
+        let (dir, exceeded) = sink_with("truncate", Format::Separate, limit, &chunks).await;
+        assert!(!exceeded);
+
+        let mut reader = Reader::open(&dir, Format::Separate, Fd::Out, Start::Offset(0))
+            .await
+            .unwrap();
+        assert_eq!(read_all(&mut reader).await, (8, b"ccccdd".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn given_kill_limit_then_output_is_capped() {
+        let limit = Limit {
+            max_bytes: 6,
+            keep_segments: 0,
+            policy: Policy::Kill,
+        };
+        let (dir, exceeded) =
+            sink_with("kill", Format::Separate, limit, &[b"one\n", b"two\n"]).await;
+        assert!(exceeded);
+        assert_eq!(std::fs::read(dir.join("out")).unwrap(), b"one\ntw");
+
+        let limit = Limit {
+            max_bytes: (FRAME_HEADER_LEN + 6) as u64,
+            ..limit
+        };
+        let (dir, exceeded) = sink_with(
+            "kill-combined",
+            Format::Combined,
+            limit,
+            &[b"one\n", b"two\n"],
+        )
+        .await;
+        assert!(exceeded);
+        assert_eq!(
+            std::fs::read(dir.join("log")).unwrap().len(),
+            FRAME_HEADER_LEN + 4
+        );
+    }
+
+    #[tokio::test]
+    async fn given_compressed_segments_then_reader_output_is_unchanged() {
+        let limit = Limit {
+            max_bytes: 4,
+            keep_segments: 3,
+            policy: Policy::Rotate,
+        };
+        let (dir, _) = sink_with(
+            "compress",
+            Format::Separate,
+            limit,
+            &[b"one\n", b"two\n", b"three\n"],
+        )
+        .await;
+        let mut follower = Reader::open(&dir, Format::Separate, Fd::Out, Start::Offset(0))
+            .await
+            .unwrap();
+
+        let savings = compress(&dir, Format::Separate).await.unwrap();
+        assert_eq!(savings.original, 14);
+        assert_eq!(
+            super::savings(&dir, Format::Separate).await.unwrap(),
+            savings
+        );
+        assert!(!dir.join("out").exists());
+        assert!(dir.join("out.12.gz").exists());
+        assert!(dir.join("err.0.gz").exists());
+
+        assert_eq!(
+            read_all(&mut follower).await,
+            (0, b"one\ntwo\nthree\n".to_vec())
+        );
+        for (start, expected) in [
+            (Start::Offset(5), (5, b"wo\nthree\n".to_vec())),
+            (Start::Tail(2), (4, b"two\nthree\n".to_vec())),
+            (Start::Tail(0), (14, Vec::new())),
+        ] {
+            let mut reader = Reader::open(&dir, Format::Separate, Fd::Out, start)
+                .await
+                .unwrap();
+            assert_eq!(read_all(&mut reader).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn given_offset_within_combined_frame_then_it_is_rejected() {
+        let dir = temp_dir("frame-offset");
+        let contents = frames(&[(Fd::Out, b"one\n"), (Fd::Err, b"two\n")]);
+        std::fs::write(path(&dir, Format::Combined, Fd::Out), &contents).unwrap();
+        let second = (FRAME_HEADER_LEN + 4) as u64;
+
+        for compressed in [false, true] {
+            if compressed {
+                compress(&dir, Format::Combined).await.unwrap();
+            }
+            for offset in [0, second, contents.len() as u64] {
+                let reader = Reader::open(&dir, Format::Combined, Fd::Out, Start::Offset(offset))
+                    .await
+                    .unwrap();
+                assert_eq!(reader.offset(), offset);
+            }
+            for offset in [5, second + 1, contents.len() as u64 + 1] {
+                let err = Reader::open(&dir, Format::Combined, Fd::Out, Start::Offset(offset))
+                    .await
+                    .err()
+                    .unwrap();
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn given_compressed_combined_log_then_tail_starts_at_frame_after_newline() {
+        let dir = temp_dir("compress-combined");
+        let contents = frames(&[(Fd::Out, b"one\n"), (Fd::Err, b"two\nthree\n")]);
+        std::fs::write(path(&dir, Format::Combined, Fd::Out), &contents).unwrap();
+        compress(&dir, Format::Combined).await.unwrap();
+
+        let second = (FRAME_HEADER_LEN + 4) as u64;
+        for (lines, offset) in [(1, second), (2, second), (3, 0)] {
+            let reader = Reader::open(&dir, Format::Combined, Fd::Out, Start::Tail(lines))
+                .await
+                .unwrap();
+            assert_eq!(reader.offset(), offset);
+        }
+    }
+
+    fn message(fd: Fd, offset: u64, output: &[u8]) -> LogMessage {
+        LogMessage {
+            fd: fd.into(),
+            output: output.to_vec(),
+            offset,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn given_pattern_then_only_matching_lines_pass() {
+        let mut filter = Filter::new(Selection::Both, Some(Regex::new("^err").unwrap()));
+        let passed = filter.apply(
+            Format::Separate,
+            vec![
+                message(Fd::Out, 0, b"ok\nerror: fir"),
+                message(Fd::Out, 13, b"st\nerr"),
+            ],
+        );
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].output, b"error: first\n");
+        assert_eq!(passed[0].offset, 3);
+
+        let passed = filter.finish();
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].output, b"err");
+        assert_eq!(passed[0].offset, 16);
+    }
+
+    #[test]
+    fn given_selection_then_other_fd_is_dropped() {
+        let mut filter = Filter::new(Selection::Err, None);
+        let passed = filter.apply(
+            Format::Combined,
+            vec![
+                message(Fd::Out, 0, b"out\n"),
+                message(Fd::Err, 25, b"err\n"),
+            ],
+        );
+        assert_eq!(passed.len(), 1);
+        assert_eq!((passed[0].fd, passed[0].offset), (1, 25));
+        assert!(filter.finish().is_empty());
+    }
+
+    #[test]
+    fn given_combined_log_then_matching_line_keeps_frame_offset() {
+        let mut filter = Filter::new(Selection::Both, Some(Regex::new("b$").unwrap()));
+        let passed = filter.apply(
+            Format::Combined,
+            vec![message(Fd::Out, 0, b"a\nb\n"), message(Fd::Err, 26, b"b\n")],
+        );
+        let offsets: Vec<_> = passed.iter().map(|msg| (msg.fd, msg.offset)).collect();
+        assert_eq!(offsets, [(0, 0), (1, 26)]);
+    }
+
+    #[test]
+    fn given_client_ceiling_then_job_limit_is_clamped() {
+        let job = Limit {
+            max_bytes: 100,
+            keep_segments: 10,
+            policy: Policy::Kill,
+        };
+        let client = Limit {
+            max_bytes: 50,
+            keep_segments: 20,
+            policy: Policy::Rotate,
+        };
+        let limit = job.within(&client).unwrap();
+        assert_eq!((limit.max_bytes, limit.keep_segments), (50, 10));
+        assert_eq!(limit.policy, Policy::Kill);
+    }
+
+    #[test]
+    fn given_rotating_job_under_kill_ceiling_then_job_is_killed_at_ceiling() {
+        let job = Limit {
+            max_bytes: 100,
+            keep_segments: 10,
+            policy: Policy::Rotate,
+        };
+        let client = Limit {
+            max_bytes: 50,
+            keep_segments: 0,
+            policy: Policy::Kill,
+        };
+        let limit = job.within(&client).unwrap();
+        assert_eq!(limit.policy, Policy::Kill);
+        assert_eq!(limit.max_bytes, 50);
+
+        let client = Limit {
+            policy: Policy::Truncate,
+            ..client
+        };
+        assert_eq!(job.within(&client).unwrap().policy, Policy::Truncate);
+    }
+
+    #[test]
+    fn given_limit_discarding_output_right_away_then_it_is_rejected() {
+        let limit = |max_bytes, keep_segments, policy| LogLimit {
+            max_bytes,
+            keep_segments,
+            policy: policy as i32,
+        };
+
+        assert!(matches!(
+            Limit::try_from(&limit(0, 3, LimitPolicy::Truncate)),
+            Err(Error::ZeroMaxBytes)
+        ));
+        assert!(matches!(
+            Limit::try_from(&limit(1024, 0, LimitPolicy::Rotate)),
+            Err(Error::NoSegments)
+        ));
+        assert!(Limit::try_from(&limit(1024, 0, LimitPolicy::Kill)).is_ok());
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use prost::Message;
+use thiserror::Error;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::runner::JobMetadata;
+
+pub const METADATA_FILE: &str = "meta";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+    #[error("Malformed job metadata: {0}")]
+    Decode(#[from] prost::DecodeError),
+}
+
+pub fn path(job_dir: &Path) -> PathBuf {
+    job_dir.join(METADATA_FILE)
+}
+
+/// Replaces metadata atomically, so it is never observed partially written.
+/// Readable by the server only, as the request describes how the job was set up
+pub async fn write(job_dir: &Path, metadata: &JobMetadata) -> Result<(), Error> {
+    let partial = job_dir.join(format!("{}.partial", METADATA_FILE));
+    // Left over by a crash, mode applies to files being created only
+    match tokio::fs::remove_file(&partial).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => (),
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&partial)
+        .await?;
+    file.write_all(&metadata.encode_to_vec()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&partial, path(job_dir)).await?;
+
+    Ok(())
+}
+
+pub async fn read(job_dir: &Path) -> Result<JobMetadata, Error> {
+    let encoded = tokio::fs::read(path(job_dir)).await?;
+    Ok(JobMetadata::decode(encoded.as_slice())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, write, METADATA_FILE};
+    use crate::runner::{
+        job_metadata::Compression, job_status::Outcome, JobMetadata, JobRequest, JobStatus,
+        SeccompViolation,
+    };
+    use crate::testing::temp_dir;
+    use std::os::unix::fs::MetadataExt;
+
+    #[tokio::test]
+    async fn given_written_metadata_then_it_is_read_back() {
+        let dir = temp_dir("metadata");
+        let metadata = JobMetadata {
+            request: Some(JobRequest {
+                executable: "ls".into(),
+                ..Default::default()
+            }),
+            owner: "client".into(),
+            started_at: 1,
+            ended_at: Some(2),
+            pid: Some(4),
+            uid: Some(5),
+            status: Some(JobStatus {
+                outcome: Some(Outcome::ExitCode(3)),
+                seccomp_violations: vec![SeccompViolation {
+                    syscall: 165,
+                    name: "mount".into(),
+                    count: 1,
+                }],
+            }),
+            compression: Some(Compression {
+                original_bytes: 6,
+                compressed_bytes: 7,
+            }),
+        };
+
+        write(&dir, &metadata).await.unwrap();
+        assert_eq!(read(&dir).await.unwrap(), metadata);
+        let mode = std::fs::metadata(dir.join(METADATA_FILE)).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,470 @@
+use std::{
+    ffi::CStr,
+    mem::MaybeUninit,
+    os::unix::prelude::RawFd,
+    ptr::{null, null_mut},
+};
+
+use nix::libc::{
+    self, c_int, c_short, c_uint, c_void, ifreq, sigset_t, AF_INET, CLONE_NEWIPC, CLONE_NEWNET,
+    CLONE_NEWNS, CLONE_NEWPID, CLONE_NEWUSER, CLONE_NEWUTS, EIO, IFF_RUNNING, IFF_UP, MS_NODEV,
+    MS_NOEXEC, MS_NOSUID, MS_PRIVATE, MS_REC, O_CLOEXEC, O_WRONLY, PR_SET_PDEATHSIG, RLIMIT_CORE,
+    RLIMIT_NOFILE, SIGCHLD, SIGKILL, SIG_DFL, SIG_SETMASK, SIG_UNBLOCK, SIOCSIFFLAGS, SOCK_CLOEXEC,
+    SOCK_DGRAM, WNOHANG,
+};
+use nix::unistd::{getgid, getuid};
+
+use crate::{job::harden, rootfs::Rootfs, runner::job_request::Isolation};
+
+/// Namespaces job is isolated in. They are entered by pre_exec closure,
+/// so everything called on the way has to be async-signal-safe
+#[derive(Debug, Clone, Default)]
+pub struct Namespaces {
+    pid: bool,
+    mount: bool,
+    hostname: Option<Vec<u8>>,
+    ipc: bool,
+    net: bool,
+    rootfs: Option<Rootfs>,
+    user: Option<UserNamespace>,
+    owner: Option<Owner>,
+}
+
+// Credentials supervisor and init of pid namespace drop to
+#[derive(Debug, Clone)]
+struct Owner {
+    uid: u32,
+    gid: u32,
+    groups: Vec<u32>,
+}
+
+/// User namespace entered before the others in rootless mode. Job user maps onto the server
+/// user, which gets capabilities over the rest of the namespaces without being root
+#[derive(Debug, Clone)]
+pub struct UserNamespace {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+impl UserNamespace {
+    /// Maps `uid` and `gid` of the job onto those of the server
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            uid_map: format!("{} {} 1", uid, getuid()).into_bytes(),
+            gid_map: format!("{} {} 1", gid, getgid()).into_bytes(),
+        }
+    }
+
+    // Calling process gets full set of capabilities within the namespace
+    fn enter(&self) -> std::io::Result<()> {
+        // Safety: takes no pointers
+        check(unsafe { libc::unshare(CLONE_NEWUSER) })?;
+
+        // Unprivileged process may write gid map only once setgroups is denied
+        write(c"/proc/self/setgroups", b"deny")?;
+        write(c"/proc/self/uid_map", &self.uid_map)?;
+        write(c"/proc/self/gid_map", &self.gid_map)
+    }
+}
+
+impl From<&Isolation> for Namespaces {
+    fn from(value: &Isolation) -> Self {
+        Self {
+            pid: value.pid,
+            mount: value.mount,
+            hostname: value.hostname.clone().map(String::into_bytes),
+            ipc: value.ipc,
+            net: value.net,
+            rootfs: None,
+            user: None,
+            owner: None,
+        }
+    }
+}
+
+impl Namespaces {
+    /// Switches job into `rootfs`, which implies mount namespace
+    pub fn set_rootfs(mut self, rootfs: Option<Rootfs>) -> Self {
+        self.rootfs = rootfs;
+        self
+    }
+
+    /// Enters user namespace first, which implies pid namespace, as otherwise job
+    /// could signal and trace the server it shares uid with
+    pub fn set_user(mut self, user: Option<UserNamespace>) -> Self {
+        self.pid |= user.is_some();
+        self.user = user;
+        self
+    }
+
+    /// Drops supervisor and init of pid namespace to credentials of the job, see `job::harden`,
+    /// as neither of them execs. They keep credentials of the server otherwise
+    pub fn set_owner(mut self, uid: u32, gid: u32, groups: Vec<u32>) -> Self {
+        self.owner = Some(Owner { uid, gid, groups });
+        self
+    }
+
+    fn is_mount(&self) -> bool {
+        self.mount || self.rootfs.is_some()
+    }
+
+    fn flags(&self) -> c_int {
+        [
+            (self.pid, CLONE_NEWPID),
+            (self.is_mount(), CLONE_NEWNS),
+            (self.hostname.is_some(), CLONE_NEWUTS),
+            (self.ipc, CLONE_NEWIPC),
+            (self.net, CLONE_NEWNET),
+        ]
+        .iter()
+        .filter(|(requested, _)| *requested)
+        .fold(0, |flags, (_, flag)| flags | flag)
+    }
+
+    /// Moves calling process into new namespaces. New pid namespace is entered by children
+    /// only, so calling process stays outside supervising init of the namespace, which in turn
+    /// forks the process returning from here. Has to be called before dropping privileges
+    pub fn enter(&self) -> std::io::Result<()> {
+        if let Some(user) = &self.user {
+            user.enter()?;
+        }
+
+        let flags = self.flags();
+        if flags == 0 {
+            return Ok(());
+        }
+
+        // Safety: takes no pointers
+        check(unsafe { libc::unshare(flags) })?;
+
+        if let Some(hostname) = &self.hostname {
+            // Safety: hostname outlives the call
+            check(unsafe { libc::sethostname(hostname.as_ptr().cast(), hostname.len()) })?;
+        }
+
+        if self.is_mount() {
+            // Keeps mounts made by the job from propagating back to the host
+            // Safety: paths are nul-terminated literals
+            check(unsafe {
+                libc::mount(null(), c"/".as_ptr(), null(), MS_REC | MS_PRIVATE, null())
+            })?;
+        }
+
+        if self.net {
+            loopback_up()?;
+        }
+
+        if self.pid {
+            fork_init(self.owner.as_ref())?;
+        }
+
+        match &self.rootfs {
+            // Mounts /proc of its own
+            Some(rootfs) => rootfs.enter()?,
+            None if self.pid && self.mount => {
+                // Safety: paths are nul-terminated literals
+                check(unsafe {
+                    libc::mount(
+                        c"proc".as_ptr(),
+                        c"/proc".as_ptr(),
+                        c"proc".as_ptr(),
+                        MS_NOSUID | MS_NODEV | MS_NOEXEC,
+                        null(),
+                    )
+                })?;
+            }
+            None => (),
+        }
+
+        Ok(())
+    }
+}
+
+// Writes whole `contents` at once, as files under /proc/self expect
+fn write(path: &CStr, contents: &[u8]) -> std::io::Result<()> {
+    // Safety: path and contents outlive the calls, fd is closed once
+    unsafe {
+        let fd = libc::open(path.as_ptr(), O_WRONLY | O_CLOEXEC);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+        let err = std::io::Error::last_os_error();
+        libc::close(fd);
+
+        match written {
+            n if n < 0 => Err(err),
+            n if n as usize != contents.len() => Err(std::io::Error::from_raw_os_error(EIO)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check(res: c_int) -> std::io::Result<()> {
+    if res < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// New network namespace comes with loopback interface, which is down
+fn loopback_up() -> std::io::Result<()> {
+    // Safety: takes no pointers
+    let sock = unsafe { libc::socket(AF_INET, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+    check(sock)?;
+
+    // Safety: ifreq is plain data, zeroed value is valid
+    let mut req: ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as _;
+    }
+    req.ifr_ifru.ifru_flags = (IFF_UP | IFF_RUNNING) as c_short;
+
+    // Safety: req outlives the call, sock is closed once
+    let res = unsafe { libc::ioctl(sock, SIOCSIFFLAGS, &req) };
+    unsafe { libc::close(sock) };
+    check(res)
+}
+
+// Forks init of the new pid namespace, which forks the job in turn. Returns in the job
+// process only, while the calling process and init exit along with the job.
+//
+// Exit status of pid 1 can't mirror that of the job, as pid 1 can't kill itself with a signal.
+// So init reports status of the job to the calling process, which mirrors it instead and
+// is what the server tracks as the job. Signals are taken synchronously by both, and
+// forwarded down to the job. Both drop to `owner` once the job is forked, which is enough
+// to signal and reap it
+fn fork_init(owner: Option<&Owner>) -> std::io::Result<()> {
+    let all = signals(None);
+    let mut mask = MaybeUninit::<sigset_t>::uninit();
+    // Safety: sets outlive the call
+    check(unsafe { libc::sigprocmask(SIG_SETMASK, &all, mask.as_mut_ptr()) })?;
+    // Safety: initialized by sigprocmask
+    let mask = unsafe { mask.assume_init() };
+
+    let mut fds = [0 as c_int; 2];
+    // Safety: fds outlives the call
+    check(unsafe { libc::pipe2(fds.as_mut_ptr(), O_CLOEXEC) })?;
+    let [reader, writer] = fds;
+
+    // Safety: child calls async-signal-safe functions only
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => {
+            // Safety: pipe fd is closed once
+            unsafe { libc::close(reader) };
+
+            // Safety: child calls async-signal-safe functions only
+            match unsafe { libc::fork() } {
+                -1 => exit(127),
+                0 => {
+                    // Safety: pipe fd is closed once and mask outlives the call
+                    unsafe {
+                        libc::close(writer);
+                        check(libc::sigprocmask(SIG_SETMASK, &mask, null_mut()))?;
+                    }
+                    Ok(())
+                }
+                job => {
+                    if drop_to(owner).is_err() {
+                        // Safety: takes no pointers
+                        unsafe { libc::kill(job, SIGKILL) };
+                        exit(127);
+                    }
+                    // Init is killed along with the calling process, e.g. once job is
+                    // cancelled. Set once credentials are dropped, which resets it
+                    // Safety: takes no pointers
+                    unsafe { libc::prctl(PR_SET_PDEATHSIG, SIGKILL) };
+                    init(job, writer)
+                }
+            }
+        }
+        init => {
+            // Safety: pipe fd is closed once
+            unsafe { libc::close(writer) };
+            if let Err(err) = drop_to(owner) {
+                // Safety: takes no pointers
+                unsafe { libc::kill(init, SIGKILL) };
+                return Err(err);
+            }
+            supervise(init, reader)
+        }
+    }
+}
+
+fn drop_to(owner: Option<&Owner>) -> std::io::Result<()> {
+    match owner {
+        Some(owner) => harden(owner.uid, owner.gid, &owner.groups),
+        None => Ok(()),
+    }
+}
+
+// Reaps every process of the namespace, until the job itself exits
+fn init(job: libc::pid_t, status_writer: RawFd) -> ! {
+    close_inherited(status_writer);
+
+    let status = wait_forwarding(job, -1);
+    // Safety: status outlives the call
+    unsafe {
+        libc::write(
+            status_writer,
+            &status as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>(),
+        );
+    }
+    exit(0)
+}
+
+// Waits for init, then exits the way the job did
+fn supervise(init: libc::pid_t, status_reader: RawFd) -> ! {
+    close_inherited(status_reader);
+
+    let init_status = wait_forwarding(init, init);
+    let mut status: c_int = 0;
+    // Safety: status outlives the call, init is gone so it does not block
+    let len = unsafe {
+        libc::read(
+            status_reader,
+            &mut status as *mut c_int as *mut c_void,
+            std::mem::size_of::<c_int>(),
+        )
+    };
+    // Init was killed before job exited
+    if len != std::mem::size_of::<c_int>() as isize {
+        status = init_status;
+    }
+
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        let core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        let unblocked = signals(Some(signal));
+        // Safety: calls take no pointers or pointers outliving them
+        unsafe {
+            libc::setrlimit(RLIMIT_CORE, &core);
+            libc::signal(signal, SIG_DFL);
+            libc::sigprocmask(SIG_UNBLOCK, &unblocked, null_mut());
+            libc::kill(libc::getpid(), signal);
+        }
+        exit(128 + signal);
+    }
+
+    exit(libc::WEXITSTATUS(status))
+}
+
+// Reaps children matching `reap` until `child` exits, meanwhile signals other than SIGCHLD
+// are forwarded to `child`. Expects all signals to be blocked
+fn wait_forwarding(child: libc::pid_t, reap: libc::pid_t) -> c_int {
+    let all = signals(None);
+    loop {
+        // Safety: set outlives the call
+        let signal = unsafe { libc::sigwaitinfo(&all, null_mut()) };
+        if signal < 0 {
+            continue;
+        }
+
+        if signal != SIGCHLD {
+            // Safety: takes no pointers
+            unsafe { libc::kill(child, signal) };
+            continue;
+        }
+
+        // Reaps all exited children, as SIGCHLD of several children may coalesce
+        loop {
+            let mut status: c_int = 0;
+            // Safety: status outlives the call
+            let pid = unsafe { libc::waitpid(reap, &mut status, WNOHANG) };
+            if pid == child {
+                return status;
+            }
+            if pid <= 0 {
+                break;
+            }
+        }
+    }
+}
+
+// Set of all signals if `signal` is None, of just `signal` otherwise
+fn signals(signal: Option<c_int>) -> sigset_t {
+    let mut set = MaybeUninit::<sigset_t>::uninit();
+    // Safety: set is initialized by either of calls
+    unsafe {
+        match signal {
+            None => libc::sigfillset(set.as_mut_ptr()),
+            Some(signal) => {
+                libc::sigemptyset(set.as_mut_ptr());
+                libc::sigaddset(set.as_mut_ptr(), signal)
+            }
+        };
+        set.assume_init()
+    }
+}
+
+// Closes fds inherited from the server other than stdio and `keep`. Otherwise init and its
+// supervisor would hold e.g. pipe spawn waits to be closed by exec of the job
+fn close_inherited(keep: RawFd) {
+    let keep = keep as c_uint;
+    let ranges = [(3, keep.saturating_sub(1)), (keep + 1, c_uint::MAX)];
+    // Safety: closes fds of this process only, which are not used past this point
+    let closed = ranges
+        .iter()
+        .filter(|(first, last)| first <= last)
+        .all(|(first, last)| unsafe { libc::close_range(*first, *last, 0) } == 0);
+    if closed {
+        return;
+    }
+
+    // Kernel older than 5.9
+    let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+    // Safety: limit outlives the call and is initialized on success
+    let max = match unsafe { libc::getrlimit(RLIMIT_NOFILE, limit.as_mut_ptr()) } {
+        0 => unsafe { limit.assume_init() }
+            .rlim_cur
+            .min(c_int::MAX as u64) as c_int,
+        _ => 1024,
+    };
+    (3..max).filter(|fd| *fd as c_uint != keep).for_each(|fd| {
+        // Safety: see above
+        unsafe { libc::close(fd) };
+    });
+}
+
+fn exit(code: c_int) -> ! {
+    // Safety: skips atexit handlers and destructors, which are not async-signal-safe
+    unsafe { libc::_exit(code) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_isolation_then_only_requested_namespaces_are_created() {
+        let namespaces = Namespaces::from(&Isolation {
+            pid: true,
+            hostname: Some("job".to_string()),
+            net: true,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            namespaces.flags(),
+            CLONE_NEWPID | CLONE_NEWUTS | CLONE_NEWNET
+        );
+        assert_eq!(Namespaces::default().flags(), 0);
+    }
+
+    #[test]
+    fn given_user_namespace_then_pid_namespace_is_created() {
+        let namespaces = Namespaces::default().set_user(Some(UserNamespace::new(2000, 1000)));
+
+        assert_eq!(namespaces.flags(), CLONE_NEWPID);
+        assert_eq!(
+            namespaces.user.unwrap().uid_map,
+            format!("2000 {} 1", getuid()).into_bytes()
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    fs::File,
+    os::unix::prelude::{AsRawFd, ExitStatusExt, FromRawFd, RawFd},
+    process::ExitStatus,
+};
+
+use nix::{
+    libc::{
+        self, c_int, id_t, siginfo_t, SYS_pidfd_open, SYS_pidfd_send_signal, CLD_EXITED, P_PIDFD,
+        WEXITED,
+    },
+    sys::signal::Signal,
+};
+use tokio::io::{unix::AsyncFd, Interest};
+
+/// Process handle immune to pid reuse, as it refers to the process rather than its pid.
+/// Works for processes which are not children of the server as well
+#[derive(Debug)]
+pub struct PidFd {
+    fd: AsyncFd<File>,
+}
+
+impl PidFd {
+    pub fn open(pid: u32) -> std::io::Result<Self> {
+        // Safety: syscall takes no pointers
+        let fd = unsafe { libc::syscall(SYS_pidfd_open, pid as libc::pid_t, 0 as c_int) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // Safety: fd was just opened and is owned by nothing else
+        Self::try_from(unsafe { File::from_raw_fd(fd as RawFd) })
+    }
+
+    pub fn signal(&self, signal: Signal) -> std::io::Result<()> {
+        // Safety: null siginfo is allowed and makes the call equivalent to kill(2)
+        let res = unsafe {
+            libc::syscall(
+                SYS_pidfd_send_signal,
+                self.as_raw_fd(),
+                signal as c_int,
+                std::ptr::null::<siginfo_t>(),
+                0 as c_int,
+            )
+        };
+
+        if res < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reaps the process once it exits, process has to be a child of the caller
+    pub async fn wait(&self) -> std::io::Result<ExitStatus> {
+        self.exited().await?;
+
+        // Safety: siginfo_t is plain data, zeroed value is valid
+        let mut info: siginfo_t = unsafe { std::mem::zeroed() };
+        // Safety: info outlives the call, process has exited so it does not block
+        let res = unsafe { libc::waitid(P_PIDFD, self.as_raw_fd() as id_t, &mut info, WEXITED) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // Safety: waitid fills in child fields of siginfo
+        let status = unsafe { info.si_status() };
+        // Raw status is encoded the way waitpid(2) reports it
+        Ok(match info.si_code {
+            CLD_EXITED => ExitStatus::from_raw((status & 0xff) << 8),
+            _ => ExitStatus::from_raw(status & 0x7f),
+        })
+    }
+
+    /// Resolves once process exits, pidfd stays readable from then on
+    pub async fn exited(&self) -> std::io::Result<()> {
+        let mut guard = self.fd.readable().await?;
+        guard.retain_ready();
+        Ok(())
+    }
+}
+
+/// Takes over pidfd obtained elsewhere, e.g. through `CLONE_PIDFD`
+impl TryFrom<File> for PidFd {
+    type Error = std::io::Error;
+
+    fn try_from(file: File) -> Result<Self, Self::Error> {
+        Ok(Self {
+            fd: AsyncFd::with_interest(file, Interest::READABLE)?,
+        })
+    }
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    // Children are reaped through pidfd
+    #[allow(clippy::zombie_processes)]
+    #[tokio::test]
+    async fn given_exited_child_then_wait_reports_exit_code() {
+        let child = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        let pidfd = PidFd::open(child.id()).unwrap();
+
+        assert_eq!(pidfd.wait().await.unwrap().code(), Some(3));
+    }
+
+    #[allow(clippy::zombie_processes)]
+    #[tokio::test]
+    async fn given_signalled_child_then_wait_reports_signal() {
+        let child = Command::new("sleep").arg("10").spawn().unwrap();
+        let pidfd = PidFd::open(child.id()).unwrap();
+
+        pidfd.signal(Signal::SIGKILL).unwrap();
+        assert_eq!(
+            pidfd.wait().await.unwrap().signal(),
+            Some(Signal::SIGKILL as i32)
+        );
+        // Reaped process can no longer be signalled, even if its pid gets reused
+        assert_eq!(
+            pidfd.signal(Signal::SIGKILL).unwrap_err().raw_os_error(),
+            Some(libc::ESRCH)
+        );
+    }
+}
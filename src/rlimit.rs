@@ -0,0 +1,270 @@
+use std::{collections::BTreeMap, fmt, mem::MaybeUninit};
+
+use nix::libc::{
+    self, c_int, RLIMIT_CORE, RLIMIT_CPU, RLIMIT_FSIZE, RLIMIT_MEMLOCK, RLIMIT_NOFILE,
+    RLIMIT_NPROC, RLIMIT_STACK,
+};
+use thiserror::Error;
+
+use crate::runner::job_request::{rlimit, Rlimit as RequestedRlimit};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unknown rlimit {0}")]
+    UnknownResource(String),
+    #[error("Rlimit {0} is requested more than once")]
+    Duplicate(Resource),
+    #[error("Soft rlimit {0} exceeds its hard limit")]
+    SoftAboveHard(Resource),
+    #[error("Rlimit {resource} exceeds client maximum of {max}")]
+    AboveMaximum { resource: Resource, max: u64 },
+    #[error("Failed to read rlimit {0}: {1}")]
+    Current(Resource, std::io::Error),
+}
+
+/// Resource without cgroup equivalent, limited per process through setrlimit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resource {
+    Nofile,
+    Core,
+    Fsize,
+    Stack,
+    Cpu,
+    Nproc,
+    Memlock,
+}
+
+impl From<&Resource> for &str {
+    fn from(value: &Resource) -> Self {
+        match value {
+            Resource::Nofile => "nofile",
+            Resource::Core => "core",
+            Resource::Fsize => "fsize",
+            Resource::Stack => "stack",
+            Resource::Cpu => "cpu",
+            Resource::Nproc => "nproc",
+            Resource::Memlock => "memlock",
+        }
+    }
+}
+
+impl TryFrom<&str> for Resource {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "nofile" => Ok(Resource::Nofile),
+            "core" => Ok(Resource::Core),
+            "fsize" => Ok(Resource::Fsize),
+            "stack" => Ok(Resource::Stack),
+            "cpu" => Ok(Resource::Cpu),
+            "nproc" => Ok(Resource::Nproc),
+            "memlock" => Ok(Resource::Memlock),
+            _ => Err(Error::UnknownResource(value.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.into())
+    }
+}
+
+impl From<rlimit::Resource> for Resource {
+    fn from(value: rlimit::Resource) -> Self {
+        match value {
+            rlimit::Resource::Nofile => Resource::Nofile,
+            rlimit::Resource::Core => Resource::Core,
+            rlimit::Resource::Fsize => Resource::Fsize,
+            rlimit::Resource::Stack => Resource::Stack,
+            rlimit::Resource::Cpu => Resource::Cpu,
+            rlimit::Resource::Nproc => Resource::Nproc,
+            rlimit::Resource::Memlock => Resource::Memlock,
+        }
+    }
+}
+
+impl Resource {
+    fn as_raw(&self) -> c_int {
+        (match self {
+            Resource::Nofile => RLIMIT_NOFILE,
+            Resource::Core => RLIMIT_CORE,
+            Resource::Fsize => RLIMIT_FSIZE,
+            Resource::Stack => RLIMIT_STACK,
+            Resource::Cpu => RLIMIT_CPU,
+            Resource::Nproc => RLIMIT_NPROC,
+            Resource::Memlock => RLIMIT_MEMLOCK,
+        }) as c_int
+    }
+
+    // Limits of the server, which job inherits unless they are set
+    fn current(&self) -> Result<Limit, Error> {
+        let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+        // Safety: limit outlives the call and is initialized on success
+        if unsafe { libc::getrlimit(self.as_raw() as _, limit.as_mut_ptr()) } < 0 {
+            return Err(Error::Current(*self, std::io::Error::last_os_error()));
+        }
+        // Safety: see above
+        let limit = unsafe { limit.assume_init() };
+
+        Ok(Limit {
+            soft: limit.rlim_cur,
+            hard: limit.rlim_max,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Limit {
+    soft: u64,
+    hard: u64,
+}
+
+/// Rlimits of the job, set by pre_exec closure before privileges are dropped,
+/// so that hard limits may be raised above those of the server
+#[derive(Debug, Clone, Default)]
+pub struct Rlimits(Vec<(Resource, Limit)>);
+
+impl Rlimits {
+    /// Validates requested limits against client `maxima`, which also cap hard limits of
+    /// resources job does not request. Absent hard limit defaults to client maximum, or to
+    /// the hard limit of the server, as unlimited one can't be set for nofile nor in rootless
+    /// mode. Absent soft limit defaults to the hard one
+    pub fn new(
+        requested: &[RequestedRlimit],
+        maxima: &BTreeMap<Resource, u64>,
+    ) -> Result<Self, Error> {
+        let mut limits: BTreeMap<Resource, Limit> = BTreeMap::new();
+
+        for rlimit in requested {
+            let resource = rlimit.resource().into();
+            let max = maxima.get(&resource).copied();
+            let hard = match rlimit.hard.or(max) {
+                Some(hard) => hard,
+                None => resource.current()?.hard,
+            };
+            let soft = rlimit.soft.unwrap_or(hard);
+
+            if let Some(max) = max.filter(|max| hard > *max) {
+                return Err(Error::AboveMaximum { resource, max });
+            }
+            if soft > hard {
+                return Err(Error::SoftAboveHard(resource));
+            }
+            if limits.insert(resource, Limit { soft, hard }).is_some() {
+                return Err(Error::Duplicate(resource));
+            }
+        }
+
+        for (resource, max) in maxima {
+            if !limits.contains_key(resource) {
+                let current = resource.current()?;
+                let limit = Limit {
+                    soft: current.soft.min(*max),
+                    hard: *max,
+                };
+                limits.insert(*resource, limit);
+            }
+        }
+
+        Ok(Self(limits.into_iter().collect()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Called by pre_exec closure, setrlimit is async-signal-safe
+    pub fn apply(&self) -> std::io::Result<()> {
+        for (resource, limit) in &self.0 {
+            let limit = libc::rlimit {
+                rlim_cur: limit.soft,
+                rlim_max: limit.hard,
+            };
+            // Safety: limit outlives the call
+            if unsafe { libc::setrlimit(resource.as_raw() as _, &limit) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requested(
+        resource: rlimit::Resource,
+        soft: Option<u64>,
+        hard: Option<u64>,
+    ) -> RequestedRlimit {
+        RequestedRlimit {
+            resource: resource as i32,
+            soft,
+            hard,
+        }
+    }
+
+    #[test]
+    fn given_limit_above_client_maximum_then_it_is_rejected() {
+        let maxima = BTreeMap::from([(Resource::Nofile, 1024)]);
+
+        assert!(matches!(
+            Rlimits::new(
+                &[requested(rlimit::Resource::Nofile, None, Some(4096))],
+                &maxima
+            ),
+            Err(Error::AboveMaximum {
+                resource: Resource::Nofile,
+                max: 1024
+            })
+        ));
+        assert!(matches!(
+            Rlimits::new(&[requested(rlimit::Resource::Nofile, Some(512), None)], &maxima),
+            Ok(Rlimits(limits)) if limits == vec![(Resource::Nofile, Limit { soft: 512, hard: 1024 })]
+        ));
+        assert!(matches!(
+            Rlimits::new(
+                &[requested(rlimit::Resource::Core, Some(2), Some(1))],
+                &maxima
+            ),
+            Err(Error::SoftAboveHard(Resource::Core))
+        ));
+    }
+
+    #[test]
+    fn given_soft_limit_without_maximum_then_hard_limit_is_the_server_one() {
+        let current = Resource::Nofile.current().unwrap();
+
+        let Rlimits(limits) = Rlimits::new(
+            &[requested(
+                rlimit::Resource::Nofile,
+                Some(current.soft),
+                None,
+            )],
+            &BTreeMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            limits,
+            vec![(
+                Resource::Nofile,
+                Limit {
+                    soft: current.soft,
+                    hard: current.hard
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn given_client_maximum_then_it_caps_job_not_requesting_resource() {
+        let maxima = BTreeMap::from([(Resource::Core, 0)]);
+
+        let Rlimits(limits) = Rlimits::new(&[], &maxima).unwrap();
+        assert_eq!(limits, vec![(Resource::Core, Limit { soft: 0, hard: 0 })]);
+    }
+}
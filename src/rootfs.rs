@@ -0,0 +1,434 @@
+use std::{
+    ffi::{CStr, CString},
+    io,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        prelude::OsStrExt,
+    },
+    path::{Component, Path, PathBuf},
+    ptr::null,
+    sync::Arc,
+};
+
+use nix::libc::{
+    self, c_int, c_uint, c_ulong, c_void, open_how, SYS_move_mount, SYS_open_tree, SYS_openat2,
+    SYS_pivot_root, AT_EMPTY_PATH, AT_FDCWD, AT_RECURSIVE, EEXIST, ESTALE, MNT_DETACH, MS_BIND,
+    MS_NODEV, MS_NOEXEC, MS_NOSUID, MS_RDONLY, MS_REMOUNT, O_CLOEXEC, O_DIRECTORY, O_PATH,
+    RESOLVE_BENEATH, RESOLVE_IN_ROOT, RESOLVE_NO_MAGICLINKS, RESOLVE_NO_SYMLINKS, S_IFDIR, S_IFMT,
+    S_IFREG,
+};
+use thiserror::Error;
+
+use crate::stack_string::serialize_i32;
+
+/// Directory of the job tarball rootfs is unpacked into
+pub const ROOTFS_DIR: &str = "rootfs";
+
+// Device node of the host and its name within /dev of the job
+const DEVICES: [(&CStr, &CStr); 6] = [
+    (c"/dev/null", c"null"),
+    (c"/dev/zero", c"zero"),
+    (c"/dev/full", c"full"),
+    (c"/dev/random", c"random"),
+    (c"/dev/urandom", c"urandom"),
+    (c"/dev/tty", c"tty"),
+];
+// Link within /dev of the job and its target
+const LINKS: [(&CStr, &CStr); 4] = [
+    (c"fd", c"/proc/self/fd"),
+    (c"stdin", c"/proc/self/fd/0"),
+    (c"stdout", c"/proc/self/fd/1"),
+    (c"stderr", c"/proc/self/fd/2"),
+];
+const PROC_SELF_FD: &[u8] = b"/proc/self/fd/";
+// Flags of mount API calls, see open_tree(2) and move_mount(2)
+const OPEN_TREE_CLONE: c_uint = 1;
+const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x4;
+const MOVE_MOUNT_T_EMPTY_PATH: c_uint = 0x40;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Path {0} contains nul byte")]
+    Nul(PathBuf),
+    #[error("Path {0} inside rootfs has to be absolute and free of ..")]
+    InvalidPath(PathBuf),
+    #[error("Failed to read path of host file: {0}")]
+    Host(io::Error),
+}
+
+// Host file or directory opened when it was resolved. Fds opened outside mount namespace
+// of the job can't be mounted within it, so it is reopened by path in there and refused
+// unless path still leads to the very same file
+#[derive(Debug, Clone)]
+struct Host {
+    fd: Arc<OwnedFd>,
+    path: CString,
+}
+
+impl Host {
+    fn new(fd: OwnedFd) -> Result<Self, Error> {
+        let path =
+            std::fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())).map_err(Error::Host)?;
+        Ok(Self {
+            fd: Arc::new(fd),
+            path: cstring(&path)?,
+        })
+    }
+
+    fn reopen(&self) -> io::Result<OwnedFd> {
+        let fd = open(AT_FDCWD, &self.path, 0, RESOLVE_NO_SYMLINKS)?;
+        let (opened, reopened) = (stat(&self.fd)?, stat(&fd)?);
+        if (opened.st_dev, opened.st_ino) != (reopened.st_dev, reopened.st_ino) {
+            return Err(io::Error::from_raw_os_error(ESTALE));
+        }
+
+        Ok(fd)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bind {
+    source: Host,
+    // Directory within rootfs, relative to its root, and name of mount point in there
+    parent: CString,
+    name: CString,
+    read_only: bool,
+}
+
+/// Root filesystem job is switched into by pre_exec closure. Host files are held open
+/// from the time they are resolved, and paths are converted to C strings upfront,
+/// so that everything called there is async-signal-safe
+#[derive(Debug, Clone)]
+pub struct Rootfs {
+    root: Host,
+    binds: Vec<Bind>,
+    working_dir: CString,
+}
+
+impl Rootfs {
+    /// Job starts in root of the rootfs, with fresh /proc and minimal /dev
+    pub fn new(root: OwnedFd) -> Result<Self, Error> {
+        Ok(Self {
+            root: Host::new(root)?,
+            binds: Vec::new(),
+            working_dir: c"/".to_owned(),
+        })
+    }
+
+    /// Binds host `source` onto absolute `target` within rootfs,
+    /// whose parent directory has to exist in rootfs
+    pub fn add_bind(
+        mut self,
+        source: OwnedFd,
+        target: &Path,
+        read_only: bool,
+    ) -> Result<Self, Error> {
+        let target = relative(target)?;
+        let parent = match target.parent() {
+            Some(parent) if parent != Path::new("") => cstring(parent)?,
+            _ => c".".to_owned(),
+        };
+        let name = target
+            .file_name()
+            .ok_or_else(|| Error::InvalidPath(target.to_owned()))?;
+        self.binds.push(Bind {
+            source: Host::new(source)?,
+            parent,
+            name: cstring(Path::new(name))?,
+            read_only,
+        });
+
+        Ok(self)
+    }
+
+    /// Absolute path within rootfs
+    pub fn set_working_dir(mut self, working_dir: &Path) -> Result<Self, Error> {
+        relative(working_dir)?;
+        self.working_dir = cstring(working_dir)?;
+        Ok(self)
+    }
+
+    /// Switches root of calling process, expected to be in mount namespace of its own
+    /// with private mount propagation. Has to be called before dropping privileges.
+    /// Mount points are resolved within rootfs without following symlinks, which
+    /// an untrusted tarball could point at the host
+    pub fn enter(&self) -> io::Result<()> {
+        // pivot_root requires new root to be a mount point
+        let root = self.root.reopen()?;
+        let root = bind(&root, &root)?;
+        // Rootfs may come from untrusted tarball
+        remount(&root, MS_NOSUID | MS_NODEV)?;
+
+        for Bind {
+            source,
+            parent,
+            name,
+            read_only,
+        } in &self.binds
+        {
+            let source = source.reopen()?;
+            let target = mount_point(&root, parent, name, is_dir(&source)?)?;
+            let mounted = bind(&source, &target)?;
+            if *read_only {
+                remount(&mounted, MS_RDONLY | MS_NOSUID | MS_NODEV)?;
+            }
+        }
+
+        let dev = mount_point(&root, c".", c"dev", true)?;
+        mount(
+            c"tmpfs",
+            &dev,
+            c"tmpfs",
+            MS_NOSUID | MS_NOEXEC,
+            c"mode=755,size=65536",
+        )?;
+        // Reopened, as fd refers to directory underneath tmpfs
+        let dev = open_in_root(&root, c"dev", O_DIRECTORY)?;
+        for (device, name) in DEVICES {
+            let device = open(AT_FDCWD, device, 0, 0)?;
+            bind(&device, &mount_point(&dev, c".", name, false)?)?;
+        }
+        for (link, target) in LINKS {
+            // Safety: paths outlive the call
+            check(unsafe { libc::symlinkat(target.as_ptr(), dev.as_raw_fd(), link.as_ptr()) })?;
+        }
+        let shm = mount_point(&dev, c".", c"shm", true)?;
+        mount(c"tmpfs", &shm, c"tmpfs", MS_NOSUID | MS_NODEV, c"mode=1777")?;
+
+        // Shows processes of pid namespace of the calling process
+        let proc = mount_point(&root, c".", c"proc", true)?;
+        mount(
+            c"proc",
+            &proc,
+            c"proc",
+            MS_NOSUID | MS_NODEV | MS_NOEXEC,
+            c"",
+        )?;
+
+        // Old root ends up stacked on top of new one, from where it is detached
+        // Safety: paths outlive the calls
+        unsafe {
+            check(libc::fchdir(root.as_raw_fd()))?;
+            check(libc::syscall(SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as c_int)?;
+            check(libc::umount2(c".".as_ptr(), MNT_DETACH))?;
+            check(libc::chdir(self.working_dir.as_ptr()))
+        }
+    }
+}
+
+/// Opens `path` relative to `dir` as O_PATH fd along with `flags`, refusing paths which
+/// leave `dir`, symlinks included. Fd keeps referring to the same file when path is
+/// replaced afterwards
+pub fn open_beneath(dir: &Path, path: &Path, flags: c_int) -> io::Result<OwnedFd> {
+    let dir = open(
+        AT_FDCWD,
+        &CString::new(dir.as_os_str().as_bytes())?,
+        O_DIRECTORY,
+        0,
+    )?;
+    open(
+        dir.as_raw_fd(),
+        &CString::new(path.as_os_str().as_bytes())?,
+        flags,
+        RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS,
+    )
+}
+
+fn cstring(path: &Path) -> Result<CString, Error> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::Nul(path.to_owned()))
+}
+
+// Path within rootfs relative to its root, can't escape it lexically
+fn relative(path: &Path) -> Result<&Path, Error> {
+    let is_valid = path.is_absolute()
+        && path
+            .components()
+            .all(|component| !matches!(component, Component::ParentDir));
+
+    path.strip_prefix("/")
+        .ok()
+        .filter(|_| is_valid)
+        .ok_or_else(|| Error::InvalidPath(path.to_owned()))
+}
+
+fn check(res: c_int) -> io::Result<()> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Fd returned by syscall, which takes its ownership
+fn owned(res: libc::c_long) -> io::Result<OwnedFd> {
+    check(res as c_int)?;
+    // Safety: fd has just been opened and is not owned elsewhere
+    Ok(unsafe { OwnedFd::from_raw_fd(res as RawFd) })
+}
+
+fn open(dir: RawFd, path: &CStr, flags: c_int, resolve: u64) -> io::Result<OwnedFd> {
+    // Safety: open_how is plain data, zeroed value is valid
+    let mut how: open_how = unsafe { std::mem::zeroed() };
+    how.flags = (O_PATH | O_CLOEXEC | flags) as u64;
+    how.resolve = resolve;
+    // Safety: path and how outlive the call
+    owned(unsafe {
+        libc::syscall(
+            SYS_openat2,
+            dir,
+            path.as_ptr(),
+            &how,
+            std::mem::size_of::<open_how>(),
+        )
+    })
+}
+
+// Path relative to `root` which is resolved as if it was /, without following symlinks
+fn open_in_root(root: &OwnedFd, path: &CStr, flags: c_int) -> io::Result<OwnedFd> {
+    open(
+        root.as_raw_fd(),
+        path,
+        flags,
+        RESOLVE_IN_ROOT | RESOLVE_NO_SYMLINKS,
+    )
+}
+
+// Nul-terminated /proc path of fd, mount follows it to the very file fd refers to
+struct FdPath([u8; 32]);
+
+impl FdPath {
+    fn new(fd: &OwnedFd) -> Self {
+        let mut path = [0; 32];
+        path[..PROC_SELF_FD.len()].copy_from_slice(PROC_SELF_FD);
+        serialize_i32(fd.as_raw_fd(), &mut path[PROC_SELF_FD.len()..])
+            .expect("Failed to fit fd into /proc path");
+        Self(path)
+    }
+
+    fn as_ptr(&self) -> *const libc::c_char {
+        self.0.as_ptr().cast()
+    }
+}
+
+fn mount(
+    source: &CStr,
+    target: &OwnedFd,
+    fstype: &CStr,
+    flags: c_ulong,
+    data: &CStr,
+) -> io::Result<()> {
+    let target = FdPath::new(target);
+    // Safety: strings outlive the call
+    check(unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            flags,
+            data.as_ptr() as *const c_void,
+        )
+    })
+}
+
+// Binds source along with its submounts onto target, returns root of the new mount
+fn bind(source: &OwnedFd, target: &OwnedFd) -> io::Result<OwnedFd> {
+    // Safety: path outlives the calls
+    unsafe {
+        let tree = owned(libc::syscall(
+            SYS_open_tree,
+            source.as_raw_fd(),
+            c"".as_ptr(),
+            OPEN_TREE_CLONE | O_CLOEXEC as c_uint | (AT_EMPTY_PATH | AT_RECURSIVE) as c_uint,
+        ))?;
+        check(libc::syscall(
+            SYS_move_mount,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            target.as_raw_fd(),
+            c"".as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH | MOVE_MOUNT_T_EMPTY_PATH,
+        ) as c_int)?;
+        Ok(tree)
+    }
+}
+
+// Changes flags of mount rooted at target
+fn remount(target: &OwnedFd, flags: c_ulong) -> io::Result<()> {
+    let target = FdPath::new(target);
+    // Safety: string outlives the call
+    check(unsafe {
+        libc::mount(
+            null(),
+            target.as_ptr(),
+            null(),
+            MS_BIND | MS_REMOUNT | flags,
+            null(),
+        )
+    })
+}
+
+fn stat(fd: &OwnedFd) -> io::Result<libc::stat> {
+    // Safety: stat is plain data, zeroed value is valid
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    // Safety: stat outlives the call
+    check(unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) })?;
+    Ok(stat)
+}
+
+fn is_dir(fd: &OwnedFd) -> io::Result<bool> {
+    Ok(stat(fd)?.st_mode & S_IFMT == S_IFDIR)
+}
+
+// Directory or file `name` within `parent` of rootfs, created unless it exists. Bind mount
+// point has to be of the same kind as its source. Neither it nor any of its parents
+// may be a symlink
+fn mount_point(root: &OwnedFd, parent: &CStr, name: &CStr, dir: bool) -> io::Result<OwnedFd> {
+    let parent = open_in_root(root, parent, O_DIRECTORY)?;
+    // Safety: name outlives the call
+    let created = check(unsafe {
+        if dir {
+            libc::mkdirat(parent.as_raw_fd(), name.as_ptr(), 0o755)
+        } else {
+            libc::mknodat(parent.as_raw_fd(), name.as_ptr(), S_IFREG | 0o644, 0)
+        }
+    });
+    match created {
+        Err(err) if err.raw_os_error() != Some(EEXIST) => return Err(err),
+        _ => (),
+    }
+
+    open_in_root(&parent, name, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn given_path_escaping_rootfs_then_it_is_rejected() {
+        assert!(relative(Path::new("/usr/../../etc")).is_err());
+        assert!(relative(Path::new("etc")).is_err());
+        assert_eq!(
+            relative(Path::new("/usr/./lib")).unwrap(),
+            Path::new("usr/lib")
+        );
+    }
+
+    #[test]
+    fn given_symlink_in_rootfs_then_mount_point_is_not_created_outside() {
+        let dir = temp_dir("rootfs-symlink");
+        std::fs::create_dir_all(dir.join("host")).unwrap();
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+        std::os::unix::fs::symlink(dir.join("host"), dir.join("root/etc")).unwrap();
+        std::os::unix::fs::symlink(dir.join("host"), dir.join("root/dev")).unwrap();
+        let root = open_beneath(&dir, Path::new("root"), O_DIRECTORY).unwrap();
+
+        assert!(mount_point(&root, c"etc", c"nologin", false).is_err());
+        assert!(mount_point(&root, c".", c"dev", true).is_err());
+        assert!(mount_point(&root, c".", c"etc", true).is_err());
+        assert_eq!(std::fs::read_dir(dir.join("host")).unwrap().count(), 0);
+        assert!(mount_point(&root, c".", c"proc", true).is_ok());
+        assert!(dir.join("root/proc").is_dir());
+    }
+}
@@ -0,0 +1,745 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    mem::{size_of, zeroed},
+    os::unix::prelude::{AsRawFd, FromRawFd, RawFd},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::error;
+use nix::libc::{
+    self, c_int, c_long, c_uint, c_void, iovec, msghdr, pollfd, seccomp_notif, seccomp_notif_resp,
+    sock_filter, sock_fprog, SYS_seccomp, AF_UNIX, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_JSET, BPF_K,
+    BPF_LD, BPF_RET, BPF_W, CLONE_NEWCGROUP, CLONE_NEWIPC, CLONE_NEWNET, CLONE_NEWNS, CLONE_NEWPID,
+    CLONE_NEWUSER, CLONE_NEWUTS, ENOENT, ENOSYS, EPERM, MSG_CMSG_CLOEXEC, MSG_DONTWAIT, POLLHUP,
+    POLLIN, PR_SET_NO_NEW_PRIVS, SCM_RIGHTS, SECCOMP_FILTER_FLAG_NEW_LISTENER,
+    SECCOMP_IOCTL_NOTIF_RECV, SECCOMP_IOCTL_NOTIF_SEND, SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO,
+    SECCOMP_RET_KILL_PROCESS, SECCOMP_RET_USER_NOTIF, SECCOMP_SET_MODE_FILTER, SOL_SOCKET,
+};
+use prost::Message;
+use thiserror::Error;
+use tokio::io::{unix::AsyncFd, Interest};
+
+use crate::runner::{self, job_request};
+
+macro_rules! syscalls {
+    ($($nr:ident),* $(,)?) => {
+        &[$((stringify!($nr), libc::$nr)),*]
+    };
+}
+
+type Syscalls = &'static [(&'static str, c_long)];
+
+// Denied by every profile, as they reach beyond the job itself
+const PRIVILEGED: Syscalls = syscalls![
+    SYS_acct,
+    SYS_add_key,
+    SYS_adjtimex,
+    SYS_bpf,
+    SYS_clock_adjtime,
+    SYS_clock_settime,
+    SYS_delete_module,
+    SYS_finit_module,
+    SYS_fsconfig,
+    SYS_fsmount,
+    SYS_fsopen,
+    SYS_fspick,
+    SYS_init_module,
+    SYS_io_uring_setup,
+    SYS_kexec_file_load,
+    SYS_kexec_load,
+    SYS_keyctl,
+    SYS_lookup_dcookie,
+    SYS_mount,
+    SYS_move_mount,
+    SYS_name_to_handle_at,
+    SYS_open_by_handle_at,
+    SYS_open_tree,
+    SYS_perf_event_open,
+    SYS_pivot_root,
+    SYS_process_vm_readv,
+    SYS_process_vm_writev,
+    SYS_ptrace,
+    SYS_quotactl,
+    SYS_reboot,
+    SYS_request_key,
+    SYS_setdomainname,
+    SYS_sethostname,
+    SYS_setns,
+    SYS_settimeofday,
+    SYS_swapoff,
+    SYS_swapon,
+    SYS_syslog,
+    SYS_umount2,
+    SYS_unshare,
+    SYS_userfaultfd,
+    SYS_vhangup,
+];
+
+#[cfg(target_arch = "x86_64")]
+const PRIVILEGED_ARCH: Syscalls = syscalls![SYS_iopl, SYS_ioperm, SYS_uselib];
+#[cfg(not(target_arch = "x86_64"))]
+const PRIVILEGED_ARCH: Syscalls = &[];
+
+// The only ones allowed by strict profile, enough for simple
+// single process programs to read and write files they have at hand
+const BASIC: Syscalls = syscalls![
+    SYS_brk,
+    SYS_chdir,
+    SYS_clock_getres,
+    SYS_clock_gettime,
+    SYS_clock_nanosleep,
+    SYS_close,
+    SYS_dup,
+    SYS_dup3,
+    SYS_execve,
+    SYS_exit,
+    SYS_exit_group,
+    SYS_faccessat,
+    SYS_faccessat2,
+    SYS_fcntl,
+    SYS_fstat,
+    SYS_futex,
+    SYS_getcwd,
+    SYS_getdents64,
+    SYS_getegid,
+    SYS_geteuid,
+    SYS_getgid,
+    SYS_getpgid,
+    SYS_getpid,
+    SYS_getppid,
+    SYS_getrandom,
+    SYS_gettid,
+    SYS_gettimeofday,
+    SYS_getuid,
+    SYS_ioctl,
+    SYS_kill,
+    SYS_lseek,
+    SYS_madvise,
+    SYS_mmap,
+    SYS_mprotect,
+    SYS_mremap,
+    SYS_munmap,
+    SYS_nanosleep,
+    SYS_newfstatat,
+    SYS_openat,
+    SYS_pipe2,
+    SYS_ppoll,
+    SYS_pread64,
+    SYS_prlimit64,
+    SYS_pselect6,
+    SYS_pwrite64,
+    SYS_read,
+    SYS_readlinkat,
+    SYS_readv,
+    SYS_rseq,
+    SYS_rt_sigaction,
+    SYS_rt_sigprocmask,
+    SYS_rt_sigreturn,
+    SYS_sched_getaffinity,
+    SYS_sched_yield,
+    SYS_set_robust_list,
+    SYS_set_tid_address,
+    SYS_setsid,
+    SYS_sigaltstack,
+    SYS_statx,
+    SYS_sysinfo,
+    SYS_tgkill,
+    SYS_umask,
+    SYS_uname,
+    SYS_wait4,
+    SYS_write,
+    SYS_writev,
+];
+
+#[cfg(target_arch = "x86_64")]
+const BASIC_ARCH: Syscalls = syscalls![
+    SYS_access,
+    SYS_arch_prctl,
+    SYS_dup2,
+    SYS_getpgrp,
+    SYS_lstat,
+    SYS_open,
+    SYS_pipe,
+    SYS_poll,
+    SYS_readlink,
+    SYS_select,
+    SYS_stat,
+    SYS_time,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const BASIC_ARCH: Syscalls = &[];
+
+// Made by the child once filter is installed, before exec. Denying them would
+// leave the child waiting for the server, which waits for the exec
+const INSTALL: Syscalls = syscalls![SYS_sendmsg];
+
+// Named in reported violations, along with the ones above
+const REPORTED: Syscalls = syscalls![
+    SYS_bind,
+    SYS_clone,
+    SYS_clone3,
+    SYS_connect,
+    SYS_fchmodat,
+    SYS_fchownat,
+    SYS_fstatfs,
+    SYS_linkat,
+    SYS_mkdirat,
+    SYS_renameat2,
+    SYS_setgid,
+    SYS_setgroups,
+    SYS_setuid,
+    SYS_socket,
+    SYS_statfs,
+    SYS_symlinkat,
+    SYS_unlinkat,
+];
+
+#[cfg(target_arch = "x86_64")]
+const REPORTED_ARCH: Syscalls = syscalls![
+    SYS_chmod,
+    SYS_chown,
+    SYS_creat,
+    SYS_fork,
+    SYS_link,
+    SYS_mkdir,
+    SYS_rename,
+    SYS_rmdir,
+    SYS_symlink,
+    SYS_unlink,
+    SYS_vfork,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const REPORTED_ARCH: Syscalls = &[];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// x32 syscalls have this bit set on x86_64, they are denied altogether
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Flags of `clone` creating namespaces, denied like `unshare`. Time namespace is left
+// out, its flag overlaps the exit signal of `clone`
+const NAMESPACE_FLAGS: c_int = CLONE_NEWCGROUP
+    | CLONE_NEWIPC
+    | CLONE_NEWNET
+    | CLONE_NEWNS
+    | CLONE_NEWPID
+    | CLONE_NEWUSER
+    | CLONE_NEWUTS;
+
+// Offsets within `seccomp_data`
+const NR: u32 = 0;
+const ARCH: u32 = 4;
+// Low half of the first argument on little endian
+const ARG0: u32 = 16;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unknown seccomp profile {0}")]
+    UnknownProfile(String),
+}
+
+/// Named set of syscalls job is allowed, in order of strictness
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Profile {
+    /// Denies syscalls affecting the host rather than the job
+    Default,
+    /// Default, as well as sockets other than unix ones
+    NoNetwork,
+    /// Allows basic syscalls only
+    Strict,
+}
+
+impl TryFrom<&str> for Profile {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "default" => Ok(Profile::Default),
+            "no-network" => Ok(Profile::NoNetwork),
+            "strict" => Ok(Profile::Strict),
+            _ => Err(Error::UnknownProfile(value.to_string())),
+        }
+    }
+}
+
+/// Taken once job invokes syscall denied by its profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    /// Syscall fails with EPERM, once server answers its notification
+    Errno,
+    /// Process invoking syscall is killed by the kernel with SIGSYS
+    Kill,
+}
+
+impl From<job_request::seccomp::Action> for Action {
+    fn from(value: job_request::seccomp::Action) -> Self {
+        match value {
+            job_request::seccomp::Action::Errno => Action::Errno,
+            job_request::seccomp::Action::Kill => Action::Kill,
+        }
+    }
+}
+
+/// Profile and action of the job, or of the client as a floor for its jobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub profile: Profile,
+    pub action: Action,
+}
+
+impl TryFrom<&job_request::Seccomp> for Policy {
+    type Error = Error;
+
+    fn try_from(value: &job_request::Seccomp) -> Result<Self, Self::Error> {
+        Ok(Self {
+            profile: value.profile.as_str().try_into()?,
+            action: value.action().into(),
+        })
+    }
+}
+
+impl Policy {
+    /// Job can't weaken policy of its client, the stricter profile and action win
+    pub fn within(&self, floor: &Policy) -> Policy {
+        Policy {
+            profile: self.profile.max(floor.profile),
+            action: self.action.max(floor.action),
+        }
+    }
+}
+
+/// File in job directory violations of running job are persisted in,
+/// so that job adopted after restart keeps them
+pub const VIOLATIONS_FILE: &str = "violations";
+
+/// Syscalls denied to the job so far, by number
+#[derive(Debug, Clone, Default)]
+pub struct Violations(Arc<Mutex<BTreeMap<i32, u64>>>);
+
+impl From<&[runner::SeccompViolation]> for Violations {
+    fn from(value: &[runner::SeccompViolation]) -> Self {
+        let violations = value
+            .iter()
+            .map(|violation| (violation.syscall, violation.count))
+            .collect();
+        Self(Arc::new(Mutex::new(violations)))
+    }
+}
+
+impl Violations {
+    fn record(&self, syscall: i32) {
+        *self.0.lock().unwrap().entry(syscall).or_default() += 1;
+    }
+
+    /// Reads violations persisted in job directory, none if there are none
+    pub async fn load(job_dir: &Path) -> Self {
+        match tokio::fs::read(job_dir.join(VIOLATIONS_FILE)).await {
+            Ok(encoded) => runner::JobStatus::decode(encoded.as_slice())
+                .map(|status| status.seccomp_violations.as_slice().into())
+                .unwrap_or_default(),
+            Err(_) => Self::default(),
+        }
+    }
+
+    // Replaced atomically, as metadata is
+    async fn persist(&self, job_dir: &Path) -> std::io::Result<()> {
+        let status = runner::JobStatus {
+            seccomp_violations: self.to_vec(),
+            ..Default::default()
+        };
+        let partial = job_dir.join(format!("{}.partial", VIOLATIONS_FILE));
+        tokio::fs::write(&partial, status.encode_to_vec()).await?;
+        tokio::fs::rename(&partial, job_dir.join(VIOLATIONS_FILE)).await
+    }
+
+    pub fn to_vec(&self) -> Vec<runner::SeccompViolation> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(syscall, count)| runner::SeccompViolation {
+                syscall: *syscall,
+                name: name(*syscall).unwrap_or_default().to_string(),
+                count: *count,
+            })
+            .collect()
+    }
+}
+
+// Name of syscall, if it is known to any of the profiles
+fn name(syscall: i32) -> Option<&'static str> {
+    [
+        PRIVILEGED,
+        PRIVILEGED_ARCH,
+        BASIC,
+        BASIC_ARCH,
+        INSTALL,
+        REPORTED,
+        REPORTED_ARCH,
+    ]
+    .iter()
+    .flat_map(|syscalls| syscalls.iter())
+    .find(|(_, nr)| *nr == syscall as c_long)
+    .map(|(name, _)| name.trim_start_matches("SYS_"))
+}
+
+/// BPF program of the profile, compiled before spawn and installed by pre_exec closure.
+/// Kill action is taken by the kernel itself, so it holds without the server. Errno one
+/// is taken by the server, through filter listener, as notifications can't be combined
+/// with other actions and denied syscalls would go unreported otherwise
+#[derive(Debug, Clone)]
+pub struct Filter {
+    program: Vec<sock_filter>,
+    action: Action,
+}
+
+impl From<Policy> for Filter {
+    fn from(policy: Policy) -> Self {
+        let denied = match policy.action {
+            Action::Errno => SECCOMP_RET_USER_NOTIF,
+            Action::Kill => SECCOMP_RET_KILL_PROCESS,
+        };
+        let mut program = vec![
+            load(ARCH),
+            jump(AUDIT_ARCH, 1, 0),
+            ret(SECCOMP_RET_KILL_PROCESS),
+            load(NR),
+        ];
+
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            sock_filter {
+                code: (BPF_JMP | 0x30 | BPF_K) as u16, // BPF_JGE
+                jt: 0,
+                jf: 1,
+                k: X32_SYSCALL_BIT,
+            },
+            ret(denied),
+        ]);
+
+        let (listed, listed_action, otherwise): (&[Syscalls], _, _) = match policy.profile {
+            Profile::Default => {
+                program.extend(clone(denied));
+                (&[PRIVILEGED, PRIVILEGED_ARCH], denied, SECCOMP_RET_ALLOW)
+            }
+            Profile::NoNetwork => {
+                program.extend(clone(denied));
+                // Sockets other than unix ones are denied
+                program.extend([
+                    jump(libc::SYS_socket as u32, 0, 4),
+                    load(ARG0),
+                    jump(AF_UNIX as u32, 0, 1),
+                    ret(SECCOMP_RET_ALLOW),
+                    ret(denied),
+                ]);
+                (&[PRIVILEGED, PRIVILEGED_ARCH], denied, SECCOMP_RET_ALLOW)
+            }
+            Profile::Strict => (&[BASIC, BASIC_ARCH, INSTALL], SECCOMP_RET_ALLOW, denied),
+        };
+
+        for (_, nr) in listed.iter().flat_map(|syscalls| syscalls.iter()) {
+            program.extend([jump(*nr as u32, 0, 1), ret(listed_action)]);
+        }
+        program.push(ret(otherwise));
+
+        Self {
+            program,
+            action: policy.action,
+        }
+    }
+}
+
+impl Filter {
+    /// Installs filter in calling process, then passes fd of filter listener over `sock`
+    /// if filter has one. Called by pre_exec closure, so everything here is async-signal-safe
+    pub fn install(&self, sock: Option<RawFd>) -> std::io::Result<()> {
+        let prog = sock_fprog {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr() as *mut sock_filter,
+        };
+
+        // Safety: prog outlives the calls, which take no other pointers
+        let listener = unsafe {
+            // Required to install filter without CAP_SYS_ADMIN
+            if libc::prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            libc::syscall(
+                SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                if self.is_reported() {
+                    SECCOMP_FILTER_FLAG_NEW_LISTENER
+                } else {
+                    0
+                },
+                &prog as *const sock_fprog,
+            ) as c_int
+        };
+        if listener < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let Some(sock) = sock else {
+            return Ok(());
+        };
+
+        let res = send_fd(sock, listener);
+        // Safety: listener is closed once
+        unsafe { libc::close(listener) };
+        res
+    }
+
+    /// Whether denied syscalls are reported to the server, which answers them
+    pub fn is_reported(&self) -> bool {
+        self.action == Action::Errno
+    }
+}
+
+// Denies `clone` creating namespaces. Flags of `clone3` are behind a pointer filter
+// can't follow, so it fails as unimplemented and libc falls back to `clone`
+fn clone(denied: c_uint) -> [sock_filter; 7] {
+    [
+        jump(libc::SYS_clone as u32, 0, 4),
+        load(ARG0),
+        sock_filter {
+            code: (BPF_JMP | BPF_JSET | BPF_K) as u16,
+            jt: 0,
+            jf: 1,
+            k: NAMESPACE_FLAGS as u32,
+        },
+        ret(denied),
+        ret(SECCOMP_RET_ALLOW),
+        jump(libc::SYS_clone3 as u32, 0, 1),
+        ret(SECCOMP_RET_ERRNO | ENOSYS as c_uint),
+    ]
+}
+
+fn load(offset: u32) -> sock_filter {
+    sock_filter {
+        code: (BPF_LD | BPF_W | BPF_ABS) as u16,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+
+fn jump(value: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: (BPF_JMP | BPF_JEQ | BPF_K) as u16,
+        jt,
+        jf,
+        k: value,
+    }
+}
+
+fn ret(action: c_uint) -> sock_filter {
+    sock_filter {
+        code: (BPF_RET | BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: action,
+    }
+}
+
+// Room for control message carrying single fd, aligned as cmsghdr
+type FdBuf = [u64; 4];
+
+fn send_fd(sock: RawFd, fd: RawFd) -> std::io::Result<()> {
+    // Safety: buffers outlive the call, CMSG macros stay within cmsg buffer
+    unsafe {
+        let mut byte = 0u8;
+        let mut iov = iovec {
+            iov_base: &mut byte as *mut u8 as *mut c_void,
+            iov_len: 1,
+        };
+        let mut buf: FdBuf = zeroed();
+        let mut msg: msghdr = zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = buf.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(size_of::<c_int>() as c_uint) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_SOCKET;
+        (*cmsg).cmsg_type = SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<c_int>() as c_uint) as _;
+        *(libc::CMSG_DATA(cmsg) as *mut c_int) = fd;
+
+        if libc::sendmsg(sock, &msg, 0) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Receives listener sent by `Filter::install`, once job is spawned
+pub fn recv_listener(sock: RawFd) -> std::io::Result<File> {
+    // Safety: buffers outlive the call, CMSG macros stay within cmsg buffer
+    unsafe {
+        let mut byte = 0u8;
+        let mut iov = iovec {
+            iov_base: &mut byte as *mut u8 as *mut c_void,
+            iov_len: 1,
+        };
+        let mut buf: FdBuf = zeroed();
+        let mut msg: msghdr = zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = buf.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = size_of::<FdBuf>() as _;
+
+        if libc::recvmsg(sock, &mut msg, MSG_DONTWAIT | MSG_CMSG_CLOEXEC) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_type != SCM_RIGHTS {
+            return Err(std::io::Error::from_raw_os_error(ENOENT));
+        }
+
+        Ok(File::from_raw_fd(*(libc::CMSG_DATA(cmsg) as *const c_int)))
+    }
+}
+
+/// Answers notifications of the job filter until job exits: denied syscall fails with EPERM.
+/// Violations are recorded and persisted in job directory, so that they outlive the server
+pub fn watch(listener: File, violations: Violations, job_dir: PathBuf) -> std::io::Result<()> {
+    let listener = AsyncFd::with_interest(listener, Interest::READABLE)?;
+
+    tokio::spawn(async move {
+        loop {
+            let mut guard = match listener.readable().await {
+                Ok(guard) => guard,
+                Err(err) => {
+                    error!("Failed to wait for seccomp notification: {}", err);
+                    return;
+                }
+            };
+
+            // Readiness is edge triggered, so every pending notification is answered
+            // before waiting again. Receiving blocks unless one is pending
+            let mut answered = false;
+            loop {
+                let mut fd = pollfd {
+                    fd: listener.as_raw_fd(),
+                    events: POLLIN,
+                    revents: 0,
+                };
+                // Safety: fd outlives the call, zero timeout does not block
+                unsafe { libc::poll(&mut fd, 1, 0) };
+
+                if fd.revents & POLLIN != 0 {
+                    answered |= answer(listener.as_raw_fd(), &violations);
+                } else if fd.revents & POLLHUP != 0 {
+                    // Every process of the job has exited
+                    return;
+                } else {
+                    break;
+                }
+            }
+
+            guard.clear_ready();
+            if answered {
+                if let Err(err) = violations.persist(&job_dir).await {
+                    error!("Failed to persist seccomp violations: {}", err);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Whether notification was answered. Server is the only receiver, so once poll reports
+// one pending, receiving doesn't block: it fails if the process got killed meanwhile
+fn answer(listener: RawFd, violations: &Violations) -> bool {
+    // Safety: notifications are plain data, zeroed values are valid and are
+    // expected by the kernel; they outlive the calls
+    unsafe {
+        let mut notif: seccomp_notif = zeroed();
+        if libc::ioctl(listener, SECCOMP_IOCTL_NOTIF_RECV, &mut notif) < 0 {
+            return false;
+        }
+        violations.record(notif.data.nr);
+
+        let mut resp: seccomp_notif_resp = zeroed();
+        resp.id = notif.id;
+        resp.error = -EPERM;
+        libc::ioctl(listener, SECCOMP_IOCTL_NOTIF_SEND, &mut resp);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn given_client_policy_then_job_can_only_tighten_it() {
+        let client = Policy {
+            profile: Profile::NoNetwork,
+            action: Action::Errno,
+        };
+        let job = Policy {
+            profile: Profile::Default,
+            action: Action::Kill,
+        };
+
+        assert_eq!(
+            job.within(&client),
+            Policy {
+                profile: Profile::NoNetwork,
+                action: Action::Kill
+            }
+        );
+        assert!(Profile::try_from("permissive").is_err());
+    }
+
+    #[test]
+    fn given_violations_then_known_syscalls_are_named() {
+        let violations = Violations::default();
+        violations.record(libc::SYS_mount as i32);
+        violations.record(libc::SYS_mount as i32);
+
+        let reported = violations.to_vec();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].name, "mount");
+        assert_eq!(reported[0].count, 2);
+    }
+
+    #[test]
+    fn given_kill_action_then_filter_kills_without_listener() {
+        let filter = Filter::from(Policy {
+            profile: Profile::Default,
+            action: Action::Kill,
+        });
+
+        assert!(!filter.is_reported());
+        assert!(filter
+            .program
+            .iter()
+            .all(|insn| insn.k != SECCOMP_RET_USER_NOTIF));
+        assert!(filter.program.iter().any(
+            |insn| insn.code == (BPF_RET | BPF_K) as u16 && insn.k == SECCOMP_RET_KILL_PROCESS
+        ));
+    }
+
+    #[tokio::test]
+    async fn given_persisted_violations_then_they_are_loaded_back() {
+        let dir = temp_dir("violations");
+        let violations = Violations::default();
+        violations.record(libc::SYS_mount as i32);
+
+        violations.persist(&dir).await.unwrap();
+        assert_eq!(Violations::load(&dir).await.to_vec(), violations.to_vec());
+    }
+}
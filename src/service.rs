@@ -0,0 +1,384 @@
+// Handlers of tonic services fail with Status, helpers feeding them do as well
+#![allow(clippy::result_large_err)]
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    pin::Pin,
+    sync::{Arc, RwLock, RwLockReadGuard},
+};
+
+use log::error;
+use tokio::sync::Mutex;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+use uuid::Uuid;
+
+use crate::{
+    auth::Caller,
+    controller::{self, Controller, OutputOptions, Settings},
+    job::{Input, Job, Started, Tty},
+    runner::{
+        attach_input, job_runner_server::JobRunner, Ack, AttachInput, AttachOutput, DiskStats,
+        JobId, JobRequest, JobStatus, LogMessage, OutputRequest, StatsRequest, StdinMessage,
+    },
+};
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+type SharedController = Arc<Mutex<Controller<'static, Job<Started>>>>;
+
+/// Job runner serving clients authenticated through mTLS, each of them identified
+/// by `O`rganization of its certificate and limited to jobs of its own.
+/// Clones share clients
+#[derive(Clone, Default)]
+pub struct Service {
+    clients: Arc<RwLock<HashMap<String, Client>>>,
+}
+
+struct Client {
+    controller: SharedController,
+    // Clients removed from config are refused, while their jobs are still supervised
+    enabled: bool,
+}
+
+/// Clients provisioned and removed by `Service::reload`
+#[derive(Debug, Default, PartialEq)]
+pub struct Reloaded {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl Service {
+    /// Provisions every client, restoring jobs which outlived previous server
+    pub async fn new(clients: &BTreeMap<String, Settings>) -> Result<Self, controller::Error> {
+        let service = Self::default();
+        service.reload(clients).await?;
+
+        Ok(service)
+    }
+
+    /// Provisions clients which are new, updates settings of known ones and refuses
+    /// clients which are gone. Running jobs are not disturbed
+    pub async fn reload(
+        &self,
+        clients: &BTreeMap<String, Settings>,
+    ) -> Result<Reloaded, controller::Error> {
+        let mut reloaded = Reloaded::default();
+        let known: HashMap<String, (SharedController, bool)> = self
+            .clients()
+            .iter()
+            .map(|(name, client)| (name.clone(), (client.controller.clone(), client.enabled)))
+            .collect();
+
+        let mut provisioned = Vec::new();
+        for (name, settings) in clients {
+            match known.get(name) {
+                Some((controller, enabled)) => {
+                    controller.lock().await.update(settings.clone()).await?;
+                    if !enabled {
+                        reloaded.added.push(name.clone());
+                    }
+                }
+                None => {
+                    // Controllers borrow name of their client, and live as long as the server
+                    let client: &'static str = Box::leak(name.clone().into_boxed_str());
+                    let controller = Controller::with_settings(client, settings.clone()).await?;
+                    provisioned.push((name.clone(), Arc::new(Mutex::new(controller))));
+                    reloaded.added.push(name.clone());
+                }
+            }
+        }
+
+        let mut current = self.clients.write().expect("Poisoned clients");
+        for (name, controller) in provisioned {
+            current.insert(
+                name,
+                Client {
+                    controller,
+                    enabled: true,
+                },
+            );
+        }
+        for (name, client) in current.iter_mut() {
+            let enabled = clients.contains_key(name);
+            if client.enabled && !enabled {
+                reloaded.removed.push(name.clone());
+            }
+            client.enabled = enabled;
+        }
+        reloaded.added.sort();
+        reloaded.removed.sort();
+
+        Ok(reloaded)
+    }
+
+    /// Removes completed jobs of every client past their retention
+    pub async fn prune(&self) {
+        let controllers: Vec<SharedController> = self
+            .clients()
+            .values()
+            .map(|client| client.controller.clone())
+            .collect();
+        for controller in controllers {
+            controller.lock().await.prune().await;
+        }
+    }
+
+    fn clients(&self) -> RwLockReadGuard<'_, HashMap<String, Client>> {
+        self.clients.read().expect("Poisoned clients")
+    }
+
+    // Controller of client the call is about, as authorized by `auth::Authorized`
+    fn controller<T>(&self, request: &Request<T>) -> Result<SharedController, Status> {
+        let caller = request
+            .extensions()
+            .get::<Caller>()
+            .ok_or_else(|| Status::unauthenticated("Caller is not authorized"))?;
+
+        match self.clients().get(&caller.client) {
+            Some(client) if client.enabled => Ok(client.controller.clone()),
+            _ => Err(Status::permission_denied(format!(
+                "Unknown client {}",
+                caller.client
+            ))),
+        }
+    }
+}
+
+// Forwards input of WriteStdin to the job named by its first message
+async fn write_stdin<S>(controller: &SharedController, mut messages: S) -> Result<(), Status>
+where
+    S: Stream<Item = Result<StdinMessage, Status>> + Unpin,
+{
+    let first = messages
+        .next()
+        .await
+        .transpose()?
+        .ok_or_else(|| Status::invalid_argument("Job id is required"))?;
+    let job_id = job_id(first.jobid.as_ref())?;
+    let stdin = controller.lock().await.stdin(job_id).await?;
+
+    let mut message = Some(first);
+    while let Some(StdinMessage { input, eof, .. }) = message {
+        if !input.is_empty() && stdin.send(Input::Data(input)).await.is_err() {
+            return Err(controller::Error::StdinClosed(job_id).into());
+        }
+        if eof {
+            // Job may have closed its stdin already, which is what eof asks for anyway
+            let _ = stdin.send(Input::Eof).await;
+            break;
+        }
+        message = messages.next().await.transpose()?;
+    }
+
+    Ok(())
+}
+
+fn job_id(job_id: Option<&JobId>) -> Result<Uuid, Status> {
+    job_id
+        .and_then(|job_id| Uuid::from_slice(&job_id.jobid).ok())
+        .ok_or_else(|| Status::invalid_argument("Invalid job id"))
+}
+
+impl From<controller::Error> for Status {
+    fn from(value: controller::Error) -> Self {
+        use controller::Error;
+
+        match value {
+            Error::JobNotFound(_) => Status::not_found(value.to_string()),
+            Error::StdinClosed(_) | Error::NotATty(_) | Error::OutputLost(_) => {
+                Status::failed_precondition(value.to_string())
+            }
+            Error::InvalidWorkingDir(_)
+            | Error::InvalidRootfs(_)
+            | Error::Unpack(_)
+            | Error::Rootfs(_)
+            | Error::LogLimit(_)
+            | Error::Seccomp(_)
+            | Error::Rlimit(_)
+            | Error::InvalidPattern(_)
+            | Error::InvalidOffset(_)
+            | Error::Cgroup(crate::cgroup::Error::InvalidCpuWeight(_)) => {
+                Status::invalid_argument(value.to_string())
+            }
+            Error::Uid(_) => Status::resource_exhausted(value.to_string()),
+            _ => {
+                error!("Request failed: {}", value);
+                Status::internal("Internal error")
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl JobRunner for Service {
+    async fn start(&self, request: Request<JobRequest>) -> Result<Response<JobId>, Status> {
+        let controller = self.controller(&request)?;
+        // Controller is held only around the quick parts, so that other calls
+        // of the client don't wait for rootfs to unpack
+        let launch = controller.lock().await.launch(request.into_inner())?;
+        let launched = launch.start().await?;
+        let job_id = controller.lock().await.register(launched);
+
+        Ok(Response::new(JobId {
+            jobid: job_id.as_bytes().to_vec(),
+        }))
+    }
+
+    async fn stop(&self, request: Request<JobId>) -> Result<Response<Ack>, Status> {
+        let controller = self.controller(&request)?;
+        let job_id = job_id(Some(request.get_ref()))?;
+
+        let ack = controller.lock().await.stop(job_id).await;
+        Ok(Response::new(ack))
+    }
+
+    async fn status(&self, request: Request<JobId>) -> Result<Response<JobStatus>, Status> {
+        let controller = self.controller(&request)?;
+        let job_id = job_id(Some(request.get_ref()))?;
+
+        let status = controller.lock().await.status(job_id).await?;
+        Ok(Response::new(status))
+    }
+
+    type OutputStream = ResponseStream<LogMessage>;
+
+    async fn output(
+        &self,
+        request: Request<OutputRequest>,
+    ) -> Result<Response<Self::OutputStream>, Status> {
+        let controller = self.controller(&request)?;
+        let job_id = job_id(request.get_ref().jobid.as_ref())?;
+        let options = OutputOptions::try_from(request.get_ref())?;
+        let output = controller.lock().await.output(job_id, options).await?;
+
+        let output = ReceiverStream::new(output).map(|message| message.map_err(Status::from));
+        Ok(Response::new(Box::pin(output)))
+    }
+
+    async fn write_stdin(
+        &self,
+        request: Request<Streaming<StdinMessage>>,
+    ) -> Result<Response<Ack>, Status> {
+        let controller = self.controller(&request)?;
+        write_stdin(&controller, request.into_inner()).await?;
+
+        Ok(Response::new(Ack {}))
+    }
+
+    type AttachStream = ResponseStream<AttachOutput>;
+
+    async fn attach(
+        &self,
+        request: Request<Streaming<AttachInput>>,
+    ) -> Result<Response<Self::AttachStream>, Status> {
+        let controller = self.controller(&request)?;
+        let mut inputs = request.into_inner();
+        let job_id = match inputs.message().await? {
+            Some(AttachInput {
+                input: Some(attach_input::Input::Jobid(job_id)),
+            }) => self::job_id(Some(&job_id))?,
+            _ => return Err(Status::invalid_argument("Job id is required")),
+        };
+        let (stdin, output) = controller.lock().await.attach(job_id).await?;
+
+        // Input is forwarded until caller or job goes away
+        tokio::spawn(async move {
+            while let Ok(Some(AttachInput { input: Some(input) })) = inputs.message().await {
+                let input = match input {
+                    attach_input::Input::Keys(keys) => Input::Data(keys),
+                    attach_input::Input::Resize(size) => Input::Resize(Tty::from(&size)),
+                    attach_input::Input::Jobid(_) => continue,
+                };
+                if stdin.send(input).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let output = ReceiverStream::new(output).map(|message| {
+            message
+                .map(|message| AttachOutput {
+                    output: message.output,
+                })
+                .map_err(Status::from)
+        });
+        Ok(Response::new(Box::pin(output)))
+    }
+
+    async fn stats(&self, request: Request<StatsRequest>) -> Result<Response<DiskStats>, Status> {
+        let controller = self.controller(&request)?;
+
+        let stats = controller.lock().await.stats();
+        Ok(Response::new(stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::sync::Mutex;
+    use tonic::{Code, Status};
+
+    use super::write_stdin;
+    use crate::{
+        controller::{Controller, Settings},
+        runner::{job_status::Outcome, JobId, JobRequest, StdinMessage},
+        testing::{own_cgroup_dir, temp_dir},
+    };
+
+    #[tokio::test]
+    async fn given_stdin_messages_then_they_are_written_until_eof() {
+        let settings = Settings {
+            storage_root: temp_dir("write-stdin"),
+            cgroup_root: own_cgroup_dir().await,
+            ..Default::default()
+        };
+        let mut controller = Controller::unprovisioned("write-stdin", settings);
+        let job_id = controller
+            .start(JobRequest {
+                executable: "/bin/cat".into(),
+                keep_stdin_open: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut output = controller.output(job_id, Default::default()).await.unwrap();
+        let controller = Arc::new(Mutex::new(controller));
+
+        let missing = tokio_stream::iter(vec![Ok::<_, Status>(StdinMessage::default())]);
+        let err = write_stdin(&controller, missing).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let messages = tokio_stream::iter(vec![
+            Ok(StdinMessage {
+                jobid: Some(JobId {
+                    jobid: job_id.as_bytes().to_vec(),
+                }),
+                input: b"one\n".to_vec(),
+                ..Default::default()
+            }),
+            Ok(StdinMessage {
+                input: b"two\n".to_vec(),
+                eof: true,
+                ..Default::default()
+            }),
+        ]);
+        write_stdin(&controller, messages).await.unwrap();
+
+        let mut bytes = Vec::new();
+        while let Some(message) = tokio::time::timeout(Duration::from_secs(5), output.recv())
+            .await
+            .unwrap()
+        {
+            bytes.extend(message.unwrap().output);
+        }
+        assert_eq!(bytes, b"one\ntwo\n");
+        let status = controller.lock().await.status(job_id).await.unwrap();
+        assert_eq!(status.outcome, Some(Outcome::ExitCode(0)));
+
+        let cgroup_dir = own_cgroup_dir().await.join("write-stdin");
+        let _ = std::fs::remove_dir(cgroup_dir.join(job_id.to_simple().to_string()));
+        let _ = std::fs::remove_dir(cgroup_dir);
+    }
+}
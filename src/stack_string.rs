@@ -3,8 +3,8 @@ use thiserror::Error;
 
 /// As per [fork(2)](https://man7.org/linux/man-pages/man2/fork.2.html) only async-signal-safe
 /// functions should be called after `fork` until `execve` is called.
-/// To ensure no allocations are done in `Command::pre_exec` stack-backed string is used for:
-/// - Transferring ownership of constructed cgroup Path to closure
+/// To ensure no allocations are done in the child before exec stack-backed string is used for:
+/// - Transferring ownership of constructed cgroup Path to the child
 /// - Writing Pid of the child process as String to cgroup.procs
 #[derive(Copy, Clone, Debug)]
 pub struct String<const N: usize> {
@@ -0,0 +1,267 @@
+use std::{
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use log::{info, warn};
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile, AllowAnyAuthenticatedClient, Certificate, ClientCertVerified,
+        ClientCertVerifier, DistinguishedNames, PrivateKey, RootCertStore, ServerConfig, TLSError,
+    },
+    server::TlsStream,
+    webpki::DNSName,
+    TlsAcceptor,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{
+    auth,
+    config::Tls,
+    crl::{self, Crl},
+};
+
+const ALPN_H2: &[u8] = b"h2";
+// Connections which do not complete handshake in time are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const BACKLOG: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("No certificate in {0}")]
+    NoCertificate(PathBuf),
+    #[error("No PKCS8 or RSA private key in {0}")]
+    NoKey(PathBuf),
+    #[error("Invalid CA certificate in {0}")]
+    InvalidCa(PathBuf),
+    #[error("Invalid server identity: {0}")]
+    Identity(#[from] TLSError),
+    #[error(transparent)]
+    Crl(#[from] crl::Error),
+}
+
+/// Server config along with CRL its client certificate verifier checks against
+#[derive(Clone)]
+pub struct Material {
+    config: Arc<ServerConfig>,
+    crl: Option<Crl>,
+}
+
+/// Accepts mTLS connections with server identity, client CA and CRL which can be
+/// replaced while the server runs. Handshakes in progress finish with material they
+/// started with. Clones share material
+#[derive(Clone)]
+pub struct Acceptor {
+    material: Arc<RwLock<Material>>,
+}
+
+impl Acceptor {
+    pub fn new(material: Material) -> Self {
+        Self {
+            material: Arc::new(RwLock::new(material)),
+        }
+    }
+
+    /// Material of connections accepted from now on
+    pub fn replace(&self, material: Material) {
+        *self.material.write().expect("Poisoned TLS material") = material;
+    }
+
+    /// Re-reads CRL of current material, if it has one, keeping revoked certificates
+    /// as they were unless it is valid
+    pub async fn reload_crl(&self) -> Result<(), crl::Error> {
+        match self.current().crl {
+            Some(crl) => crl.reload().await,
+            None => Ok(()),
+        }
+    }
+
+    /// Connections accepted on every listener, each of them handshaken on its own
+    /// so a slow client does not hold up others
+    pub fn incoming(
+        &self,
+        listeners: Vec<TcpListener>,
+    ) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+        let (tx, rx) = mpsc::channel(BACKLOG);
+        for listener in listeners {
+            let acceptor = self.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, peer) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            warn!("Failed to accept connection: {}", err);
+                            continue;
+                        }
+                    };
+                    let tls = TlsAcceptor::from(acceptor.current().config);
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                let _ = tx.send(Ok(stream)).await;
+                            }
+                            Ok(Err(err)) => info!("Handshake with {} failed: {}", peer, err),
+                            Err(_) => info!("Handshake with {} timed out", peer),
+                        }
+                    });
+                }
+            });
+        }
+
+        ReceiverStream::new(rx)
+    }
+
+    fn current(&self) -> Material {
+        self.material.read().expect("Poisoned TLS material").clone()
+    }
+}
+
+async fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|err| Error::Read(path.to_owned(), err))
+}
+
+/// Server config requiring client certificates issued by CA of TLS material,
+/// and not revoked by its CRL
+pub async fn load(tls: &Tls) -> Result<Material, Error> {
+    let certs = pemfile::certs(&mut Cursor::new(read(&tls.cert).await?))
+        .ok()
+        .filter(|certs| !certs.is_empty())
+        .ok_or_else(|| Error::NoCertificate(tls.cert.clone()))?;
+    let key = private_key(&read(&tls.key).await?).ok_or_else(|| Error::NoKey(tls.key.clone()))?;
+
+    let ca = read(&tls.ca).await?;
+    let mut roots = RootCertStore::empty();
+    match roots.add_pem_file(&mut Cursor::new(&ca)) {
+        Ok((valid, _)) if valid > 0 => (),
+        _ => return Err(Error::InvalidCa(tls.ca.clone())),
+    }
+    let crl = match &tls.crl {
+        Some(path) => Some(Crl::load(path, &ca).await?),
+        None => None,
+    };
+
+    let mut config = ServerConfig::new(Arc::new(Verifier {
+        inner: AllowAnyAuthenticatedClient::new(roots),
+        crl: crl.clone(),
+    }));
+    config.set_single_cert(certs, key)?;
+    config.set_protocols(&[ALPN_H2.to_vec()]);
+
+    Ok(Material {
+        config: Arc::new(config),
+        crl,
+    })
+}
+
+// Verifies client certificates are issued by CA, and refuses ones revoked by its CRL
+struct Verifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    crl: Option<Crl>,
+}
+
+impl ClientCertVerifier for Verifier {
+    fn client_auth_root_subjects(&self, sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        let verified = self.inner.verify_client_cert(presented_certs, sni)?;
+
+        if let Some(crl) = &self.crl {
+            for der in presented_certs {
+                let (_, cert) = X509Certificate::from_der(&der.0)
+                    .map_err(|_| TLSError::General("Invalid certificate".to_owned()))?;
+                if crl.is_revoked(&cert) {
+                    warn!(
+                        "Refused revoked certificate {} of {}",
+                        cert.raw_serial_as_string(),
+                        auth::organization(&cert).unwrap_or("unknown organization")
+                    );
+                    return Err(TLSError::General("Certificate is revoked".to_owned()));
+                }
+            }
+        }
+
+        Ok(verified)
+    }
+}
+
+// First PKCS8 or, failing that, RSA key in PEM
+fn private_key(pem: &[u8]) -> Option<PrivateKey> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut Cursor::new(pem)).unwrap_or_default();
+    let rsa = || pemfile::rsa_private_keys(&mut Cursor::new(pem)).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .next()
+        .or_else(|| rsa().into_iter().next())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::{rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/crl");
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(FIXTURES).join(name)
+    }
+
+    // Whether server accepts client with given certificate
+    async fn handshake(material: &Material, client: &str) -> bool {
+        let pem = |name: String| std::fs::read(fixture(&name)).unwrap();
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_pem_file(&mut Cursor::new(pem("ca.pem".to_owned())))
+            .unwrap();
+        let certs = pemfile::certs(&mut Cursor::new(pem(format!("{}.pem", client)))).unwrap();
+        let key = private_key(&pem(format!("{}.key", client))).unwrap();
+        config.set_single_client_cert(certs, key).unwrap();
+
+        let (client, server) = tokio::io::duplex(16384);
+        let connector = TlsConnector::from(Arc::new(config));
+        let localhost = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let (accepted, _) = tokio::join!(
+            TlsAcceptor::from(material.config.clone()).accept(server),
+            connector.connect(localhost, client)
+        );
+        accepted.is_ok()
+    }
+
+    #[tokio::test]
+    async fn given_crl_then_revoked_client_is_refused_during_handshake() {
+        let mut tls = Tls {
+            cert: fixture("server.pem"),
+            key: fixture("server.key"),
+            ca: fixture("ca.pem"),
+            crl: None,
+        };
+        let material = load(&tls).await.unwrap();
+        assert!(handshake(&material, "revoked").await);
+
+        tls.crl = Some(fixture("crl.pem"));
+        let material = load(&tls).await.unwrap();
+        assert!(handshake(&material, "valid").await);
+        assert!(!handshake(&material, "revoked").await);
+    }
+}
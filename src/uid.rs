@@ -0,0 +1,107 @@
+use std::{
+    collections::BTreeSet,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Every uid in range {}..{} is leased", .0.start, .0.end)]
+    Exhausted(Range<u32>),
+}
+
+/// Range of uids jobs run as, so that jobs of the same client can't signal, ptrace or read
+/// files of one another. Shared by controllers of all clients, range must not overlap with
+/// uids of existing users
+#[derive(Debug, Clone)]
+pub struct UidPool {
+    range: Range<u32>,
+    leased: Arc<Mutex<BTreeSet<u32>>>,
+}
+
+/// Uid of a single job, returned to the pool once dropped
+#[derive(Debug)]
+pub struct Lease {
+    uid: u32,
+    leased: Arc<Mutex<BTreeSet<u32>>>,
+}
+
+impl UidPool {
+    pub fn new(range: Range<u32>) -> Self {
+        Self {
+            range,
+            leased: Default::default(),
+        }
+    }
+
+    pub fn range(&self) -> &Range<u32> {
+        &self.range
+    }
+
+    /// Leases the lowest uid not leased to any other job
+    pub fn lease(&self) -> Result<Lease, Error> {
+        let mut leased = self.leased.lock().unwrap();
+        let uid = self
+            .range
+            .clone()
+            .find(|uid| !leased.contains(uid))
+            .ok_or_else(|| Error::Exhausted(self.range.clone()))?;
+        leased.insert(uid);
+
+        Ok(Lease {
+            uid,
+            leased: self.leased.clone(),
+        })
+    }
+
+    /// Leases uid of job adopted after restart, None if it is outside of the range
+    /// or already leased
+    pub fn reserve(&self, uid: u32) -> Option<Lease> {
+        let mut leased = self.leased.lock().unwrap();
+        (self.range.contains(&uid) && leased.insert(uid)).then(|| Lease {
+            uid,
+            leased: self.leased.clone(),
+        })
+    }
+}
+
+impl Lease {
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.leased.lock().unwrap().remove(&self.uid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn given_leased_uid_then_it_is_reused_only_once_released() {
+        let pool = UidPool::new(1000..1002);
+
+        let first = pool.lease().unwrap();
+        let second = pool.lease().unwrap();
+        assert_eq!((first.uid(), second.uid()), (1000, 1001));
+        assert!(matches!(pool.lease(), Err(Error::Exhausted(_))));
+        assert!(pool.reserve(1001).is_none());
+
+        drop(first);
+        assert_eq!(pool.lease().unwrap().uid(), 1000);
+    }
+
+    #[test]
+    fn given_uid_outside_of_range_then_it_is_not_reserved() {
+        let pool = UidPool::new(1000..1002);
+
+        assert!(pool.reserve(999).is_none());
+        assert_eq!(pool.reserve(1001).map(|lease| lease.uid()), Some(1001));
+    }
+}
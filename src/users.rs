@@ -0,0 +1,311 @@
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    ops::Range,
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Mutex,
+};
+
+use nix::{
+    libc,
+    unistd::{geteuid, Gid, Group, Uid, User},
+};
+use thiserror::Error;
+
+use crate::{USERS_DB, USER_RANGE};
+
+// Serializes reads and appends of the database, which is shared by controllers of all clients
+static DB_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+    #[error("Failed to look up user: {0}")]
+    Lookup(#[from] nix::Error),
+    #[error("{0} is not a valid user name")]
+    InvalidName(String),
+    #[error("Line {1} of user database {0} is malformed")]
+    Malformed(PathBuf, usize),
+    #[error("Line {1} of user database {0} has id outside of user range")]
+    OutOfRange(PathBuf, usize),
+    #[error("User database {0} must be a file owned by the server and writable only by it")]
+    Untrusted(PathBuf),
+    #[error("Every id in range {}..{} is taken", .0.start, .0.end)]
+    Exhausted(Range<u32>),
+    #[error("useradd failed with {0}")]
+    Useradd(ExitStatus),
+    #[error("User {0} is missing after useradd")]
+    Missing(String),
+}
+
+/// Provisions users clients' jobs run as. Existing system users are used as they are,
+/// others get uid and gid allocated from `range` and recorded in database owned by pls,
+/// so `/etc/passwd` is left untouched unless `useradd` is opted into
+#[derive(Debug, Clone)]
+pub struct Users {
+    range: Range<u32>,
+    db: PathBuf,
+    useradd: bool,
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Self::new(USER_RANGE, PathBuf::from(USERS_DB))
+    }
+}
+
+// Line of the database, formatted as `name:uid:gid`
+#[derive(Debug, PartialEq)]
+struct Entry {
+    name: String,
+    uid: u32,
+    gid: u32,
+}
+
+impl Users {
+    pub fn new(range: Range<u32>, db: PathBuf) -> Self {
+        Self {
+            range,
+            db,
+            useradd: false,
+        }
+    }
+
+    /// Creates system users with `useradd` instead of recording them in the database
+    pub fn set_useradd(mut self, useradd: bool) -> Self {
+        self.useradd = useradd;
+        self
+    }
+
+    /// Uid and gid of the client, provisioned on first use
+    pub fn ensure(&self, client: &str) -> Result<(u32, u32), Error> {
+        validate(client)?;
+        if let Some(user) = User::from_name(client)? {
+            return Ok((user.uid.as_raw(), user.gid.as_raw()));
+        }
+
+        let _lock = DB_LOCK.lock().unwrap();
+        let entries = read(&self.db, &self.range)?;
+        if let Some(entry) = entries.iter().find(|entry| entry.name == client) {
+            return Ok((entry.uid, entry.gid));
+        }
+
+        if self.useradd {
+            return useradd(client);
+        }
+
+        let id = self.free_id(&entries)?;
+        append(
+            &self.db,
+            &Entry {
+                name: client.to_owned(),
+                uid: id,
+                gid: id,
+            },
+        )?;
+
+        Ok((id, id))
+    }
+
+    // Lowest id neither recorded in database nor taken by system user or group,
+    // it serves as both uid and gid
+    fn free_id(&self, entries: &[Entry]) -> Result<u32, Error> {
+        for id in self.range.clone() {
+            if entries
+                .iter()
+                .any(|entry| entry.uid == id || entry.gid == id)
+            {
+                continue;
+            }
+            if User::from_uid(Uid::from_raw(id))?.is_none()
+                && Group::from_gid(Gid::from_raw(id))?.is_none()
+            {
+                return Ok(id);
+            }
+        }
+
+        Err(Error::Exhausted(self.range.clone()))
+    }
+}
+
+// Names end up in database lines and useradd arguments
+fn validate(client: &str) -> Result<(), Error> {
+    let valid = !client.is_empty()
+        && !client.starts_with('-')
+        && client
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidName(client.to_owned()))
+    }
+}
+
+// Database decides whom jobs run as, so it is trusted only if nobody but the server
+// could have written it. Symlinks are not followed
+fn open(db: &Path, options: &mut std::fs::OpenOptions) -> Result<File, Error> {
+    let file = options
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o644)
+        .open(db)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.uid() != geteuid().as_raw() || metadata.mode() & 0o022 != 0 {
+        return Err(Error::Untrusted(db.to_owned()));
+    }
+
+    Ok(file)
+}
+
+fn read(db: &Path, range: &Range<u32>) -> Result<Vec<Entry>, Error> {
+    let mut contents = String::new();
+    match open(db, std::fs::OpenOptions::new().read(true)) {
+        Ok(mut file) => file.read_to_string(&mut contents)?,
+        Err(Error::IO(err)) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    contents
+        .lines()
+        .enumerate()
+        .map(|(ix, line)| {
+            let mut fields = line.split(':');
+            let entry = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(uid), Some(gid), None) => uid
+                    .parse()
+                    .ok()
+                    .zip(gid.parse().ok())
+                    .map(|(uid, gid)| Entry {
+                        name: name.to_owned(),
+                        uid,
+                        gid,
+                    }),
+                _ => None,
+            };
+            match entry {
+                // Root is never handed out, whatever the range
+                Some(entry)
+                    if entry.uid == 0
+                        || !range.contains(&entry.uid)
+                        || !range.contains(&entry.gid) =>
+                {
+                    Err(Error::OutOfRange(db.to_owned(), ix + 1))
+                }
+                Some(entry) => Ok(entry),
+                None => Err(Error::Malformed(db.to_owned(), ix + 1)),
+            }
+        })
+        .collect()
+}
+
+fn append(db: &Path, entry: &Entry) -> Result<(), Error> {
+    if let Some(dir) = db.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut file = open(db, std::fs::OpenOptions::new().create(true).append(true))?;
+    // Single write, so that the line is never left half written
+    file.write_all(format!("{}:{}:{}\n", entry.name, entry.uid, entry.gid).as_bytes())?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+fn useradd(client: &str) -> Result<(u32, u32), Error> {
+    let status = std::process::Command::new("useradd")
+        .args(["-s", "/sbin/nologin", "-U", client])
+        .status()?;
+    if !status.success() {
+        return Err(Error::Useradd(status));
+    }
+
+    User::from_name(client)?
+        .map(|user| (user.uid.as_raw(), user.gid.as_raw()))
+        .ok_or_else(|| Error::Missing(client.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn users(name: &str, range: Range<u32>) -> Users {
+        Users::new(range, temp_dir(name).join("users"))
+    }
+
+    #[test]
+    fn given_unknown_clients_then_ids_are_allocated_once_and_persisted() {
+        let users = users("allocate", 3_999_000..3_999_002);
+
+        assert_eq!(users.ensure("alpha").unwrap(), (3_999_000, 3_999_000));
+        assert_eq!(users.ensure("beta").unwrap(), (3_999_001, 3_999_001));
+        assert!(matches!(users.ensure("gamma"), Err(Error::Exhausted(_))));
+
+        let reopened = Users::new(3_999_000..3_999_002, users.db.clone());
+        assert_eq!(reopened.ensure("alpha").unwrap(), (3_999_000, 3_999_000));
+    }
+
+    #[test]
+    fn given_system_user_then_its_ids_are_used() {
+        let users = users("system", 3_999_100..3_999_101);
+
+        assert_eq!(users.ensure("root").unwrap(), (0, 0));
+        assert!(!users.db.exists());
+    }
+
+    #[test]
+    fn given_invalid_name_then_it_is_rejected() {
+        let users = users("invalid", 3_999_200..3_999_201);
+
+        for name in ["", "-rf", "a:b", "a\nb"] {
+            assert!(matches!(users.ensure(name), Err(Error::InvalidName(_))));
+        }
+    }
+
+    #[test]
+    fn given_malformed_database_then_error_points_at_line() {
+        let users = users("malformed", 3_999_300..3_999_301);
+        std::fs::create_dir_all(users.db.parent().unwrap()).unwrap();
+        std::fs::write(&users.db, "alpha:3999300:3999300\nbeta:x:1\n").unwrap();
+
+        assert!(matches!(users.ensure("gamma"), Err(Error::Malformed(_, 2))));
+    }
+
+    #[test]
+    fn given_root_or_id_outside_range_in_database_then_it_is_rejected() {
+        let users = users("range", 3_999_400..3_999_401);
+        std::fs::create_dir_all(users.db.parent().unwrap()).unwrap();
+
+        for line in ["alpha:0:0\n", "alpha:3999400:0\n", "alpha:1000:1000\n"] {
+            std::fs::write(&users.db, line).unwrap();
+            assert!(matches!(
+                users.ensure("alpha"),
+                Err(Error::OutOfRange(_, 1))
+            ));
+        }
+    }
+
+    #[test]
+    fn given_database_writable_by_others_or_symlink_then_it_is_refused() {
+        let users = users("untrusted", 3_999_500..3_999_501);
+        std::fs::create_dir_all(users.db.parent().unwrap()).unwrap();
+        std::fs::write(&users.db, "alpha:3999500:3999500\n").unwrap();
+        std::fs::set_permissions(&users.db, std::fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(matches!(users.ensure("alpha"), Err(Error::Untrusted(_))));
+
+        std::fs::set_permissions(&users.db, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let link = users.db.with_extension("link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&users.db, &link).unwrap();
+        let linked = Users::new(users.range.clone(), link);
+        assert!(matches!(
+            linked.ensure("alpha"),
+            Err(Error::IO(err)) if err.raw_os_error() == Some(libc::ELOOP)
+        ));
+    }
+}
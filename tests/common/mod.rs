@@ -0,0 +1,27 @@
+//! Fixtures shared by integration tests
+
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// Empty directory of its own for every call, so that tests running in parallel
+/// or re-run by another process never share one
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join("pls-test")
+        .join(std::process::id().to_string())
+        .join(format!("{}-{}", name, DIRS.fetch_add(1, Ordering::Relaxed)));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Cgroup v2 directory of the test process, jobs are spawned into it
+pub async fn own_cgroup_dir() -> PathBuf {
+    pls::cgroup::own_cgroup_dir()
+        .await
+        .expect("Not in cgroup v2 hierarchy")
+}
@@ -0,0 +1,79 @@
+#!/bin/sh
+# Regenerates CA, certificates and CRLs used by revocation and authorization tests
+set -e
+cd "$(dirname "$0")"
+
+cat > openssl.cnf <<'CNF'
+[ ca ]
+default_ca = test_ca
+
+[ test_ca ]
+database = index.txt
+default_md = default
+default_crl_days = 36500
+crl_extensions = crl_ext
+
+[ crl_ext ]
+authorityKeyIdentifier = keyid:always
+
+[ ca_ext ]
+basicConstraints = critical, CA:TRUE
+keyUsage = critical, keyCertSign, cRLSign
+subjectKeyIdentifier = hash
+
+[ server_ext ]
+basicConstraints = CA:FALSE
+keyUsage = critical, digitalSignature
+extendedKeyUsage = serverAuth
+subjectAltName = DNS:localhost
+
+[ client_ext ]
+basicConstraints = CA:FALSE
+keyUsage = critical, digitalSignature
+extendedKeyUsage = clientAuth
+CNF
+
+ca() {
+    openssl genpkey -algorithm ed25519 -out "$1.key"
+    openssl req -x509 -new -key "$1.key" -subj "$2" -days 36500 \
+        -config openssl.cnf -extensions ca_ext -out "$1.pem"
+}
+
+# issue NAME SUBJECT EXTENSIONS SERIAL
+issue() {
+    openssl genpkey -algorithm ed25519 -out "$1.key"
+    openssl req -new -key "$1.key" -subj "$2" -out "$1.csr"
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -set_serial "$4" -days 36500 \
+        -extfile openssl.cnf -extensions "$3" -out "$1.pem"
+    rm "$1.csr"
+}
+
+# crl CA OUT REVOKED...
+crl() {
+    ca_name=$1
+    out=$2
+    shift 2
+    : > index.txt
+    for cert in "$@"; do
+        openssl ca -config openssl.cnf -keyfile "$ca_name.key" -cert "$ca_name.pem" -revoke "$cert.pem"
+    done
+    openssl ca -gencrl -config openssl.cnf -keyfile "$ca_name.key" -cert "$ca_name.pem" -out "$out"
+}
+
+ca ca "/O=pls test CA"
+ca other-ca "/O=other test CA"
+# Same subject as CA, different key
+ca forged-ca "/O=pls test CA"
+issue server "/CN=localhost" server_ext 1
+issue valid "/O=acme/CN=alice" client_ext 2
+issue revoked "/O=acme/CN=mallory" client_ext 3
+# Roles are read from organizational units
+issue operator "/O=acme/OU=operator/CN=bob" client_ext 4
+issue sre "/O=acme/OU=admin/OU=sre/CN=carol" client_ext 5
+
+crl ca crl.pem revoked
+crl other-ca other-crl.pem
+crl forged-ca forged-crl.pem
+
+# Only certificates and keys of servers and clients are needed by tests
+rm -f index.txt* openssl.cnf forged-ca.pem ca.key other-ca.key forged-ca.key
@@ -0,0 +1,93 @@
+//! Spawns jobs as `nobody` and checks from within that privileges are dropped.
+//! Requires root and cgroup v2, jobs are spawned into cgroup of the test process
+
+mod common;
+
+use common::{own_cgroup_dir, temp_dir};
+use nix::{
+    fcntl::{open, OFlag},
+    sys::stat::Mode,
+    unistd::close,
+};
+use pls::{
+    job::Job,
+    runner::{job_status::Outcome, JobRequest},
+};
+
+const NOBODY: u32 = 65534;
+// Supplementary group of the job, other than its primary one
+const USERS: u32 = 100;
+
+// Runs shell script as a job, returning its outcome
+async fn run(name: &str, script: &str) -> Option<Outcome> {
+    let job_dir = temp_dir(name);
+
+    let job_request = JobRequest {
+        executable: "/bin/sh".into(),
+        args: vec!["-c".into(), script.into()],
+        inherit_env: true,
+        ..Default::default()
+    };
+    let job = Job::default()
+        .add_command(&job_request)
+        .add_to_cgroup(own_cgroup_dir().await)
+        .unwrap()
+        .set_ownership(NOBODY, NOBODY, vec![NOBODY, USERS])
+        .set_job_dir(job_dir)
+        .spawn()
+        .unwrap();
+
+    job.wait().await.outcome
+}
+
+#[tokio::test]
+async fn given_client_groups_then_job_has_only_them() {
+    let outcome = run(
+        "groups",
+        &format!(r#"test "$(id -G)" = "{NOBODY} {USERS}""#),
+    )
+    .await;
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
+
+#[tokio::test]
+async fn given_job_then_bounding_set_is_empty() {
+    let outcome = run(
+        "capabilities",
+        "grep -q '^CapBnd:\t0000000000000000$' /proc/self/status && \
+         grep -q '^CapAmb:\t0000000000000000$' /proc/self/status",
+    )
+    .await;
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
+
+#[tokio::test]
+async fn given_job_then_no_new_privs_is_set() {
+    let outcome = run(
+        "no-new-privs",
+        "grep -q '^NoNewPrivs:\t1$' /proc/self/status",
+    )
+    .await;
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
+
+#[tokio::test]
+async fn given_fd_leaked_by_server_then_job_does_not_inherit_it() {
+    // Opened without O_CLOEXEC
+    let leaked = open("/dev/null", OFlag::O_RDONLY, Mode::empty()).unwrap();
+
+    let outcome = run("fds", &format!("test ! -e /proc/self/fd/{leaked}")).await;
+    close(leaked).unwrap();
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
+
+#[tokio::test]
+async fn given_job_then_its_files_are_private() {
+    let outcome = run("umask", r#"test "$(umask)" = 0077"#).await;
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
@@ -0,0 +1,139 @@
+//! Spawns jobs as `nobody` in namespaces of their own and checks from within what they see.
+//! Requires root and cgroup v2, jobs are spawned into cgroup of the test process
+
+mod common;
+
+use common::{own_cgroup_dir, temp_dir};
+use pls::{
+    job::{Job, Started},
+    namespace::Namespaces,
+    runner::{job_request::Isolation, job_status::Outcome, JobRequest},
+};
+
+const NOBODY: u32 = 65534;
+
+// Spawns shell script as a job isolated as requested
+async fn spawn(name: &str, isolation: Isolation, script: &str) -> Job<Started> {
+    let job_request = JobRequest {
+        executable: "/bin/sh".into(),
+        args: vec!["-c".into(), script.into()],
+        inherit_env: true,
+        ..Default::default()
+    };
+    let namespaces = Namespaces::from(&isolation).set_owner(NOBODY, NOBODY, vec![NOBODY]);
+
+    Job::default()
+        .add_command(&job_request)
+        .add_to_cgroup(own_cgroup_dir().await)
+        .unwrap()
+        .isolate(Some(namespaces))
+        .set_ownership(NOBODY, NOBODY, vec![NOBODY])
+        .set_job_dir(temp_dir(name))
+        .spawn()
+        .unwrap()
+}
+
+async fn run(name: &str, isolation: Isolation, script: &str) -> Option<Outcome> {
+    spawn(name, isolation, script).await.wait().await.outcome
+}
+
+#[tokio::test]
+async fn given_pid_namespace_then_job_is_forked_by_init_with_pid_1() {
+    let isolation = Isolation {
+        pid: true,
+        mount: true,
+        ..Default::default()
+    };
+    // Init is pid 1 with no parent in the namespace, and the job is the next one
+    let outcome = run(
+        "pid",
+        isolation,
+        r#"test "$$" = 2 && grep -q '^PPid:.0$' /proc/1/status"#,
+    )
+    .await;
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
+
+#[tokio::test]
+async fn given_net_namespace_then_only_loopback_is_up() {
+    let isolation = Isolation {
+        net: true,
+        ..Default::default()
+    };
+    let outcome = run(
+        "net",
+        isolation,
+        r#"test "$(ip -o link | wc -l)" = 1 && ip -o link show lo | grep -q ',UP'"#,
+    )
+    .await;
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
+
+#[tokio::test]
+async fn given_hostname_then_job_has_it() {
+    let isolation = Isolation {
+        hostname: Some("job".into()),
+        ..Default::default()
+    };
+    let outcome = run("uts", isolation, r#"test "$(hostname)" = job"#).await;
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
+
+#[tokio::test]
+async fn given_mount_namespace_then_mounts_are_private() {
+    let isolation = Isolation {
+        mount: true,
+        ..Default::default()
+    };
+    let host = std::fs::read_link("/proc/self/ns/mnt").unwrap();
+    let outcome = run(
+        "mount",
+        isolation,
+        &format!(
+            r#"test "$(readlink /proc/self/ns/mnt)" != "{}" && ! grep -q shared: /proc/self/mountinfo"#,
+            host.display()
+        ),
+    )
+    .await;
+
+    assert_eq!(outcome, Some(Outcome::ExitCode(0)));
+}
+
+#[tokio::test]
+async fn given_pid_namespace_then_supervisor_and_init_keep_no_privileges() {
+    let isolation = Isolation {
+        pid: true,
+        ..Default::default()
+    };
+    let job = spawn("supervisor", isolation, "sleep 100").await;
+    let supervisor = job.pid().unwrap();
+
+    // Init is forked before the supervisor drops its credentials
+    let mut statuses = Vec::new();
+    for _ in 0..100 {
+        let children =
+            std::fs::read_to_string(format!("/proc/{0}/task/{0}/children", supervisor)).unwrap();
+        let status = std::fs::read_to_string(format!("/proc/{}/status", supervisor)).unwrap();
+        if let Some(init) = children.split_whitespace().next() {
+            let init = std::fs::read_to_string(format!("/proc/{}/status", init)).unwrap();
+            if status.contains(&format!("Uid:\t{0}\t{0}", NOBODY))
+                && init.contains(&format!("Uid:\t{0}\t{0}", NOBODY))
+            {
+                statuses = vec![status, init];
+                break;
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    job.cancel();
+    job.wait().await;
+
+    assert_eq!(statuses.len(), 2);
+    for status in statuses {
+        assert!(status.contains("CapEff:\t0000000000000000"));
+        assert!(status.contains("CapBnd:\t0000000000000000"));
+    }
+}
@@ -0,0 +1,76 @@
+//! Spawns jobs as `nobody` confined by seccomp filter and checks from within what they are denied.
+//! Requires root and cgroup v2, jobs are spawned into cgroup of the test process
+
+mod common;
+
+use common::{own_cgroup_dir, temp_dir};
+use nix::libc;
+use pls::{
+    job::Job,
+    runner::{job_status::Outcome, JobRequest, JobStatus},
+    seccomp::{Action, Filter, Policy, Profile},
+};
+
+const NOBODY: u32 = 65534;
+
+// Runs perl script as a job confined by default profile, violations are reported
+async fn run(name: &str, script: &str) -> JobStatus {
+    let job_request = JobRequest {
+        executable: "/usr/bin/perl".into(),
+        args: vec!["-e".into(), script.into()],
+        inherit_env: true,
+        ..Default::default()
+    };
+    let filter = Filter::from(Policy {
+        profile: Profile::Default,
+        action: Action::Errno,
+    });
+
+    Job::default()
+        .add_command(&job_request)
+        .add_to_cgroup(own_cgroup_dir().await)
+        .unwrap()
+        .set_ownership(NOBODY, NOBODY, vec![NOBODY])
+        .confine(Some(filter))
+        .unwrap()
+        .set_job_dir(temp_dir(name))
+        .spawn()
+        .unwrap()
+        .wait()
+        .await
+}
+
+#[tokio::test]
+async fn given_clone_into_new_namespace_then_it_fails_with_eperm_and_is_reported() {
+    // Unprivileged user namespace is allowed otherwise. Child, if any, exits with 1 too
+    let status = run(
+        "clone",
+        &format!(
+            "exit(syscall({}, {} | 17, 0, 0, 0, 0) == -1 && $!{{EPERM}} ? 0 : 1)",
+            libc::SYS_clone,
+            libc::CLONE_NEWUSER
+        ),
+    )
+    .await;
+
+    assert_eq!(status.outcome, Some(Outcome::ExitCode(0)));
+    assert_eq!(status.seccomp_violations.len(), 1);
+    assert_eq!(status.seccomp_violations[0].name, "clone");
+    assert_eq!(status.seccomp_violations[0].count, 1);
+}
+
+#[tokio::test]
+async fn given_clone3_then_it_is_unimplemented_and_fork_still_works() {
+    let status = run(
+        "clone3",
+        &format!(
+            "exit(1) unless syscall({}, 0, 0) == -1 && $!{{ENOSYS}}; \
+             my $pid = fork() // exit(1); exit(0) unless $pid; waitpid($pid, 0); exit($?)",
+            libc::SYS_clone3
+        ),
+    )
+    .await;
+
+    assert_eq!(status.outcome, Some(Outcome::ExitCode(0)));
+    assert!(status.seccomp_violations.is_empty());
+}
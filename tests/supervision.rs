@@ -0,0 +1,50 @@
+//! Spawns jobs and checks how their completion is supervised.
+//! Requires root and cgroup v2, jobs are spawned into cgroup of the test process
+
+mod common;
+
+use std::time::Duration;
+
+use common::{own_cgroup_dir, temp_dir};
+use pls::{
+    job::Job,
+    runner::{job_status::Outcome, JobRequest},
+};
+
+const NOBODY: u32 = 65534;
+
+#[tokio::test]
+async fn given_background_process_holding_output_then_job_completes_on_exit() {
+    let job_dir = temp_dir("background");
+    // Written by the job, as nobody
+    nix::unistd::chown(&job_dir, Some(nix::unistd::Uid::from_raw(NOBODY)), None).unwrap();
+    let pid_file = job_dir.join("background.pid");
+
+    let job_request = JobRequest {
+        executable: "/bin/sh".into(),
+        args: vec![
+            "-c".into(),
+            format!("sleep 1000 & echo $! > {}; exit 0", pid_file.display()),
+        ],
+        inherit_env: true,
+        ..Default::default()
+    };
+    let job = Job::default()
+        .add_command(&job_request)
+        .add_to_cgroup(own_cgroup_dir().await)
+        .unwrap()
+        .set_ownership(NOBODY, NOBODY, vec![NOBODY])
+        .set_job_dir(job_dir)
+        .spawn()
+        .unwrap();
+
+    let status = tokio::time::timeout(Duration::from_secs(5), job.wait()).await;
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(pid.trim().parse().unwrap()),
+        nix::sys::signal::Signal::SIGKILL,
+    )
+    .unwrap();
+
+    assert_eq!(status.unwrap().outcome, Some(Outcome::ExitCode(0)));
+}